@@ -1,8 +1,11 @@
-use crate::memory::{GiB, PAGE_SIZE};
+use crate::memory::{GiB, MiB, PAGE_SIZE};
 
 // The kernel virtual address range [0xffffffffc0000000, 0xffffffffffffffff]
-// maps to the physical address range [0, 0x3fffffff]
+// maps to the physical address range [0, 0x3fffffff]. The GiB below that,
+// [0xffffffff80000000, 0xffffffffbfffffff], isn't part of the linear map and
+// is used for dynamically mapped regions like the kernel stacks.
 const ADDRESS_SPACE_SIZE: u64 = GiB;
+const _KERNEL_VA_START: u64 = 0xFFFF_FFFF_8000_0000;
 const _HIGH_MEMORY_START: u64 = 0xFFFF_FFFF_C000_0000;
 const VC_MMU_RAM_RANGE: core::ops::RangeInclusive<u32> = 0xC000_0000..=0xFEFF_FFFF;
 const VC_MMU_PERIPHERALS_RANGE: core::ops::RangeInclusive<u32> = 0x7E00_0000..=0x7EFF_FFFF;
@@ -16,6 +19,28 @@ pub const KSTACK_TOP_CPU0: AddressVirtual = AddressVirtual::new(0xFFFF_FFFF_C008
 pub const KSTACK_BOTTOM_CPU0: AddressVirtual = KSTACK_TOP_CPU0.subtract(KSTACK_SIZE);
 pub const KSTACK_GUARD_CPU0: AddressVirtual = KSTACK_BOTTOM_CPU0.subtract(KSTACK_GUARD_SIZE);
 
+// Kernel stacks allocated at runtime live in fixed-size slots in their own
+// region. The lower half of each slot is left unmapped and acts as a guard
+// area, the upper half is the stack itself. The exception vectors rely on the
+// slot size and the region size and base being powers of two to detect stack
+// overflows cheaply, see exceptions.s.
+pub const KSTACK_REGION_START: AddressVirtual = AddressVirtual::new(_KERNEL_VA_START);
+pub const KSTACK_REGION_SIZE: u64 = 64 * MiB;
+pub const KSTACK_SLOT_SIZE: u64 = KSTACK_SIZE * 2;
+
+// A single page right after the kernel stack region, used to temporarily map
+// pages of the read-only kernel text as writable when patching instructions
+#[cfg(feature = "gdb")]
+pub const TEXT_PATCH_VA: AddressVirtual = KSTACK_REGION_START.add(KSTACK_REGION_SIZE);
+
+const _: () = {
+    assert!(KSTACK_SIZE.is_power_of_two());
+    assert!(KSTACK_REGION_SIZE.is_power_of_two());
+    assert!(KSTACK_REGION_START
+        .as_u64()
+        .is_multiple_of(KSTACK_REGION_SIZE));
+};
+
 #[derive(Clone, Copy, Debug)]
 pub struct AddressPhysical {
     addr: u64,
@@ -76,7 +101,7 @@ impl AddressVirtual {
     #[allow(clippy::absurd_extreme_comparisons)]
     pub const fn new(addr: u64) -> Self {
         assert!(
-            (addr >= _HIGH_MEMORY_START) && (addr <= _HIGH_MEMORY_START + (ADDRESS_SPACE_SIZE - 1))
+            (addr >= _KERNEL_VA_START) && (addr <= _HIGH_MEMORY_START + (ADDRESS_SPACE_SIZE - 1))
         );
         Self { addr }
     }
@@ -96,7 +121,14 @@ impl AddressVirtual {
         Self::new((self.addr + alignment - 1) & !(alignment - 1))
     }
 
+    pub const fn align_down(&self, alignment: u64) -> Self {
+        assert!(alignment.is_power_of_two());
+        Self::new(self.addr & !(alignment - 1))
+    }
+
+    /// Panics if the address is not part of the linear map
     pub const fn as_physical(&self) -> AddressPhysical {
+        assert!(self.addr >= _HIGH_MEMORY_START);
         let addr = self.addr - HIGH_MEMORY_START.as_u64();
         AddressPhysical::new(addr)
     }
@@ -11,6 +11,11 @@ pub enum AllocError {
     OutOfMemory,
 }
 
+pub struct MemoryStats {
+    pub total_pages: usize,
+    pub free_pages: usize,
+}
+
 // The page allocator is implemented using a linked list. Each free page in its
 // first 64 bytes stores a pointer (virtual address) to the next free page.
 struct PageAllocator<const PAGE_SZ: u64> {
@@ -55,6 +60,14 @@ impl<const PAGE_SZ: u64> PageAllocator<PAGE_SZ> {
         self.regions.clone()
     }
 
+    fn stats(&self) -> MemoryStats {
+        let total_bytes: u64 = self.regions.iter().map(|r| r.size()).sum();
+        MemoryStats {
+            total_pages: (total_bytes / PAGE_SZ) as usize,
+            free_pages: self.free_pages,
+        }
+    }
+
     fn allocate_page(&mut self) -> Result<AddressVirtual, AllocError> {
         if self.free_pages == 0 {
             return Err(AllocError::OutOfMemory);
@@ -89,7 +102,7 @@ impl<const PAGE_SZ: u64> PageAllocator<PAGE_SZ> {
 // doesn't overlap with regions previously donated to the allocator.
 pub unsafe fn add_region(region: &RangePhysical) {
     let num_pages = region.size() / PAGE_SIZE;
-    crate::println!("Adding {num_pages} pages to physical memory allocator: {region:#x?}");
+    crate::info!("Adding {num_pages} pages to physical memory allocator: {region:#x?}");
     let mut p = PAGE_ALLOCATOR.lock();
     p.add_region(region);
 }
@@ -99,6 +112,11 @@ pub fn get_regions() -> Vec<RangePhysical, NUM_REGIONS> {
     p.get_regions()
 }
 
+pub fn stats() -> MemoryStats {
+    let p = PAGE_ALLOCATOR.lock();
+    p.stats()
+}
+
 /// Allocates a 4KiB page with all bytes set to 0.
 pub fn allocate_page() -> Result<AddressVirtual, AllocError> {
     let mut p = PAGE_ALLOCATOR.lock();
@@ -0,0 +1,169 @@
+// Frame pointer based stack unwinding. Every function that sets up a frame
+// stores a frame record {previous fp, lr} and points x29 at it, so the chain
+// of frame records can be followed up to the entry point of the stack, where
+// kstack::switch_to_stack() zeroes the frame pointer.
+//
+// The symbol table used to print function names is generated from the kernel
+// ELF by tools/ksyms after linking, see the layout described there.
+
+use crate::paging;
+use crate::println;
+
+const MAX_FRAMES: usize = 32;
+
+// Space reserved for the symbol table. The debug build with all features
+// needs about 140 KiB, tools/ksyms reports the usage after every build and
+// warns when the table is more than 75% full. To grow it just raise this,
+// the linker script sizes the .ksyms area from the static below.
+const KSYMS_SIZE: usize = 256 * 1024;
+const KSYMS_MAGIC: &[u8; 4] = b"KSYM";
+const KSYMS_ENTRY_SIZE: usize = 12;
+
+// Reserves space for the symbol table. The table itself is accessed through
+// the __ksyms_start symbol because the compiler assumes this is all zeroes.
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS_SPACE: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+struct SymbolTable {
+    entries: &'static [u8],
+    names: &'static [u8],
+    text_start: u64,
+}
+
+impl SymbolTable {
+    fn get() -> Option<Self> {
+        extern "C" {
+            static __ksyms_start: u8;
+            static __text_start: u64;
+        }
+
+        // SAFETY: The linker script places __ksyms_start right before
+        // KSYMS_SPACE which is KSYMS_SIZE bytes long
+        let table = unsafe { core::slice::from_raw_parts(&raw const __ksyms_start, KSYMS_SIZE) };
+        if &table[0..4] != KSYMS_MAGIC {
+            // The binary wasn't patched by tools/ksyms
+            return None;
+        }
+
+        let count = u32::from_le_bytes(table[4..8].try_into().unwrap()) as usize;
+        let entries_end = 8 + count * KSYMS_ENTRY_SIZE;
+        if entries_end > KSYMS_SIZE {
+            return None;
+        }
+
+        Some(SymbolTable {
+            entries: &table[8..entries_end],
+            names: &table[entries_end..],
+            text_start: &raw const __text_start as u64,
+        })
+    }
+
+    fn entry(&self, i: usize) -> (u64, u64, usize) {
+        let e = &self.entries[i * KSYMS_ENTRY_SIZE..(i + 1) * KSYMS_ENTRY_SIZE];
+        let field = |n: usize| u32::from_le_bytes(e[n * 4..n * 4 + 4].try_into().unwrap());
+        (field(0) as u64, field(1) as u64, field(2) as usize)
+    }
+
+    fn name(&self, offset: usize) -> &'static str {
+        let names = &self.names[offset..];
+        let len = names.iter().position(|&b| b == 0).unwrap_or(0);
+        core::str::from_utf8(&names[..len]).unwrap_or("?")
+    }
+
+    fn lookup(&self, pc: u64) -> Option<(&'static str, u64)> {
+        let pc_offset = pc.checked_sub(self.text_start)?;
+        let count = self.entries.len() / KSYMS_ENTRY_SIZE;
+
+        // Binary search for the last symbol that starts at or before pc
+        let (mut lo, mut hi) = (0, count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.entry(mid).0 <= pc_offset {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let idx = lo.checked_sub(1)?;
+
+        let (start, size, name) = self.entry(idx);
+        if size != 0 && pc_offset >= start + size {
+            return None;
+        }
+
+        Some((self.name(name), pc_offset - start))
+    }
+}
+
+/// Returns the name of the function containing `pc` and the offset of `pc`
+/// from the start of that function.
+pub fn symbolize(pc: u64) -> Option<(&'static str, u64)> {
+    SymbolTable::get()?.lookup(pc)
+}
+
+fn print_frame(index: usize, pc: u64) {
+    match symbolize(pc) {
+        Some((name, offset)) => println!("  #{index:<2} {pc:#018x} {name}+{offset:#x}"),
+        None => println!("  #{index:<2} {pc:#018x} ?"),
+    }
+}
+
+/// Prints a backtrace starting at the function `pc` belongs to and
+/// continuing with the callers found by walking the frame records from `fp`.
+pub fn print(fp: u64, pc: u64) {
+    println!("Backtrace:");
+    print_frame(0, pc);
+
+    let mut fp = fp;
+    for index in 1..MAX_FRAMES {
+        // Stop at the end of the chain or at anything that doesn't look like
+        // a frame record. A corrupted stack must not fault in here.
+        if fp == 0
+            || !fp.is_multiple_of(8)
+            || !paging::is_readable(fp)
+            || !paging::is_readable(fp + 8)
+        {
+            return;
+        }
+
+        // SAFETY: We checked above that both words of the frame record can
+        // be read
+        let (next_fp, lr) = unsafe { (*(fp as *const u64), *((fp + 8) as *const u64)) };
+        if lr == 0 {
+            return;
+        }
+
+        // The LR points to the instruction after the call. Use the call
+        // itself so that calls at the very end of a function are attributed
+        // to the right one.
+        print_frame(index, lr - 4);
+
+        // Stacks grow downwards so the caller's frame must be above ours
+        if next_fp <= fp {
+            return;
+        }
+        fp = next_fp;
+    }
+
+    println!("  ...");
+}
+
+/// Prints the backtrace of the caller
+#[inline(always)]
+pub fn print_current() {
+    let fp: u64;
+    let pc: u64;
+    // SAFETY: Simply reading the frame pointer and the program counter
+    unsafe {
+        core::arch::asm!(
+            "mov {fp}, x29",
+            "adr {pc}, .",
+            fp = out(reg) fp,
+            pc = out(reg) pc,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+
+    print(fp, pc);
+}
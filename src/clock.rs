@@ -0,0 +1,66 @@
+// The clocks are owned by the VideoCore firmware and changed through the
+// mailbox. Peripherals whose timing is derived from a clock need to be
+// re-programmed when it changes.
+//
+// Besides the changes made here, the firmware changes the core clock on its
+// own, e.g. when it throttles because of the temperature or an undervoltage.
+// The rate is polled from a system timer alarm so that the mini UART follows
+// those changes too.
+
+use crate::drivers::mailbox::{self, ClockId, MailboxError};
+use crate::drivers::system_timer::{self, Channel};
+use crate::drivers::uart_mini;
+use crate::{info, warn};
+use crate::{ACTIONS, PENDING_ACTIONS};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+const CORE_CLOCK_POLL_INTERVAL_US: u64 = 1_000_000;
+const CORE_CLOCK_POLL_CHANNEL: Channel = Channel::One;
+
+// The last core clock rate the mini UART was programmed for
+static CORE_CLOCK_HZ: AtomicU32 = AtomicU32::new(0);
+
+// Re-programs the mini UART if the core clock rate is not the last one seen
+fn core_clock_changed(rate_hz: u32) {
+    let old_rate_hz = CORE_CLOCK_HZ.swap(rate_hz, Ordering::Relaxed);
+    if old_rate_hz != rate_hz {
+        uart_mini::core_clock_changed(rate_hz);
+        info!("Core clock changed from {old_rate_hz} Hz to {rate_hz} Hz");
+    }
+}
+
+fn poll_alarm(channel: Channel) {
+    // Asking the firmware takes too long for interrupt context
+    *PENDING_ACTIONS.lock() |= 1 << (ACTIONS::ClockAction as u64);
+    let _ = system_timer::set_alarm_in(channel, CORE_CLOCK_POLL_INTERVAL_US, poll_alarm);
+}
+
+/// Starts following the core clock
+pub fn init() {
+    match mailbox::get_clock_rate(ClockId::Core) {
+        Ok(rate) => CORE_CLOCK_HZ.store(rate, Ordering::Relaxed),
+        Err(e) => warn!("Can't get the core clock rate: {e}"),
+    }
+    poll_alarm(CORE_CLOCK_POLL_CHANNEL);
+}
+
+// This is the bottom half of the polling alarm
+pub fn process_pending_poll() {
+    if let Ok(rate) = mailbox::get_clock_rate(ClockId::Core) {
+        core_clock_changed(rate);
+    }
+}
+
+/// Sets the core clock and returns the rate the firmware actually set
+pub fn set_core_clock_rate(rate_hz: u32) -> Result<u32, MailboxError> {
+    let rate = mailbox::set_clock_rate(ClockId::Core, rate_hz, false)?;
+    core_clock_changed(rate);
+    Ok(rate)
+}
+
+/// Switches turbo mode on or off, which changes the core clock too
+pub fn set_turbo(on: bool) -> Result<(), MailboxError> {
+    mailbox::set_turbo(on)?;
+    process_pending_poll();
+    Ok(())
+}
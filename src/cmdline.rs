@@ -0,0 +1,39 @@
+// The kernel command line. The firmware builds it from cmdline.txt on the
+// boot partition plus options of its own, and hands it over through the
+// mailbox. Options are separated by spaces and are either `key=value` or a
+// plain `key`.
+
+use crate::drivers::mailbox::{self, MAX_COMMAND_LINE_LEN};
+use crate::locking::SpinLock;
+use crate::warn;
+use heapless::String;
+
+static CMDLINE: SpinLock<String<MAX_COMMAND_LINE_LEN>> = SpinLock::new(String::new());
+
+/// Fetches the command line from the firmware. Without one every option is
+/// left at its default.
+pub fn init() {
+    match mailbox::get_command_line() {
+        Ok(cmdline) => *CMDLINE.lock() = cmdline,
+        Err(e) => warn!("Can't get the command line: {e}"),
+    }
+}
+
+/// Returns the value of the last `key=value` option, parsed. Options that
+/// don't parse are ignored with a warning.
+pub fn value<T: core::str::FromStr>(key: &str) -> Option<T> {
+    let cmdline = CMDLINE.lock();
+    let value = cmdline
+        .split(' ')
+        .rev()
+        .filter_map(|option| option.split_once('='))
+        .filter(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+        .next()?;
+
+    let parsed = value.parse().ok();
+    if parsed.is_none() {
+        warn!("Ignoring invalid command line option {key}={value}");
+    }
+    parsed
+}
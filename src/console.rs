@@ -0,0 +1,182 @@
+// The kernel console. Every message is written while holding the console
+// lock so that messages from different CPUs, or from IRQ handlers, don't
+// interleave. Interrupts are disabled while the lock is held.
+//
+// The lock is recursive per CPU: a synchronous exception taken in the middle
+// of a message (e.g. a watchpoint hit) can still print, at the cost of its
+// output ending up in the middle of that message, instead of deadlocking.
+// The panic handler breaks the lock, since the CPU holding it could be the one
+// that crashed or could never release it.
+//
+// Either UART can host the console. The mini UART does by default and the
+// console-pl011 feature picks the PL011 instead. The gdb feature implies
+// console-pl011 because the GDB stub owns the mini UART.
+
+use crate::cpu;
+use crate::drivers::{uart_mini, uart_pl011};
+use crate::irq;
+use aarch64_cpu::registers::DAIF;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tock_registers::interfaces::{Readable, Writeable};
+
+const NO_OWNER: usize = usize::MAX;
+
+// The CPU holding the lock and how many times it has taken it
+static OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+// Set once the lock has been broken, after which it's ignored
+static BYPASS: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    MiniUart,
+    Pl011,
+}
+
+impl Backend {
+    fn put_char(self, c: char) {
+        match self {
+            Self::MiniUart => uart_mini::put_char(c),
+            Self::Pl011 => uart_pl011::put_char(c),
+        }
+    }
+}
+
+const BACKEND: Backend = if cfg!(feature = "console-pl011") {
+    Backend::Pl011
+} else {
+    Backend::MiniUart
+};
+
+// Whether the console UARTs use RTS/CTS, which needs a cable with those lines
+// connected. Without them the UART never transmits.
+pub const FLOW_CONTROL: bool = cfg!(feature = "uart-flow-control");
+
+/// Initializes the UART selected at build time for the console
+pub fn init(baud_rate: u32) {
+    match BACKEND {
+        Backend::MiniUart => uart_mini::init(baud_rate, FLOW_CONTROL),
+        Backend::Pl011 => uart_pl011::init(baud_rate, FLOW_CONTROL),
+    }
+}
+
+pub fn backend() -> Backend {
+    BACKEND
+}
+
+/// Changes the baud rate of the console UART
+pub fn set_baud_rate(baud_rate: u32) {
+    // Taking the lock makes sure no message is split between the two rates
+    let _guard = lock();
+    match backend() {
+        Backend::MiniUart => uart_mini::set_baud_rate(baud_rate),
+        Backend::Pl011 => uart_pl011::set_baud_rate(baud_rate),
+    }
+}
+
+/// Makes console output synchronous, for when interrupts can't be relied on
+/// anymore like after a panic
+pub fn disable_buffering() {
+    uart_mini::disable_tx_buffering();
+    uart_pl011::disable_tx_buffering();
+}
+
+struct SerialConsole;
+
+impl core::fmt::Write for SerialConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let backend = backend();
+        for c in s.chars() {
+            if c == '\n' {
+                backend.put_char('\r');
+            }
+            backend.put_char(c);
+        }
+        Ok(())
+    }
+}
+
+/// Exclusive access to the console. Output written through this is never
+/// interleaved with output from other CPUs.
+pub struct ConsoleGuard {
+    // Whether this guard took the lock, false if it was bypassed
+    locked: bool,
+    // The value of DAIF before the ConsoleGuard instance was created
+    old_daif: u64,
+}
+
+pub fn lock() -> ConsoleGuard {
+    let old_daif = DAIF.get();
+    irq::disable_interrupts();
+
+    let cpu = cpu::current_id();
+    let mut locked = false;
+    if OWNER.load(Ordering::Relaxed) == cpu {
+        // Only this CPU can change the owner away from itself
+        DEPTH.fetch_add(1, Ordering::Relaxed);
+        locked = true;
+    } else {
+        while !BYPASS.load(Ordering::Relaxed) {
+            if OWNER
+                .compare_exchange_weak(NO_OWNER, cpu, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                DEPTH.store(1, Ordering::Relaxed);
+                locked = true;
+                break;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    ConsoleGuard { locked, old_daif }
+}
+
+impl Drop for ConsoleGuard {
+    fn drop(&mut self) {
+        // The lock could have been broken and taken by someone else since
+        if self.locked
+            && OWNER.load(Ordering::Relaxed) == cpu::current_id()
+            && DEPTH.fetch_sub(1, Ordering::Relaxed) == 1
+        {
+            OWNER.store(NO_OWNER, Ordering::Release);
+        }
+        DAIF.set(self.old_daif);
+    }
+}
+
+impl core::fmt::Write for ConsoleGuard {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        SerialConsole.write_str(s)
+    }
+}
+
+/// Makes the console usable regardless of who holds the lock. Only meant for
+/// the panic handler, output from different CPUs can interleave afterwards.
+pub fn break_lock() {
+    BYPASS.store(true, Ordering::Relaxed);
+}
+
+/// Called by print!() and println!()
+pub fn print(args: core::fmt::Arguments) {
+    use core::fmt::Write;
+    lock().write_fmt(args).unwrap();
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::console::print(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::console::print(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
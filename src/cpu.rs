@@ -0,0 +1,10 @@
+use aarch64_cpu::registers::MPIDR_EL1;
+use tock_registers::interfaces::Readable;
+
+pub const NUM_CPUS: usize = 4;
+
+/// Returns the ID of the CPU we are running on. For RPi3 this is just Aff0.
+#[inline]
+pub fn current_id() -> usize {
+    (MPIDR_EL1.get() & (NUM_CPUS as u64 - 1)) as usize
+}
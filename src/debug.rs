@@ -0,0 +1,457 @@
+// Self-hosted debug support. Debug exceptions other than BRK (breakpoints,
+// watchpoints and software step) are only taken from EL1 if MDSCR_EL1.KDE is
+// set, the OS lock is clear and PSTATE.D is clear in the debugged context.
+//
+// Hardware breakpoints and watchpoints report the PC and a backtrace when they
+// hit and execution then continues. Resuming would hit them again straight
+// away, so they are disabled while the triggering instruction is single
+// stepped and enabled again after the step.
+
+use crate::cpu::{self, NUM_CPUS};
+use crate::esr::{self, ExceptionClass, Iss};
+use crate::exceptions::ExceptionFrame;
+use crate::locking::SpinLock;
+use crate::{backtrace, println};
+use aarch64_cpu::asm::barrier;
+use aarch64_cpu::registers::{DAIF, ID_AA64DFR0_EL1, OSLAR_EL1, SPSR_EL1};
+use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
+use tock_registers::{register_bitfields, LocalRegisterCopy};
+
+// The architectural maximum, the Cortex-A53 implements 6 and 4
+const MAX_BREAKPOINTS: usize = 16;
+const MAX_WATCHPOINTS: usize = 16;
+
+#[derive(Debug)]
+pub enum DebugError {
+    NoFreeSlot,
+    InvalidRange,
+    // The breakpoint or watchpoint to clear isn't set
+    NotSet,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum WatchKind {
+    Load,
+    Store,
+    Any,
+}
+
+register_bitfields! {
+    u64,
+
+    DBGBCR [
+        ENABLE OFFSET(0)  NUMBITS(1) [],
+        // Privileged mode control
+        PMC    OFFSET(1)  NUMBITS(2) [
+            EL1 = 0b01,
+        ],
+        // Byte address select, which halfwords of the instruction match
+        BAS    OFFSET(5)  NUMBITS(4) [
+            AArch64 = 0b1111,
+        ],
+        // Breakpoint type
+        BT     OFFSET(20) NUMBITS(4) [
+            UnlinkedAddressMatch = 0b0000,
+        ],
+    ],
+
+    DBGWCR [
+        ENABLE OFFSET(0)  NUMBITS(1) [],
+        // Privileged access control
+        PAC    OFFSET(1)  NUMBITS(2) [
+            EL1 = 0b01,
+        ],
+        // Load/store control
+        LSC    OFFSET(3)  NUMBITS(2) [
+            Load = 0b01,
+            Store = 0b10,
+            Any = 0b11,
+        ],
+        // Byte address select, which bytes of the doubleword match
+        BAS    OFFSET(5)  NUMBITS(8) [],
+        // Address mask, how many low bits of the address are ignored
+        MASK   OFFSET(24) NUMBITS(5) [],
+    ],
+
+    MDSCR_EL1 [
+        // Software step enable
+        SS  OFFSET(0)  NUMBITS(1) [],
+        // Local (kernel) debug enable
+        KDE OFFSET(13) NUMBITS(1) [],
+        // Monitor debug events, enables breakpoints and watchpoints
+        MDE OFFSET(15) NUMBITS(1) [],
+    ],
+}
+
+// The aarch64_cpu crate doesn't provide MDSCR_EL1
+struct MdscrEl1;
+
+impl Readable for MdscrEl1 {
+    type T = u64;
+    type R = MDSCR_EL1::Register;
+
+    fn get(&self) -> u64 {
+        let value;
+        // SAFETY: Reading MDSCR_EL1 has no side effects
+        unsafe {
+            core::arch::asm!("mrs {}, mdscr_el1", out(reg) value, options(nomem, nostack));
+        }
+        value
+    }
+}
+
+impl Writeable for MdscrEl1 {
+    type T = u64;
+    type R = MDSCR_EL1::Register;
+
+    fn set(&self, value: u64) {
+        // SAFETY: Callers are responsible for the debug configuration they set
+        unsafe {
+            core::arch::asm!("msr mdscr_el1, {}", in(reg) value, options(nomem, nostack));
+        }
+    }
+}
+
+const MDSCR_EL1: MdscrEl1 = MdscrEl1;
+
+// The breakpoint and watchpoint registers have the index in their names, so
+// each index needs its own instruction
+macro_rules! numbered_register {
+    (read $name:literal, $n:expr) => {
+        numbered_register!(@read $name, $n, [0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15])
+    };
+    (write $name:literal, $n:expr, $value:expr) => {
+        numbered_register!(@write $name, $n, $value, [0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15])
+    };
+    (@read $name:literal, $n:expr, [$($i:literal)*]) => {{
+        let value: u64;
+        match $n {
+            // SAFETY: Reading the debug registers has no side effects
+            $($i => unsafe {
+                core::arch::asm!(
+                    concat!("mrs {}, ", $name, $i, "_el1"),
+                    out(reg) value,
+                    options(nomem, nostack),
+                )
+            },)*
+            _ => unreachable!(),
+        }
+        value
+    }};
+    (@write $name:literal, $n:expr, $value:expr, [$($i:literal)*]) => {
+        match $n {
+            // SAFETY: Breakpoints and watchpoints only cause debug exceptions,
+            // which are handled below
+            $($i => unsafe {
+                core::arch::asm!(
+                    concat!("msr ", $name, $i, "_el1, {}"),
+                    in(reg) $value,
+                    options(nomem, nostack),
+                )
+            },)*
+            _ => unreachable!(),
+        }
+    };
+}
+
+fn set_breakpoint_control(n: usize, control: LocalRegisterCopy<u64, DBGBCR::Register>) {
+    numbered_register!(write "dbgbcr", n, control.get());
+}
+
+fn breakpoint_control(n: usize) -> LocalRegisterCopy<u64, DBGBCR::Register> {
+    LocalRegisterCopy::new(numbered_register!(read "dbgbcr", n))
+}
+
+fn set_watchpoint_control(n: usize, control: LocalRegisterCopy<u64, DBGWCR::Register>) {
+    numbered_register!(write "dbgwcr", n, control.get());
+}
+
+fn watchpoint_control(n: usize) -> LocalRegisterCopy<u64, DBGWCR::Register> {
+    LocalRegisterCopy::new(numbered_register!(read "dbgwcr", n))
+}
+
+fn num_breakpoints() -> usize {
+    ID_AA64DFR0_EL1.read(ID_AA64DFR0_EL1::BRPs) as usize + 1
+}
+
+fn num_watchpoints() -> usize {
+    ID_AA64DFR0_EL1.read(ID_AA64DFR0_EL1::WRPs) as usize + 1
+}
+
+struct Slots {
+    // Bitmasks of the breakpoints and watchpoints in use
+    breakpoints: u16,
+    watchpoints: u16,
+}
+
+static SLOTS: SpinLock<Slots> = SpinLock::new(Slots {
+    breakpoints: 0,
+    watchpoints: 0,
+});
+
+fn allocate_slot(in_use: &mut u16, count: usize) -> Result<usize, DebugError> {
+    let n = (0..count)
+        .find(|&n| *in_use & (1 << n) == 0)
+        .ok_or(DebugError::NoFreeSlot)?;
+    *in_use |= 1 << n;
+    Ok(n)
+}
+
+pub fn init() {
+    // The OS lock is set on reset and blocks all debug exceptions except BRK
+    OSLAR_EL1.write(OSLAR_EL1::OSLK::Unlocked);
+
+    // Start from a clean state, the registers are UNKNOWN on reset
+    for n in 0..num_breakpoints().min(MAX_BREAKPOINTS) {
+        set_breakpoint_control(n, LocalRegisterCopy::new(0));
+    }
+    for n in 0..num_watchpoints().min(MAX_WATCHPOINTS) {
+        set_watchpoint_control(n, LocalRegisterCopy::new(0));
+    }
+
+    MDSCR_EL1.modify(MDSCR_EL1::KDE::SET + MDSCR_EL1::MDE::SET);
+    barrier::isb(barrier::SY);
+
+    DAIF.modify(DAIF::D::Unmasked);
+}
+
+/// Sets a hardware breakpoint on the instruction at `addr` and returns its
+/// index
+pub fn set_breakpoint(addr: u64) -> Result<usize, DebugError> {
+    if !addr.is_multiple_of(4) {
+        return Err(DebugError::InvalidRange);
+    }
+
+    let n = allocate_slot(
+        &mut SLOTS.lock().breakpoints,
+        num_breakpoints().min(MAX_BREAKPOINTS),
+    )?;
+
+    numbered_register!(write "dbgbvr", n, addr);
+    let mut control = LocalRegisterCopy::new(0);
+    control.write(
+        DBGBCR::ENABLE::SET
+            + DBGBCR::PMC::EL1
+            + DBGBCR::BAS::AArch64
+            + DBGBCR::BT::UnlinkedAddressMatch,
+    );
+    set_breakpoint_control(n, control);
+    barrier::isb(barrier::SY);
+
+    Ok(n)
+}
+
+pub fn clear_breakpoint(n: usize) -> Result<(), DebugError> {
+    let mut slots = SLOTS.lock();
+    if n >= MAX_BREAKPOINTS || slots.breakpoints & (1 << n) == 0 {
+        return Err(DebugError::NotSet);
+    }
+    set_breakpoint_control(n, LocalRegisterCopy::new(0));
+    barrier::isb(barrier::SY);
+    slots.breakpoints &= !(1 << n);
+    Ok(())
+}
+
+/// Sets a hardware watchpoint on `len` bytes starting at `addr` and returns
+/// its index. Either the range has to be within a single 8-byte aligned
+/// doubleword, or `len` has to be a power of two of at least 8 bytes and
+/// `addr` aligned to it, e.g. a whole page.
+pub fn set_watchpoint(addr: u64, len: usize, kind: WatchKind) -> Result<usize, DebugError> {
+    let len = len as u64;
+    let (base, bas, mask) = if len > 0 && (addr % 8) + len <= 8 {
+        (addr & !7, ((1 << len) - 1) << (addr % 8), 0)
+    } else if len.is_power_of_two() && len >= 8 && addr.is_multiple_of(len) {
+        // Masked watchpoints must select all bytes. A mask of 1 or 2 is
+        // reserved, so 8 bytes use plain byte selection.
+        let mask = if len == 8 {
+            0
+        } else {
+            len.trailing_zeros() as u64
+        };
+        (addr, 0xff, mask)
+    } else {
+        return Err(DebugError::InvalidRange);
+    };
+
+    let n = allocate_slot(
+        &mut SLOTS.lock().watchpoints,
+        num_watchpoints().min(MAX_WATCHPOINTS),
+    )?;
+
+    let lsc = match kind {
+        WatchKind::Load => DBGWCR::LSC::Load,
+        WatchKind::Store => DBGWCR::LSC::Store,
+        WatchKind::Any => DBGWCR::LSC::Any,
+    };
+
+    numbered_register!(write "dbgwvr", n, base);
+    let mut control = LocalRegisterCopy::new(0);
+    control.write(
+        DBGWCR::ENABLE::SET
+            + DBGWCR::PAC::EL1
+            + lsc
+            + DBGWCR::BAS.val(bas)
+            + DBGWCR::MASK.val(mask),
+    );
+    set_watchpoint_control(n, control);
+    barrier::isb(barrier::SY);
+
+    Ok(n)
+}
+
+pub fn clear_watchpoint(n: usize) -> Result<(), DebugError> {
+    let mut slots = SLOTS.lock();
+    if n >= MAX_WATCHPOINTS || slots.watchpoints & (1 << n) == 0 {
+        return Err(DebugError::NotSet);
+    }
+    set_watchpoint_control(n, LocalRegisterCopy::new(0));
+    barrier::isb(barrier::SY);
+    slots.watchpoints &= !(1 << n);
+    Ok(())
+}
+
+#[derive(Clone, Copy)]
+struct Step {
+    // The SPSR of the stepped context before start_step() changed it
+    spsr: u64,
+    // Bitmasks of the breakpoints and watchpoints to enable after the step
+    rearm_breakpoints: u16,
+    rearm_watchpoints: u16,
+    // Whether the GDB stub asked for this step
+    for_gdb: bool,
+}
+
+// The step in progress on each CPU, if any
+static STEPS: SpinLock<[Option<Step>; NUM_CPUS]> = SpinLock::new([None; NUM_CPUS]);
+
+// Arranges for a software step exception after the instruction at ELR
+// executes. Interrupts stay masked for the step so that it doesn't land in
+// the IRQ handler.
+fn start_step(eframe: &mut ExceptionFrame, update: impl FnOnce(&mut Step)) {
+    let mut steps = STEPS.lock();
+    let step = steps[cpu::current_id()].get_or_insert(Step {
+        spsr: eframe.spsr_el1,
+        rearm_breakpoints: 0,
+        rearm_watchpoints: 0,
+        for_gdb: false,
+    });
+    update(step);
+
+    let mut spsr = LocalRegisterCopy::<u64, SPSR_EL1::Register>::new(eframe.spsr_el1);
+    spsr.modify(SPSR_EL1::SS::SET + SPSR_EL1::I::Masked + SPSR_EL1::D::Unmasked);
+    eframe.spsr_el1 = spsr.get();
+
+    MDSCR_EL1.modify(MDSCR_EL1::SS::SET);
+    barrier::isb(barrier::SY);
+}
+
+/// Single steps the instruction at ELR on behalf of the GDB stub. The stub
+/// gets the software step exception.
+#[cfg(feature = "gdb")]
+pub fn start_step_for_gdb(eframe: &mut ExceptionFrame) {
+    start_step(eframe, |step| step.for_gdb = true);
+}
+
+// Returns true if the GDB stub asked for the step
+fn finish_step(eframe: &mut ExceptionFrame) -> bool {
+    MDSCR_EL1.modify(MDSCR_EL1::SS::CLEAR);
+
+    let Some(step) = STEPS.lock()[cpu::current_id()].take() else {
+        barrier::isb(barrier::SY);
+        return false;
+    };
+
+    for n in 0..MAX_BREAKPOINTS {
+        if step.rearm_breakpoints & (1 << n) != 0 {
+            let mut control = breakpoint_control(n);
+            control.modify(DBGBCR::ENABLE::SET);
+            set_breakpoint_control(n, control);
+        }
+    }
+    for n in 0..MAX_WATCHPOINTS {
+        if step.rearm_watchpoints & (1 << n) != 0 {
+            let mut control = watchpoint_control(n);
+            control.modify(DBGWCR::ENABLE::SET);
+            set_watchpoint_control(n, control);
+        }
+    }
+    barrier::isb(barrier::SY);
+
+    // Restore the interrupt and debug masks of the stepped context. The
+    // stepped instruction can't have changed them since it ran with
+    // interrupts masked and debug exceptions unmasked by start_step().
+    let old = LocalRegisterCopy::<u64, SPSR_EL1::Register>::new(step.spsr);
+    let mut spsr = LocalRegisterCopy::<u64, SPSR_EL1::Register>::new(eframe.spsr_el1);
+    spsr.modify(
+        SPSR_EL1::SS::CLEAR
+            + SPSR_EL1::I.val(old.read(SPSR_EL1::I))
+            + SPSR_EL1::D.val(old.read(SPSR_EL1::D)),
+    );
+    eframe.spsr_el1 = spsr.get();
+
+    step.for_gdb
+}
+
+// Disables the enabled breakpoints and returns a bitmask of them
+fn disable_breakpoints() -> u16 {
+    let mut disabled = 0;
+    for n in 0..num_breakpoints().min(MAX_BREAKPOINTS) {
+        let mut control = breakpoint_control(n);
+        if control.is_set(DBGBCR::ENABLE) {
+            control.modify(DBGBCR::ENABLE::CLEAR);
+            set_breakpoint_control(n, control);
+            disabled |= 1 << n;
+        }
+    }
+    disabled
+}
+
+// Disables the enabled watchpoints and returns a bitmask of them
+fn disable_watchpoints() -> u16 {
+    let mut disabled = 0;
+    for n in 0..num_watchpoints().min(MAX_WATCHPOINTS) {
+        let mut control = watchpoint_control(n);
+        if control.is_set(DBGWCR::ENABLE) {
+            control.modify(DBGWCR::ENABLE::CLEAR);
+            set_watchpoint_control(n, control);
+            disabled |= 1 << n;
+        }
+    }
+    disabled
+}
+
+/// Handles breakpoint, watchpoint and software step exceptions from EL1.
+/// Returns false if the exception is none of these or if it's a step the GDB
+/// stub asked for, which the caller has to pass on to the stub.
+pub fn handle_exception(eframe: &mut ExceptionFrame) -> bool {
+    let syndrome = esr::decode(eframe.esr_el1);
+    let pc = eframe.elr_el1;
+
+    match syndrome.class {
+        ExceptionClass::BreakpointCurrentEL => {
+            println!("Hardware breakpoint hit at pc {pc:#x}");
+            backtrace::print(eframe.fp(), pc);
+
+            let disabled = disable_breakpoints();
+            start_step(eframe, |step| step.rearm_breakpoints |= disabled);
+            true
+        }
+        ExceptionClass::WatchpointCurrentEL => {
+            let wnr = matches!(syndrome.iss, Iss::Watchpoint { wnr: true, .. });
+            println!(
+                "Watchpoint hit {} address {:#x} at pc {pc:#x}",
+                if wnr { "writing" } else { "reading" },
+                eframe.far_el1
+            );
+            backtrace::print(eframe.fp(), pc);
+
+            // The hardware doesn't report which watchpoint hit, so disable
+            // all of them
+            let disabled = disable_watchpoints();
+            start_step(eframe, |step| step.rearm_watchpoints |= disabled);
+            true
+        }
+        ExceptionClass::SoftwareStepCurrentEL => !finish_step(eframe),
+        _ => false,
+    }
+}
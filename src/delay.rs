@@ -1,33 +1,19 @@
+use crate::time::Instant;
 use core::time::Duration;
 
-/// Busy-wait for the specified duration. The highest precision that can be
-/// used is in milliseconds (anything else will be rounded to the closest
-/// millisecond value).
+/// Busy-wait for at least the specified duration. The precision is one tick
+/// of the generic timer, tens of nanoseconds on the RPi3.
 pub fn busy_wait(duration: Duration) {
-    let frequency: u64;
-    // SAFETY: Executing a simple assembly instruction
-    unsafe {
-        core::arch::asm!("mrs {0}, CNTFRQ_EL0", out(reg) frequency);
+    let deadline = Instant::now().saturating_add(duration);
+    while Instant::now() < deadline {
+        core::hint::spin_loop();
     }
+}
 
-    let start: u64;
-    // SAFETY: Executing a simple assembly instruction
-    unsafe {
-        core::arch::asm!("mrs {0}, CNTPCT_EL0", out(reg) start);
-    }
-
-    let ticks_needed = (frequency * duration.as_millis() as u64) / 1000;
-    let deadline = start.wrapping_add(ticks_needed);
-
-    loop {
-        let now: u64;
-        // SAFETY: Executing a simple assembly instruction
-        unsafe {
-            core::arch::asm!("mrs {0}, CNTPCT_EL0", out(reg) now);
-        }
+pub fn delay_us(us: u64) {
+    busy_wait(Duration::from_micros(us));
+}
 
-        if now >= deadline {
-            break;
-        }
-    }
+pub fn delay_ns(ns: u64) {
+    busy_wait(Duration::from_nanos(ns));
 }
@@ -1,7 +1,13 @@
+pub mod framebuffer;
 pub mod gpio;
 pub mod interrupt_controller;
 pub mod mailbox;
+pub mod pm;
+pub mod rng;
+pub mod serial;
+pub mod system_timer;
 pub mod uart_mini;
+pub mod uart_pl011;
 
 use crate::address::{AddressVirtual, PERIPHERALS_BASE};
 use aarch64_cpu::asm;
@@ -0,0 +1,191 @@
+// The framebuffer the VideoCore firmware scans out to HDMI. The firmware
+// allocates it in its own memory when asked through the mailbox. That memory
+// isn't part of the runtime page tables, so it's mapped here at its linear
+// map address as non-cacheable memory: the display sees writes without any
+// cache maintenance and consecutive writes can still be combined into bursts.
+//
+// For double buffering the virtual framebuffer is made several screens high,
+// one buffer below the other. The display shows the buffer at the virtual
+// offset and flip() moves the offset to the next buffer. Buffer 0 belongs to
+// the framebuffer console, so flip() only cycles through the buffers after it
+// and show_console() switches back to buffer 0.
+
+use crate::drivers::mailbox::{self, MailboxError, PixelOrder};
+use crate::graphics::{PixelFormat, Surface};
+use crate::locking::SpinLock;
+use crate::memory::PAGE_SIZE;
+use crate::{info, paging};
+
+const DEPTH: u32 = 32;
+
+#[derive(Debug)]
+pub enum FramebufferError {
+    Mailbox(MailboxError),
+    // The firmware allocated a framebuffer with this many bits per pixel
+    UnsupportedDepth(u32),
+    AlreadyInitialized,
+    NotInitialized,
+    // flip() was called but there are fewer than two buffers besides the
+    // console's
+    SingleBuffered,
+    // The firmware set the vertical offset to this instead of the one asked
+    // for
+    OffsetRejected(u32),
+}
+
+impl core::fmt::Display for FramebufferError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Mailbox(e) => write!(f, "{e}"),
+            Self::UnsupportedDepth(depth) => write!(f, "unsupported depth {depth}"),
+            Self::AlreadyInitialized => write!(f, "already initialized"),
+            Self::NotInitialized => write!(f, "not initialized"),
+            Self::SingleBuffered => write!(f, "single buffered"),
+            Self::OffsetRejected(y) => write!(f, "firmware moved the offset to y={y}"),
+        }
+    }
+}
+
+impl From<MailboxError> for FramebufferError {
+    fn from(e: MailboxError) -> Self {
+        Self::Mailbox(e)
+    }
+}
+
+/// A handle to the mapped framebuffer
+#[derive(Clone, Copy)]
+pub struct Framebuffer {
+    base: u64,
+    width: u32,
+    height: u32,
+    // Bytes per line, which can be more than the width needs
+    pitch: u32,
+    format: PixelFormat,
+    bgr: bool,
+    buffers: u32,
+}
+
+impl Framebuffer {
+    pub fn buffers(&self) -> u32 {
+        self.buffers
+    }
+
+    /// Returns a surface for one of the buffers. Buffer 0 is the one shown
+    /// after init().
+    pub fn buffer(&self, index: u32) -> Surface {
+        assert!(index < self.buffers);
+        let offset = (index * self.height) as u64 * self.pitch as u64;
+        // SAFETY: Each buffer is height lines of pitch bytes inside the
+        // framebuffer, which stays mapped
+        unsafe {
+            Surface::new(
+                self.base + offset,
+                self.width,
+                self.height,
+                self.pitch,
+                self.format,
+                self.bgr,
+            )
+        }
+    }
+}
+
+static FRAMEBUFFER: SpinLock<Option<Framebuffer>> = SpinLock::new(None);
+
+// The buffer the display shows
+static FRONT: SpinLock<u32> = SpinLock::new(0);
+
+/// Allocates a framebuffer of the given resolution with up to `buffers`
+/// screens worth of memory, and maps it. The firmware can pick a different
+/// resolution, e.g. the one of the attached display, and fewer buffers.
+pub fn init(width: u32, height: u32, buffers: u32) -> Result<Framebuffer, FramebufferError> {
+    let mut framebuffer = FRAMEBUFFER.lock();
+    if framebuffer.is_some() {
+        return Err(FramebufferError::AlreadyInitialized);
+    }
+
+    let fb = mailbox::allocate_framebuffer(width, height, height * buffers, DEPTH)?;
+    let format =
+        PixelFormat::from_depth(fb.depth).ok_or(FramebufferError::UnsupportedDepth(fb.depth))?;
+
+    let pa = fb.memory.base();
+    let offset = pa.as_u64() % PAGE_SIZE;
+    let va = pa.as_virtual().align_down(PAGE_SIZE);
+    let size = (offset + fb.memory.size()).next_multiple_of(PAGE_SIZE);
+    paging::map_kernel_write_combining(va, va.as_physical(), size);
+
+    let handle = Framebuffer {
+        base: va.as_u64() + offset,
+        width: fb.width,
+        height: fb.height,
+        pitch: fb.pitch,
+        format,
+        bgr: fb.pixel_order == PixelOrder::Bgr,
+        buffers: (fb.virtual_height / fb.height.max(1)).clamp(1, buffers),
+    };
+    info!(
+        "Framebuffer {}x{} {:?} pitch {} buffers {} at {:#x}",
+        handle.width,
+        handle.height,
+        handle.format,
+        handle.pitch,
+        handle.buffers,
+        pa.as_u64()
+    );
+
+    *framebuffer = Some(handle);
+    Ok(handle)
+}
+
+/// Returns the framebuffer if init() succeeded
+pub fn get() -> Option<Framebuffer> {
+    *FRAMEBUFFER.lock()
+}
+
+// Returns the buffer flip() shows next: the one after the front buffer among
+// buffers 1 to buffers - 1, or buffer 1 while the console is shown
+fn next_buffer(fb: &Framebuffer, front: u32) -> Result<u32, FramebufferError> {
+    if fb.buffers < 3 {
+        return Err(FramebufferError::SingleBuffered);
+    }
+    Ok(front % (fb.buffers - 1) + 1)
+}
+
+/// Returns the buffer flip() shows next, which is never the console's
+pub fn back_buffer() -> Result<Surface, FramebufferError> {
+    let fb = get().ok_or(FramebufferError::NotInitialized)?;
+    Ok(fb.buffer(next_buffer(&fb, *FRONT.lock())?))
+}
+
+/// Shows the back buffer. Returns once the display has switched to it, so the
+/// previous front buffer can be drawn on without tearing. Where the firmware
+/// can't wait for vsync the switch happens at the next frame anyway, but
+/// drawing can start before it.
+pub fn flip() -> Result<(), FramebufferError> {
+    let fb = get().ok_or(FramebufferError::NotInitialized)?;
+    let mut front = FRONT.lock();
+    let next = next_buffer(&fb, *front)?;
+    show(&fb, &mut front, next)
+}
+
+/// Shows the console's buffer again after flip()
+pub fn show_console() -> Result<(), FramebufferError> {
+    let fb = get().ok_or(FramebufferError::NotInitialized)?;
+    show(&fb, &mut FRONT.lock(), 0)
+}
+
+fn show(fb: &Framebuffer, front: &mut u32, index: u32) -> Result<(), FramebufferError> {
+    let (_, y) = mailbox::set_virtual_offset(0, index * fb.height)?;
+    if y != index * fb.height {
+        // FRONT must name the buffer on screen, or back_buffer() would hand
+        // it out for drawing
+        if y % fb.height == 0 && y / fb.height < fb.buffers {
+            *front = y / fb.height;
+        }
+        return Err(FramebufferError::OffsetRejected(y));
+    }
+    // The firmware applies the new offset at the start of the next frame
+    let _ = mailbox::wait_for_vsync();
+    *front = index;
+    Ok(())
+}
@@ -1,11 +1,14 @@
 use crate::drivers::{peripheral_switch_in, MMIORegisters, PERIPHERALS_BASE};
-use crate::locking::SpinLock;
+use crate::irq::{disable_irq, enable_irq, GpuIrq, Irq};
+use crate::locking::{IRQSpinLock, SpinLock};
+use crate::{delay, warn};
 use core;
-use tock_registers::interfaces::Writeable;
+use tock_registers::interfaces::{Readable, Writeable};
 use tock_registers::register_structs;
-use tock_registers::registers::WriteOnly;
+use tock_registers::registers::{ReadOnly, ReadWrite, WriteOnly};
 
 #[allow(dead_code)]
+#[derive(Clone, Copy)]
 pub(crate) enum PinMode {
     Input = 0b000,
     Output = 0b001,
@@ -17,6 +20,48 @@ pub(crate) enum PinMode {
     Alt5 = 0b010,
 }
 
+#[derive(Clone, Copy)]
+pub(crate) enum PullMode {
+    Off = 0b00,
+    Down = 0b01,
+    Up = 0b10,
+}
+
+/// The events a pin can raise an interrupt on. The asynchronous edge events
+/// aren't sampled by the system clock, so they catch very short pulses.
+#[derive(Clone, Copy)]
+pub(crate) enum Event {
+    RisingEdge,
+    FallingEdge,
+    High,
+    Low,
+    AsyncRisingEdge,
+    AsyncFallingEdge,
+}
+
+impl Event {
+    const ALL: [Event; 6] = [
+        Event::RisingEdge,
+        Event::FallingEdge,
+        Event::High,
+        Event::Low,
+        Event::AsyncRisingEdge,
+        Event::AsyncFallingEdge,
+    ];
+}
+
+// Called in interrupt context with the number of the pin that raised the event
+pub(crate) type EventHandler = fn(u8);
+
+#[derive(Debug)]
+pub(crate) enum GpioError {
+    InvalidPin,
+    // The pin is claimed already, by the owner named here
+    AlreadyClaimed(&'static str),
+}
+
+/// A claimed GPIO pin. Only one handle exists per pin at a time and the pin
+/// stays claimed until the handle is dropped.
 pub(crate) struct GPIOPin {
     pin: u8,
 }
@@ -30,6 +75,20 @@ const REGS: MMIORegisters<GPIORegisters> =
 // to make it protect only a certain field of the GPIORegisters struct.
 static GPFSELX_SPINLOCK: SpinLock<()> = SpinLock::new(());
 
+// Protects the GPPUD/GPPUDCLKn sequence, which can't be interleaved
+static PULL_SPINLOCK: SpinLock<()> = SpinLock::new(());
+
+// Protects the event detect enable registers, which are changed from
+// interrupt context too
+static EVENTS_SPINLOCK: IRQSpinLock<()> = IRQSpinLock::new(());
+
+static HANDLERS: IRQSpinLock<[Option<EventHandler>; GPIOPin::NUM_GPIO_PINS as usize]> =
+    IRQSpinLock::new([None; GPIOPin::NUM_GPIO_PINS as usize]);
+
+// The name of the owner of each claimed pin
+static OWNERS: SpinLock<[Option<&'static str>; GPIOPin::NUM_GPIO_PINS as usize]> =
+    SpinLock::new([None; GPIOPin::NUM_GPIO_PINS as usize]);
+
 register_structs! {
     #[allow(non_snake_case)]
     GPIORegisters {
@@ -40,6 +99,27 @@ register_structs! {
         (0x028 => GPCLR0: WriteOnly<u32>),
         (0x02c => GPCLR1: WriteOnly<u32>),
         (0x030 => _reserved2),
+        (0x034 => GPLEV0: ReadOnly<u32>),
+        (0x038 => GPLEV1: ReadOnly<u32>),
+        (0x03c => _reserved3),
+        (0x040 => GPEDS: [ReadWrite<u32>; 2]),
+        (0x048 => _reserved4),
+        (0x04c => GPREN: [ReadWrite<u32>; 2]),
+        (0x054 => _reserved5),
+        (0x058 => GPFEN: [ReadWrite<u32>; 2]),
+        (0x060 => _reserved6),
+        (0x064 => GPHEN: [ReadWrite<u32>; 2]),
+        (0x06c => _reserved7),
+        (0x070 => GPLEN: [ReadWrite<u32>; 2]),
+        (0x078 => _reserved8),
+        (0x07c => GPAREN: [ReadWrite<u32>; 2]),
+        (0x084 => _reserved9),
+        (0x088 => GPAFEN: [ReadWrite<u32>; 2]),
+        (0x090 => _reserved10),
+        (0x094 => GPPUD: ReadWrite<u32>),
+        (0x098 => GPPUDCLK0: ReadWrite<u32>),
+        (0x09c => GPPUDCLK1: ReadWrite<u32>),
+        (0x0a0 => _reserved11),
         (0x0b4 => @END),
     }
 }
@@ -47,12 +127,32 @@ register_structs! {
 impl GPIOPin {
     const NUM_GPIO_PINS: u8 = 54;
 
-    pub fn new(pin: u8) -> Self {
-        assert!(pin < Self::NUM_GPIO_PINS);
-        GPIOPin { pin }
+    /// Takes ownership of a pin. `owner` names the driver claiming it, for
+    /// reporting conflicts.
+    pub fn claim(pin: u8, owner: &'static str) -> Result<Self, GpioError> {
+        if pin >= Self::NUM_GPIO_PINS {
+            return Err(GpioError::InvalidPin);
+        }
+
+        let mut owners = OWNERS.lock();
+        if let Some(current) = owners[pin as usize] {
+            return Err(GpioError::AlreadyClaimed(current));
+        }
+        owners[pin as usize] = Some(owner);
+        Ok(GPIOPin { pin })
+    }
+
+    pub fn number(&self) -> u8 {
+        self.pin
     }
 
-    pub fn select_mode(self, mode: PinMode) {
+    // The bit for this pin in the registers that have one bit per pin, split
+    // in two banks
+    fn bank_bit(&self) -> (usize, u32) {
+        ((self.pin / 32) as usize, 1 << (self.pin % 32))
+    }
+
+    pub fn select_mode(&self, mode: PinMode) {
         peripheral_switch_in();
         // Used to pick a register from GPFSEL0 to GPFSEL5
         let reg_index = self.pin as usize / 10;
@@ -76,23 +176,182 @@ impl GPIOPin {
         };
     }
 
-    #[allow(dead_code)]
-    pub fn set_high(self) {
+    pub fn set_high(&self) {
         peripheral_switch_in();
-        if self.pin < 32 {
-            REGS.GPSET0.set(1 << self.pin);
-        } else {
-            REGS.GPSET1.set(1 << (self.pin - 32));
+        match self.bank_bit() {
+            (0, bit) => REGS.GPSET0.set(bit),
+            (_, bit) => REGS.GPSET1.set(bit),
         }
     }
 
-    #[allow(dead_code)]
-    pub fn set_low(self) {
+    pub fn set_low(&self) {
         peripheral_switch_in();
-        if self.pin < 32 {
-            REGS.GPCLR0.set(1 << self.pin);
+        match self.bank_bit() {
+            (0, bit) => REGS.GPCLR0.set(bit),
+            (_, bit) => REGS.GPCLR1.set(bit),
+        }
+    }
+
+    /// Returns the level of the pin, whatever its mode
+    pub fn read(&self) -> bool {
+        peripheral_switch_in();
+        let (bank, bit) = self.bank_bit();
+        let levels = if bank == 0 {
+            REGS.GPLEV0.get()
         } else {
-            REGS.GPCLR1.set(1 << (self.pin - 32));
+            REGS.GPLEV1.get()
+        };
+        levels & bit != 0
+    }
+
+    /// Inverts the level of an output pin
+    pub fn toggle(&self) {
+        if self.read() {
+            self.set_low();
+        } else {
+            self.set_high();
+        }
+    }
+
+    /// Enables the pull-up or pull-down resistor of the pin, or disables both.
+    /// There is no way to read the current setting back.
+    pub fn set_pull(&self, mode: PullMode) {
+        // The control signal needs 150 cycles to set up and to be clocked
+        // into the pin, see the GPPUDCLKn description in BCM2837 section 6.1.
+        // Those are cycles of the core clock, which runs slower than the ARM,
+        // and at 250MHz or more they take at most 600ns.
+        let wait_150_cycles = || delay::delay_ns(600);
+        let (bank, bit) = self.bank_bit();
+        let gppudclk = if bank == 0 {
+            &REGS.GPPUDCLK0
+        } else {
+            &REGS.GPPUDCLK1
+        };
+
+        let _lock = PULL_SPINLOCK.lock();
+        peripheral_switch_in();
+        REGS.GPPUD.set(mode as u32);
+        wait_150_cycles();
+        gppudclk.set(bit);
+        wait_150_cycles();
+        REGS.GPPUD.set(0);
+        gppudclk.set(0);
+    }
+
+    fn event_register(event: Event) -> &'static [ReadWrite<u32>; 2] {
+        match event {
+            Event::RisingEdge => &REGS.GPREN,
+            Event::FallingEdge => &REGS.GPFEN,
+            Event::High => &REGS.GPHEN,
+            Event::Low => &REGS.GPLEN,
+            Event::AsyncRisingEdge => &REGS.GPAREN,
+            Event::AsyncFallingEdge => &REGS.GPAFEN,
+        }
+    }
+
+    /// Starts detecting an event on the pin. Several events can be enabled at
+    /// once, e.g. both edges.
+    pub fn enable_event(&self, event: Event) {
+        let (bank, bit) = self.bank_bit();
+        let reg = &Self::event_register(event)[bank];
+        let _lock = EVENTS_SPINLOCK.lock();
+        peripheral_switch_in();
+        reg.set(reg.get() | bit);
+    }
+
+    pub fn disable_event(&self, event: Event) {
+        let (bank, bit) = self.bank_bit();
+        let reg = &Self::event_register(event)[bank];
+        let _lock = EVENTS_SPINLOCK.lock();
+        peripheral_switch_in();
+        reg.set(reg.get() & !bit);
+    }
+
+    /// Sets the function called when an enabled event is detected on the pin
+    /// and enables the interrupt of the pin's bank.
+    ///
+    /// The event is acknowledged before the handler runs. A level event is
+    /// detected again for as long as the level holds, so its handler must
+    /// disable it or remove the cause, otherwise the interrupt keeps firing.
+    pub fn set_event_handler(&self, handler: EventHandler) {
+        HANDLERS.lock()[self.pin as usize] = Some(handler);
+        enable_irq(Irq::Gpu(bank_irq(self.pin)));
+    }
+
+    /// Stops detecting events on the pin and removes its handler. The
+    /// interrupt of the pin's bank is disabled once no pin in it has a handler.
+    pub fn remove_event_handler(&self) {
+        let (bank, bit) = self.bank_bit();
+        {
+            let _lock = EVENTS_SPINLOCK.lock();
+            peripheral_switch_in();
+            for event in Event::ALL {
+                let reg = &Self::event_register(event)[bank];
+                reg.set(reg.get() & !bit);
+            }
+            // Drop an event that was detected before it was disabled
+            REGS.GPEDS[bank].set(bit);
+        }
+
+        let mut handlers = HANDLERS.lock();
+        handlers[self.pin as usize] = None;
+        if bank_pins(bank_irq(self.pin)).all(|pin| handlers[pin as usize].is_none()) {
+            disable_irq(Irq::Gpu(bank_irq(self.pin)));
+        }
+    }
+}
+
+// Gives the pin back so that it can be claimed again
+impl Drop for GPIOPin {
+    fn drop(&mut self) {
+        if HANDLERS.lock()[self.pin as usize].is_some() {
+            self.remove_event_handler();
+        }
+        OWNERS.lock()[self.pin as usize] = None;
+    }
+}
+
+// There's an interrupt for each of the 3 banks the pins are split in for
+// interrupt purposes, and one for all pins which isn't used. See the BCM2835
+// GPIO interrupt description in the Linux pinctrl-bcm2835 driver.
+fn bank_irq(pin: u8) -> GpuIrq {
+    match pin {
+        0..=27 => GpuIrq::Gpio0,
+        28..=45 => GpuIrq::Gpio1,
+        _ => GpuIrq::Gpio2,
+    }
+}
+
+fn bank_pins(irq: GpuIrq) -> core::ops::Range<u8> {
+    match irq {
+        GpuIrq::Gpio0 => 0..28,
+        GpuIrq::Gpio1 => 28..46,
+        GpuIrq::Gpio2 => 46..GPIOPin::NUM_GPIO_PINS,
+        _ => 0..GPIOPin::NUM_GPIO_PINS,
+    }
+}
+
+/// Calls the handlers of the pins in the bank of `irq` with a pending event
+pub fn process_irq(irq: GpuIrq) {
+    peripheral_switch_in();
+    let status = [REGS.GPEDS[0].get(), REGS.GPEDS[1].get()];
+
+    for pin in bank_pins(irq) {
+        let (bank, bit) = (pin as usize / 32, 1 << (pin % 32));
+        if status[bank] & bit == 0 {
+            continue;
+        }
+
+        // Writing 1 clears the bit. Only the bits of this pin are written so
+        // that events that arrive in the meantime aren't lost.
+        REGS.GPEDS[bank].set(bit);
+
+        // Don't hold the lock while the handler runs so that it can change
+        // handlers
+        let handler = HANDLERS.lock()[pin as usize];
+        match handler {
+            Some(handler) => handler(pin),
+            None => warn!("GPIO{pin}: event without a handler"),
         }
     }
 }
@@ -45,7 +45,6 @@ pub fn enable_irq(irq: Irq) {
     }
 }
 
-#[allow(dead_code)]
 pub fn disable_irq(irq: Irq) {
     peripheral_switch_in();
     match irq {
@@ -71,7 +70,7 @@ pub struct PendingIrqs {
 
 pub fn pending_irqs() -> PendingIrqs {
     peripheral_switch_in();
-    let gpu = REGS.IRQ_PENDING1.get() as u64 | (REGS.IRQ_PENDING2.get() as u64 >> 32);
+    let gpu = REGS.IRQ_PENDING1.get() as u64 | ((REGS.IRQ_PENDING2.get() as u64) << 32);
     let arm = REGS.IRQ_BASIC_PENDING.get() as u8;
     PendingIrqs { gpu, arm }
 }
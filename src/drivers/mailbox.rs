@@ -4,8 +4,9 @@
 use crate::address::{AddressPhysical, AddressVirtual, RangePhysical};
 use crate::drivers::{peripheral_switch_in, MMIORegisters, PERIPHERALS_BASE};
 use crate::locking::SpinLock;
-use crate::memory::{dcache_clean_va_range, dcache_invalidate_va_range};
+use crate::memory::{dcache_clean_va_range, dcache_invalidate_va_range, PAGE_SIZE};
 use aarch64_cpu::asm::barrier;
+use heapless::{String, Vec};
 use tock_registers::interfaces::{Readable, Writeable};
 use tock_registers::registers::{ReadOnly, WriteOnly};
 use tock_registers::{register_bitfields, register_structs};
@@ -16,7 +17,16 @@ const MB0: MMIORegisters<Mailbox0Registers> =
 const MB1: MMIORegisters<Mailbox1Registers> =
     unsafe { MMIORegisters::<Mailbox1Registers>::new(PERIPHERALS_BASE.add(0xB880 + 0x20)) };
 
-static MAILBOX_SPINLOCK: SpinLock<()> = SpinLock::new(());
+const MAILBOX_BUFFER_SIZE: usize = 1024;
+
+// The firmware accesses messages through their bus address, which only memory
+// in the linear map has. Messages are copied here so that callers can build
+// them anywhere, e.g. on a kernel stack outside the linear map.
+#[repr(C, align(16))]
+struct MailboxBuffer([u8; MAILBOX_BUFFER_SIZE]);
+
+static MAILBOX_BUFFER: SpinLock<MailboxBuffer> =
+    SpinLock::new(MailboxBuffer([0; MAILBOX_BUFFER_SIZE]));
 
 const CHANNEL_BITMASK: u32 = 0xf;
 const TAGS_CHANNEL: u32 = 8;
@@ -55,19 +65,29 @@ register_structs! {
     }
 }
 
-fn mailbox_send(channel: u32, buffer_addr: AddressVirtual, buffer_size: u32) {
+fn mailbox_send(channel: u32, msg_addr: AddressVirtual, msg_size: u32) {
     peripheral_switch_in();
+    let size = msg_size as usize;
+    assert!(size <= MAILBOX_BUFFER_SIZE);
+
+    let mut buffer = MAILBOX_BUFFER.lock();
+    // SAFETY: The caller's message is msg_size bytes long and we have
+    // exclusive access to the mailbox buffer
+    unsafe {
+        core::ptr::copy_nonoverlapping(msg_addr.as_u64() as *const u8, buffer.0.as_mut_ptr(), size);
+    }
+    let buffer_addr = AddressVirtual::new(buffer.0.as_ptr() as u64);
+    let buffer_size = msg_size;
 
     let mut bus_addr = buffer_addr.as_bus().as_u32();
     assert!(bus_addr & CHANNEL_BITMASK == 0);
     bus_addr |= TAGS_CHANNEL;
 
-    let _lock = MAILBOX_SPINLOCK.lock();
     while MB1.STATUS.is_set(MB1_STATUS::FULL) {}
 
-    // Since the mbox buffer lives in the kernel stack in normal cacheable
-    // memory which is non-DMA coherent we need to flush the cache lines so
-    // that the mbox controller can see what we wrote there.
+    // Since the mbox buffer lives in normal cacheable memory which is non-DMA
+    // coherent we need to flush the cache lines so that the mbox controller
+    // can see what we wrote there.
     dcache_clean_va_range(buffer_addr, buffer_size.into());
     // Use a DMB so that the write to MB1.DATA isn't re-ordered before the read
     // from MB1.STATUS OR before the cache flushing
@@ -88,6 +108,11 @@ fn mailbox_send(channel: u32, buffer_addr: AddressVirtual, buffer_size: u32) {
             break;
         }
     }
+
+    // SAFETY: Same as above
+    unsafe {
+        core::ptr::copy_nonoverlapping(buffer.0.as_ptr(), msg_addr.as_u64() as *mut u8, size);
+    }
 }
 
 #[derive(PartialEq)]
@@ -101,160 +126,557 @@ enum MailboxBufferCode {
     Error = 0x80000001,
 }
 
-#[repr(C)]
-struct MailboxMsgHeader {
-    size: u32, // buffer size in bytes (including the header values, the end tag and padding)
-    code: MailboxBufferCode, // buffer request / response code
-}
-
-#[repr(C)]
-struct MailboxMsgFooter {
-    end: u32, // must be 0
-}
-
 // --------------------------------------------------------------------------
 // Property Tags Channel
 // --------------------------------------------------------------------------
 
+#[derive(Clone, Copy, Debug)]
 #[repr(u32)]
-enum PropertyTag {
+pub enum PropertyTag {
     GetFwVersion = 0x00000001,
+    GetBoardModel = 0x00010001,
+    GetBoardRevision = 0x00010002,
+    GetBoardMacAddress = 0x00010003,
     GetBoardSerial = 0x00010004,
     GetArmMemory = 0x00010005,
     GetVideoCoreMemory = 0x00010006,
+    GetPowerState = 0x00020001,
+    SetPowerState = 0x00028001,
+    GetClockRate = 0x00030002,
+    GetVoltage = 0x00030003,
+    GetMaxClockRate = 0x00030004,
+    GetMaxVoltage = 0x00030005,
+    GetTemperature = 0x00030006,
+    GetMinClockRate = 0x00030007,
+    GetMinVoltage = 0x00030008,
+    GetTurbo = 0x00030009,
+    GetMaxTemperature = 0x0003000a,
+    SetClockRate = 0x00038002,
+    SetVoltage = 0x00038003,
+    SetTurbo = 0x00038009,
     SetOnboardLedStatus = 0x00038041,
+    AllocateFramebuffer = 0x00040001,
+    GetPitch = 0x00040008,
+    SetPhysicalSize = 0x00048003,
+    SetVirtualSize = 0x00048004,
+    SetDepth = 0x00048005,
+    SetPixelOrder = 0x00048006,
+    SetVirtualOffset = 0x00048009,
+    WaitForVsync = 0x0004800e,
+    GetCommandLine = 0x00050001,
+    GetDmaChannels = 0x00060001,
 }
 
-#[repr(C)]
-struct PropertyMsgHeader {
-    mbox_header: MailboxMsgHeader,
-    id: PropertyTag,
-    value_size: u32, // value buffer size in bytes
-    value_code: u32, // request / response code
+const BATCH_BUFFER_WORDS: usize = MAILBOX_BUFFER_SIZE / 4;
+const MAX_BATCH_TAGS: usize = 16;
+
+// Set in a tag's value_code once the firmware has processed it. The rest of
+// value_code is then the length of the response in bytes.
+const TAG_RESPONSE_BIT: u32 = 1 << 31;
+
+#[derive(Debug)]
+pub enum MailboxError {
+    // The tags don't fit in a single message
+    BatchFull,
+    // The firmware failed to parse the message, with the buffer response code
+    RequestFailed(u32),
+    // The firmware didn't process the tag
+    TagFailed(PropertyTag),
+    // The response of the tag is shorter than expected
+    ShortResponse(PropertyTag),
+    // The firmware doesn't know the device, clock or voltage ID
+    InvalidId,
+    // The requested value is outside the range the firmware reports
+    OutOfRange,
 }
 
-macro_rules! define_and_init_property_msg {
-    ($struct_name:ident, $var_name:ident, $tag_id:expr, $( $field:ident : $ty:ty = $val:expr),* $(,)?) => {
-        #[repr(C, align(16))]
-        struct $struct_name {
-            tag_header: PropertyMsgHeader,
-            $( $field: $ty, )*
-            tag_footer: MailboxMsgFooter,
+impl core::fmt::Display for MailboxError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::BatchFull => write!(f, "too many property tags"),
+            Self::RequestFailed(code) => write!(f, "request failed with code {code:#x}"),
+            Self::TagFailed(tag) => write!(f, "tag {tag:?} not processed"),
+            Self::ShortResponse(tag) => write!(f, "short response to tag {tag:?}"),
+            Self::InvalidId => write!(f, "invalid ID"),
+            Self::OutOfRange => write!(f, "value out of range"),
         }
+    }
+}
 
-        let value_size = 0 $( + core::mem::size_of::<$ty>() as u32 )*;
-        let full_size = core::mem::size_of::<PropertyMsgHeader>() as u32
-            + core::mem::size_of::<MailboxMsgFooter>() as u32
-            + value_size;
-
-        let mut uninit = core::mem::MaybeUninit::<$struct_name>::uninit();
-        let p = uninit.as_mut_ptr();
-        // SAFETY: The pointers satisfy the requirements set by write_volatile()
-        // and assume_init() is called after the memory is initialised.
-        let $var_name = unsafe {
-            core::ptr::write_volatile(&raw mut (*p).tag_header.mbox_header.size, full_size);
-            core::ptr::write_volatile(
-                &raw mut (*p).tag_header.mbox_header.code,
-                MailboxBufferCode::ProcessRequest,
-            );
-            core::ptr::write_volatile(&raw mut (*p).tag_header.id, $tag_id);
-            core::ptr::write_volatile(&raw mut (*p).tag_header.value_size, value_size);
-            core::ptr::write_volatile(&raw mut (*p).tag_header.value_code, 0);
-            $(
-                core::ptr::write_volatile(&raw mut (*p).$field, $val);
-            )*
-            core::ptr::write_volatile(&raw mut (*p).tag_footer.end, 0);
-            uninit.assume_init()
-        };
-    };
+/// Identifies a tag added to a PropertyBatch
+#[derive(Clone, Copy)]
+pub struct TagHandle(usize);
+
+/// Several property tags sent to the firmware in a single message. Each tag
+/// gets its own response which is checked separately.
+pub struct PropertyBatch {
+    buffer: [u32; BATCH_BUFFER_WORDS],
+    // Words used so far, starting with the size and code of the buffer
+    len: usize,
+    // Each tag and where its header starts in the buffer
+    tags: Vec<(PropertyTag, usize), MAX_BATCH_TAGS>,
 }
 
-macro_rules! mailbox_send_ptag_and_handle_error {
-    ($msg:ident) => {
-        let addr = AddressVirtual::new(&$msg as *const _ as u64);
-        mailbox_send(TAGS_CHANNEL, addr, $msg.tag_header.mbox_header.size);
+impl PropertyBatch {
+    pub fn new() -> Self {
+        PropertyBatch {
+            buffer: [0; BATCH_BUFFER_WORDS],
+            len: 2,
+            tags: Vec::new(),
+        }
+    }
+
+    /// Adds a tag with `values` as its value buffer, which must also be big
+    /// enough for the response
+    pub fn add(&mut self, tag: PropertyTag, values: &[u32]) -> Result<TagHandle, MailboxError> {
+        // The tag header and values, and room for the end tag
+        if self.len + 3 + values.len() + 1 > BATCH_BUFFER_WORDS || self.tags.is_full() {
+            return Err(MailboxError::BatchFull);
+        }
+
+        let offset = self.len;
+        self.buffer[offset] = tag as u32;
+        self.buffer[offset + 1] = (values.len() * 4) as u32;
+        self.buffer[offset + 2] = 0;
+        self.buffer[offset + 3..offset + 3 + values.len()].copy_from_slice(values);
+        self.len += 3 + values.len();
+
+        self.tags.push((tag, offset)).unwrap();
+        Ok(TagHandle(self.tags.len() - 1))
+    }
+
+    /// Sends all the tags in one message. Only failures of the message as a
+    /// whole are reported here, see response() for the tags.
+    pub fn send(&mut self) -> Result<(), MailboxError> {
+        let size = (self.len + 1) * 4;
+        self.buffer[0] = size as u32;
+        self.buffer[1] = MailboxBufferCode::ProcessRequest as u32;
+        self.buffer[self.len] = 0;
+
+        let addr = AddressVirtual::new(self.buffer.as_ptr() as u64);
+        mailbox_send(TAGS_CHANNEL, addr, size as u32);
 
         // SAFETY: The pointer satisfies the requirements set by read_volatile()
-        let code = unsafe { core::ptr::read_volatile(&$msg.tag_header.mbox_header.code) };
-        if code != MailboxBufferCode::Success {
-            return Err(code as u32);
+        let code = unsafe { core::ptr::read_volatile(&self.buffer[1]) };
+        if code != MailboxBufferCode::Success as u32 {
+            return Err(MailboxError::RequestFailed(code));
         }
-    };
+        Ok(())
+    }
+
+    /// Returns the response values of a tag after send()
+    pub fn response(&self, handle: TagHandle) -> Result<&[u32], MailboxError> {
+        let (tag, offset) = self.tags[handle.0];
+        // SAFETY: The pointers satisfy the requirements set by read_volatile()
+        let (value_size, code) = unsafe {
+            (
+                core::ptr::read_volatile(&self.buffer[offset + 1]),
+                core::ptr::read_volatile(&self.buffer[offset + 2]),
+            )
+        };
+        if code & TAG_RESPONSE_BIT == 0 {
+            return Err(MailboxError::TagFailed(tag));
+        }
+
+        // The firmware reports the full length of responses that didn't fit
+        let len = (code & !TAG_RESPONSE_BIT).min(value_size) as usize;
+        let start = offset + 3;
+        Ok(&self.buffer[start..start + len.div_ceil(4)])
+    }
+
+    /// Like response() for a tag whose response has exactly N values
+    pub fn response_array<const N: usize>(
+        &self,
+        handle: TagHandle,
+    ) -> Result<[u32; N], MailboxError> {
+        let tag = self.tags[handle.0].0;
+        self.response(handle)?
+            .try_into()
+            .map_err(|_| MailboxError::ShortResponse(tag))
+    }
 }
 
-pub fn get_vc_fw_version() -> Result<u32, u32> {
-    define_and_init_property_msg!(
-        PropertyMsgFwVersion,
-        msg,
-        PropertyTag::GetFwVersion,
-        fw_version: u32 = 0,
-    );
+// Sends a single tag and returns its response, which must be as long as the
+// request
+fn query<const N: usize>(tag: PropertyTag, request: [u32; N]) -> Result<[u32; N], MailboxError> {
+    let mut batch = PropertyBatch::new();
+    let handle = batch.add(tag, &request)?;
+    batch.send()?;
+    batch.response_array(handle)
+}
+
+fn range_from_words(words: [u32; 2]) -> RangePhysical {
+    RangePhysical::new(AddressPhysical::new(words[0] as u64), words[1] as u64)
+}
+
+// --------------------------------------------------------------------------
+// Hardware
+// --------------------------------------------------------------------------
 
-    mailbox_send_ptag_and_handle_error!(msg);
+/// Returns the revision code, which encodes the model, memory size and
+/// manufacturer, see https://www.raspberrypi.com/documentation/computers/raspberry-pi.html#raspberry-pi-revision-codes
+pub fn get_board_revision() -> Result<u32, MailboxError> {
+    let [revision] = query(PropertyTag::GetBoardRevision, [0])?;
+    Ok(revision)
+}
 
-    // SAFETY: The pointer satisfies the requirements set by read_volatile()
-    unsafe { Ok(core::ptr::read_volatile(&msg.fw_version)) }
+pub fn get_board_mac_address() -> Result<[u8; 6], MailboxError> {
+    // The 6 bytes of the address in network byte order, padded to 8
+    let words = query(PropertyTag::GetBoardMacAddress, [0, 0])?;
+    let mut bytes = [0; 8];
+    bytes[0..4].copy_from_slice(&words[0].to_le_bytes());
+    bytes[4..8].copy_from_slice(&words[1].to_le_bytes());
+    Ok(bytes[0..6].try_into().unwrap())
 }
 
-pub fn get_board_serial() -> Result<u64, u32> {
-    define_and_init_property_msg!(
-        PropertyMsgBoardSerial,
-        msg,
-        PropertyTag::GetBoardSerial,
-        serial_low: u32 = 0,
-        serial_high: u32 = 0,
-    );
+pub struct BoardInfo {
+    pub fw_version: u32,
+    pub model: u32,
+    pub serial: u64,
+    pub arm_memory: RangePhysical,
+    pub videocore_memory: RangePhysical,
+}
+
+/// Queries the firmware version, the board model and serial number and the
+/// memory split with a single message
+pub fn get_board_info() -> Result<BoardInfo, MailboxError> {
+    let mut batch = PropertyBatch::new();
+    let fw_version = batch.add(PropertyTag::GetFwVersion, &[0])?;
+    let model = batch.add(PropertyTag::GetBoardModel, &[0])?;
+    let serial = batch.add(PropertyTag::GetBoardSerial, &[0, 0])?;
+    let arm_memory = batch.add(PropertyTag::GetArmMemory, &[0, 0])?;
+    let videocore_memory = batch.add(PropertyTag::GetVideoCoreMemory, &[0, 0])?;
+    batch.send()?;
+
+    let [fw_version] = batch.response_array(fw_version)?;
+    let [model] = batch.response_array(model)?;
+    let [serial_low, serial_high] = batch.response_array(serial)?;
+    Ok(BoardInfo {
+        fw_version,
+        model,
+        serial: ((serial_high as u64) << 32) | serial_low as u64,
+        arm_memory: range_from_words(batch.response_array(arm_memory)?),
+        videocore_memory: range_from_words(batch.response_array(videocore_memory)?),
+    })
+}
+
+// --------------------------------------------------------------------------
+// Configuration
+// --------------------------------------------------------------------------
+
+// The value buffer has to fit in one message together with the message
+// header (2 words), the tag header (3 words) and the end tag
+pub const MAX_COMMAND_LINE_LEN: usize = MAILBOX_BUFFER_SIZE - (2 + 3 + 1) * 4;
+const _: () = assert!(MAX_COMMAND_LINE_LEN.is_multiple_of(4));
+const _: () = assert!(2 + 3 + MAX_COMMAND_LINE_LEN / 4 < BATCH_BUFFER_WORDS);
+
+/// Returns the kernel command line the firmware built from cmdline.txt and
+/// its own settings, truncated to MAX_COMMAND_LINE_LEN bytes
+pub fn get_command_line() -> Result<String<MAX_COMMAND_LINE_LEN>, MailboxError> {
+    const WORDS: usize = MAX_COMMAND_LINE_LEN / 4;
 
-    mailbox_send_ptag_and_handle_error!(msg);
+    let mut batch = PropertyBatch::new();
+    let handle = batch.add(PropertyTag::GetCommandLine, &[0; WORDS])?;
+    batch.send()?;
 
-    // SAFETY: The pointers satisfy the requirements set by read_volatile()
-    let serial_low = unsafe { core::ptr::read_volatile(&msg.serial_low) };
-    let serial_high = unsafe { core::ptr::read_volatile(&msg.serial_high) };
-    let serial_num = ((serial_high as u64) << 32) | serial_low as u64;
+    let mut bytes: Vec<u8, MAX_COMMAND_LINE_LEN> = Vec::new();
+    for word in batch.response(handle)? {
+        bytes.extend_from_slice(&word.to_le_bytes()).unwrap();
+    }
+    // The response may be NUL terminated and padded
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    bytes.truncate(len);
 
-    Ok(serial_num)
+    let mut cmdline = String::new();
+    for &b in &bytes {
+        cmdline.push(b as char).unwrap_or(());
+    }
+    Ok(cmdline)
 }
 
-pub fn get_arm_memory() -> Result<RangePhysical, u32> {
-    define_and_init_property_msg!(
-        PropertyMsgArmMemory,
-        msg,
-        PropertyTag::GetArmMemory,
-        base: u32 = 0,
-        size: u32 = 0,
-    );
+// --------------------------------------------------------------------------
+// Shared Resources
+// --------------------------------------------------------------------------
 
-    mailbox_send_ptag_and_handle_error!(msg);
+/// Returns the mask of the DMA channels the ARM is free to use
+pub fn get_dma_channels() -> Result<u32, MailboxError> {
+    let [mask] = query(PropertyTag::GetDmaChannels, [0])?;
+    Ok(mask)
+}
 
-    // SAFETY: The pointers satisfy the requirements set by read_volatile()
-    let base = unsafe { core::ptr::read_volatile(&msg.base) } as u64;
-    let size = unsafe { core::ptr::read_volatile(&msg.size) } as u64;
+// --------------------------------------------------------------------------
+// Power
+// --------------------------------------------------------------------------
 
-    let range = RangePhysical::new(AddressPhysical::new(base), size);
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum PowerDevice {
+    SdCard = 0,
+    Uart0 = 1,
+    Uart1 = 2,
+    UsbHcd = 3,
+    I2c0 = 4,
+    I2c1 = 5,
+    I2c2 = 6,
+    Spi = 7,
+    Ccp2tx = 8,
+}
 
-    Ok(range)
+const POWER_STATE_ON: u32 = 1 << 0;
+// Set in requests to wait for the device to become stable
+const POWER_STATE_WAIT: u32 = 1 << 1;
+// Set in responses for devices that don't exist
+const POWER_STATE_NO_DEVICE: u32 = 1 << 1;
+
+/// Returns whether the device is powered on
+pub fn get_power_state(device: PowerDevice) -> Result<bool, MailboxError> {
+    let [_, state] = query(PropertyTag::GetPowerState, [device as u32, 0])?;
+    if state & POWER_STATE_NO_DEVICE != 0 {
+        return Err(MailboxError::InvalidId);
+    }
+    Ok(state & POWER_STATE_ON != 0)
 }
 
-pub fn get_videocore_memory() -> Result<RangePhysical, u32> {
-    define_and_init_property_msg!(
-        PropertyMsgVideoCoreMemory,
-        msg,
-        PropertyTag::GetVideoCoreMemory,
-        base: u32 = 0,
-        size: u32 = 0,
-    );
+/// Powers the device on or off, waiting until its power is stable. Returns
+/// the new state.
+pub fn set_power_state(device: PowerDevice, on: bool) -> Result<bool, MailboxError> {
+    let state = if on { POWER_STATE_ON } else { 0 } | POWER_STATE_WAIT;
+    let [_, state] = query(PropertyTag::SetPowerState, [device as u32, state])?;
+    if state & POWER_STATE_NO_DEVICE != 0 {
+        return Err(MailboxError::InvalidId);
+    }
+    Ok(state & POWER_STATE_ON != 0)
+}
 
-    mailbox_send_ptag_and_handle_error!(msg);
+// --------------------------------------------------------------------------
+// Clocks
+// --------------------------------------------------------------------------
 
-    // SAFETY: The pointers satisfy the requirements set by read_volatile()
-    let base = unsafe { core::ptr::read_volatile(&msg.base) } as u64;
-    let size = unsafe { core::ptr::read_volatile(&msg.size) } as u64;
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum ClockId {
+    Emmc = 1,
+    Uart = 2,
+    Arm = 3,
+    // The VPU clock, which also drives the mini UART and the system timer
+    Core = 4,
+    V3d = 5,
+    H264 = 6,
+    Isp = 7,
+    Sdram = 8,
+    Pixel = 9,
+    Pwm = 10,
+}
+
+// The firmware answers with a rate of 0 for clocks it doesn't know
+fn query_clock_rate(tag: PropertyTag, request: [u32; 2]) -> Result<u32, MailboxError> {
+    match query(tag, request)? {
+        [_, 0] => Err(MailboxError::InvalidId),
+        [_, rate] => Ok(rate),
+    }
+}
+
+/// Returns the current rate of the clock in Hz
+pub fn get_clock_rate(clock: ClockId) -> Result<u32, MailboxError> {
+    query_clock_rate(PropertyTag::GetClockRate, [clock as u32, 0])
+}
+
+/// Returns the maximum rate the clock can be set to in Hz
+pub fn get_max_clock_rate(clock: ClockId) -> Result<u32, MailboxError> {
+    query_clock_rate(PropertyTag::GetMaxClockRate, [clock as u32, 0])
+}
+
+/// Returns the minimum rate the clock can be set to in Hz
+pub fn get_min_clock_rate(clock: ClockId) -> Result<u32, MailboxError> {
+    query_clock_rate(PropertyTag::GetMinClockRate, [clock as u32, 0])
+}
+
+/// Sets the rate of the clock in Hz and returns the rate the firmware actually
+/// set. Unless `skip_turbo` is set, setting the ARM clock above its default
+/// rate also raises the other clocks and the voltage to their turbo settings.
+pub fn set_clock_rate(clock: ClockId, rate_hz: u32, skip_turbo: bool) -> Result<u32, MailboxError> {
+    let [_, rate, _] = query(
+        PropertyTag::SetClockRate,
+        [clock as u32, rate_hz, skip_turbo as u32],
+    )?;
+    if rate == 0 {
+        return Err(MailboxError::InvalidId);
+    }
+    Ok(rate)
+}
+
+/// Returns whether turbo mode is on
+pub fn get_turbo() -> Result<bool, MailboxError> {
+    // There is only one turbo setting, with ID 0
+    let [_, level] = query(PropertyTag::GetTurbo, [0, 0])?;
+    Ok(level != 0)
+}
+
+/// Switches turbo mode on or off. In turbo mode the ARM, core, V3D and other
+/// clocks run at their maximum rates.
+pub fn set_turbo(on: bool) -> Result<(), MailboxError> {
+    query(PropertyTag::SetTurbo, [0, on as u32])?;
+    Ok(())
+}
+
+// --------------------------------------------------------------------------
+// Voltages and Temperature
+// --------------------------------------------------------------------------
+
+#[derive(Clone, Copy)]
+#[repr(u32)]
+pub enum VoltageId {
+    Core = 1,
+    SdramC = 2,
+    SdramP = 3,
+    SdramI = 4,
+}
+
+// The firmware answers with this value for voltages it doesn't know
+const INVALID_VOLTAGE: u32 = 0x8000_0000;
+
+// Voltages are offsets from 1.2V in units of 25mV, return them in uV
+fn query_voltage(tag: PropertyTag, request: [u32; 2]) -> Result<i32, MailboxError> {
+    match query(tag, request)? {
+        [_, INVALID_VOLTAGE] => Err(MailboxError::InvalidId),
+        [_, value] => Ok(1_200_000 + value as i32 * 25_000),
+    }
+}
+
+/// Returns the voltage in microvolts
+pub fn get_voltage(id: VoltageId) -> Result<i32, MailboxError> {
+    query_voltage(PropertyTag::GetVoltage, [id as u32, 0])
+}
 
-    let range = RangePhysical::new(AddressPhysical::new(base), size);
+pub fn get_max_voltage(id: VoltageId) -> Result<i32, MailboxError> {
+    query_voltage(PropertyTag::GetMaxVoltage, [id as u32, 0])
+}
+
+pub fn get_min_voltage(id: VoltageId) -> Result<i32, MailboxError> {
+    query_voltage(PropertyTag::GetMinVoltage, [id as u32, 0])
+}
+
+/// Sets the voltage, rounded down to a multiple of 25mV, and returns the
+/// voltage the firmware actually set. Both are in microvolts. Voltages
+/// outside the minimum and maximum the firmware reports are refused.
+pub fn set_voltage(id: VoltageId, microvolts: i32) -> Result<i32, MailboxError> {
+    // Don't rely on the firmware to refuse an overvolt, it can damage the board
+    if !(get_min_voltage(id)?..=get_max_voltage(id)?).contains(&microvolts) {
+        return Err(MailboxError::OutOfRange);
+    }
+    let value = (microvolts - 1_200_000).div_euclid(25_000);
+    query_voltage(PropertyTag::SetVoltage, [id as u32, value as u32])
+}
+
+/// Returns the SoC temperature in thousandths of a degree Celsius
+pub fn get_temperature() -> Result<u32, MailboxError> {
+    // There is only one temperature sensor, with ID 0
+    let [_, temperature] = query(PropertyTag::GetTemperature, [0, 0])?;
+    Ok(temperature)
+}
+
+/// Returns the temperature above which the firmware throttles the clocks, in
+/// thousandths of a degree Celsius
+pub fn get_max_temperature() -> Result<u32, MailboxError> {
+    let [_, temperature] = query(PropertyTag::GetMaxTemperature, [0, 0])?;
+    Ok(temperature)
+}
+
+// --------------------------------------------------------------------------
+// Framebuffer
+// --------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+pub enum PixelOrder {
+    Bgr = 0,
+    Rgb = 1,
+}
+
+pub struct FramebufferInfo {
+    pub width: u32,
+    pub height: u32,
+    pub virtual_height: u32,
+    // Bits per pixel
+    pub depth: u32,
+    pub pixel_order: PixelOrder,
+    // Bytes per line
+    pub pitch: u32,
+    pub memory: RangePhysical,
+}
+
+/// Asks the firmware for a framebuffer of the given size and depth and returns
+/// what it actually allocated, which can differ from the request. The virtual
+/// height can be bigger than the physical one, the display then shows the
+/// part at the virtual offset.
+pub fn allocate_framebuffer(
+    width: u32,
+    height: u32,
+    virtual_height: u32,
+    depth: u32,
+) -> Result<FramebufferInfo, MailboxError> {
+    let mut batch = PropertyBatch::new();
+    let physical_size = batch.add(PropertyTag::SetPhysicalSize, &[width, height])?;
+    let virtual_size = batch.add(PropertyTag::SetVirtualSize, &[width, virtual_height])?;
+    let depth = batch.add(PropertyTag::SetDepth, &[depth])?;
+    let pixel_order = batch.add(PropertyTag::SetPixelOrder, &[PixelOrder::Rgb as u32])?;
+    // The framebuffer is mapped page by page, so ask for it to be page aligned
+    let buffer = batch.add(PropertyTag::AllocateFramebuffer, &[PAGE_SIZE as u32, 0])?;
+    let pitch = batch.add(PropertyTag::GetPitch, &[0])?;
+    batch.send()?;
+
+    let response = |handle, tag, len| match batch.response(handle) {
+        Ok(words) if words.len() < len => Err(MailboxError::ShortResponse(tag)),
+        result => result,
+    };
+    let physical_size = response(physical_size, PropertyTag::SetPhysicalSize, 2)?;
+    let virtual_size = response(virtual_size, PropertyTag::SetVirtualSize, 2)?;
+    let depth = response(depth, PropertyTag::SetDepth, 1)?;
+    let pixel_order = response(pixel_order, PropertyTag::SetPixelOrder, 1)?;
+    let buffer = response(buffer, PropertyTag::AllocateFramebuffer, 2)?;
+    let pitch = response(pitch, PropertyTag::GetPitch, 1)?;
+
+    // The firmware answers with a zero sized buffer if it can't allocate one
+    if buffer[0] == 0 || buffer[1] == 0 {
+        return Err(MailboxError::TagFailed(PropertyTag::AllocateFramebuffer));
+    }
+    // The address is a bus address, but depending on the firmware it can use
+    // any of the bus aliases, or none at all on QEMU. The low 30 bits are the
+    // physical address in all cases.
+    let base = AddressPhysical::new((buffer[0] & 0x3FFF_FFFF) as u64);
+
+    Ok(FramebufferInfo {
+        width: physical_size[0],
+        height: physical_size[1],
+        virtual_height: virtual_size[1],
+        depth: depth[0],
+        pixel_order: match pixel_order[0] {
+            0 => PixelOrder::Bgr,
+            _ => PixelOrder::Rgb,
+        },
+        pitch: pitch[0],
+        memory: RangePhysical::new(base, buffer[1] as u64),
+    })
+}
 
-    Ok(range)
+/// Moves the part of the virtual framebuffer shown on the display. Returns the
+/// offset the firmware actually set.
+pub fn set_virtual_offset(x: u32, y: u32) -> Result<(u32, u32), MailboxError> {
+    let [x, y] = query(PropertyTag::SetVirtualOffset, [x, y])?;
+    Ok((x, y))
 }
 
+/// Waits for the start of the next vertical blanking interval. Not all
+/// firmware versions support this, QEMU doesn't.
+pub fn wait_for_vsync() -> Result<(), MailboxError> {
+    query(PropertyTag::WaitForVsync, [0])?;
+    Ok(())
+}
+
+// --------------------------------------------------------------------------
+// LEDs
+// --------------------------------------------------------------------------
+
 // The documentation in https://github.com/raspberrypi/firmware/wiki/Mailbox-property-interface
 // says that it's status=42, power=130. However it seems that pin 130 controls
 // the activity LED (ACT not PWR) on RPi3b.
@@ -269,16 +691,13 @@ pub enum OnboardLEDStatus {
     High = 1,
 }
 
-pub fn set_onboard_led_status(pin: OnboardLEDPin, status: OnboardLEDStatus) -> Result<(), u32> {
-    define_and_init_property_msg!(
-        PropertyMsgSetOnboardLedStatus,
-        msg,
+pub fn set_onboard_led_status(
+    pin: OnboardLEDPin,
+    status: OnboardLEDStatus,
+) -> Result<(), MailboxError> {
+    query(
         PropertyTag::SetOnboardLedStatus,
-        pin_num: OnboardLEDPin = pin,
-        status: OnboardLEDStatus = status,
-    );
-
-    mailbox_send_ptag_and_handle_error!(msg);
-
+        [pin as u32, status as u32],
+    )?;
     Ok(())
 }
@@ -0,0 +1,187 @@
+// The power management block. It isn't documented in the BCM2837 datasheet,
+// the registers used here are the ones the Linux bcm2835_wdt driver uses.
+//
+// Its watchdog resets the board when it expires. Besides rebooting on demand
+// it can guard against hangs: once started it must be petted before it
+// expires, which the boot thread does every time around its loop. A system
+// timer alarm wakes that loop up often enough, so the watchdog only expires
+// if the loop stops running, e.g. because a thread never yields.
+
+use crate::drivers::system_timer::{self, Channel};
+use crate::drivers::{peripheral_switch_in, MMIORegisters, PERIPHERALS_BASE};
+use crate::locking::IRQSpinLock;
+use crate::{cmdline, delay, info, println};
+use core::sync::atomic::{AtomicI64, AtomicU32, Ordering};
+use core::time::Duration;
+use tock_registers::interfaces::{Readable, Writeable};
+use tock_registers::register_structs;
+use tock_registers::registers::ReadWrite;
+
+// SAFETY: There should a be a power management block behind that address
+const REGS: MMIORegisters<PMRegisters> =
+    unsafe { MMIORegisters::<PMRegisters>::new(PERIPHERALS_BASE.add(0x10_0000)) };
+
+// Every write must include this in the top byte, otherwise it's ignored
+const PM_PASSWORD: u32 = 0x5a00_0000;
+
+const PM_RSTC_WRCFG_MASK: u32 = 0x30;
+const PM_RSTC_WRCFG_FULL_RESET: u32 = 0x20;
+// Stops the watchdog
+const PM_RSTC_RESET: u32 = 0x102;
+
+// The firmware boots from the partition whose number is spread over the even
+// bits of PM_RSTS. Partition 63 isn't a real one, it makes the firmware halt.
+const PM_RSTS_PARTITION_MASK: u32 = 0x555;
+const PM_RSTS_PARTITION_HALT: u32 = 0x555;
+
+// The watchdog counts down in ticks of 1/65536s, ~15us
+const PM_WDOG_TIME_MASK: u32 = 0x000f_ffff;
+const WDOG_TICKS_PER_SEC: u64 = 1 << 16;
+
+// Which system timer channel wakes up the boot thread to pet the watchdog
+const WATCHDOG_ALARM_CHANNEL: Channel = Channel::Three;
+
+register_structs! {
+    #[allow(non_snake_case)]
+    PMRegisters {
+        (0x00 => _reserved0),
+        (0x1c => PM_RSTC: ReadWrite<u32>),
+        (0x20 => PM_RSTS: ReadWrite<u32>),
+        (0x24 => PM_WDOG: ReadWrite<u32>),
+        (0x28 => @END),
+    }
+}
+
+// Serializes the read-modify-write sequences on the registers
+static PM_LOCK: IRQSpinLock<()> = IRQSpinLock::new(());
+
+// The timeout of the running watchdog in ticks, 0 if it's stopped
+static WATCHDOG_TICKS: AtomicU32 = AtomicU32::new(0);
+
+// What to do after a panic, in seconds with the same meaning as Linux's
+// panic= option: 0 halts, a positive value reboots after that long and a
+// negative one reboots right away
+static PANIC_TIMEOUT: AtomicI64 = AtomicI64::new(0);
+
+// Must be called with PM_LOCK held or from the panic handler
+fn arm_watchdog(ticks: u32) {
+    peripheral_switch_in();
+    REGS.PM_WDOG.set(PM_PASSWORD | (ticks & PM_WDOG_TIME_MASK));
+    let rstc = REGS.PM_RSTC.get() & !PM_RSTC_WRCFG_MASK;
+    REGS.PM_RSTC
+        .set(PM_PASSWORD | rstc | PM_RSTC_WRCFG_FULL_RESET);
+}
+
+// Resets the board by letting the watchdog expire almost immediately. The
+// firmware then boots from `partition`.
+fn restart(partition: u32) -> ! {
+    peripheral_switch_in();
+    let rsts = REGS.PM_RSTS.get() & !PM_RSTS_PARTITION_MASK;
+    REGS.PM_RSTS.set(PM_PASSWORD | rsts | partition);
+    arm_watchdog(10);
+
+    loop {
+        aarch64_cpu::asm::wfe();
+    }
+}
+
+/// Resets the board
+pub fn reboot() -> ! {
+    restart(0)
+}
+
+/// Resets the board into a halted state. The SoC can't actually switch its
+/// power off, so it stays on and drawing a little current until unplugged.
+pub fn poweroff() -> ! {
+    restart(PM_RSTS_PARTITION_HALT)
+}
+
+fn watchdog_alarm(channel: Channel) {
+    // Nothing to do but to keep waking up the boot thread while the watchdog
+    // runs. Petting it here would keep the board alive even if the threads
+    // are stuck.
+    let ticks = WATCHDOG_TICKS.load(Ordering::Relaxed);
+    if ticks != 0 {
+        let interval = ticks as u64 * system_timer::FREQUENCY_HZ / WDOG_TICKS_PER_SEC / 2;
+        let _ = system_timer::set_alarm_in(channel, interval, watchdog_alarm);
+    }
+}
+
+/// Starts the watchdog, or changes its timeout if it runs already. The timeout
+/// is clamped to the ~16s the hardware supports. Returns the timeout set.
+pub fn watchdog_start(timeout: Duration) -> Duration {
+    // Saturating is fine since anything that large gets clamped anyway
+    let micros = u64::try_from(timeout.as_micros()).unwrap_or(u64::MAX);
+    let ticks = (micros.saturating_mul(WDOG_TICKS_PER_SEC) / 1_000_000)
+        .clamp(1, PM_WDOG_TIME_MASK as u64) as u32;
+
+    let _lock = PM_LOCK.lock();
+    arm_watchdog(ticks);
+    if WATCHDOG_TICKS.swap(ticks, Ordering::Relaxed) == 0 {
+        watchdog_alarm(WATCHDOG_ALARM_CHANNEL);
+    }
+    Duration::from_micros(ticks as u64 * 1_000_000 / WDOG_TICKS_PER_SEC)
+}
+
+/// Restarts the countdown of the watchdog, if it runs
+pub fn watchdog_pet() {
+    let _lock = PM_LOCK.lock();
+    let ticks = WATCHDOG_TICKS.load(Ordering::Relaxed);
+    if ticks != 0 {
+        peripheral_switch_in();
+        REGS.PM_WDOG.set(PM_PASSWORD | ticks);
+    }
+}
+
+pub fn watchdog_stop() {
+    let _lock = PM_LOCK.lock();
+    peripheral_switch_in();
+    REGS.PM_RSTC.set(PM_PASSWORD | PM_RSTC_RESET);
+    WATCHDOG_TICKS.store(0, Ordering::Relaxed);
+    system_timer::cancel_alarm(WATCHDOG_ALARM_CHANNEL);
+}
+
+/// Returns how long until the watchdog expires, zero if it's stopped
+pub fn watchdog_time_left() -> Duration {
+    if WATCHDOG_TICKS.load(Ordering::Relaxed) == 0 {
+        return Duration::ZERO;
+    }
+    peripheral_switch_in();
+    let ticks = (REGS.PM_WDOG.get() & PM_WDOG_TIME_MASK) as u64;
+    Duration::from_micros(ticks * 1_000_000 / WDOG_TICKS_PER_SEC)
+}
+
+/// Sets what the panic handler does once it's done, see PANIC_TIMEOUT
+pub fn set_panic_timeout(seconds: i64) {
+    PANIC_TIMEOUT.store(seconds, Ordering::Relaxed);
+}
+
+/// Called at the end of the panic handler. Reboots if the panic policy says
+/// so, otherwise returns and the caller halts. A running watchdog still
+/// reboots the board in that case.
+pub fn reboot_after_panic() {
+    let seconds = PANIC_TIMEOUT.load(Ordering::Relaxed);
+    if seconds == 0 {
+        return;
+    }
+    if seconds > 0 {
+        println!("\nRebooting in {seconds} seconds...");
+        // Interrupts can't be relied on anymore, so neither can sleeping
+        delay::busy_wait(Duration::from_secs(seconds as u64));
+    }
+    reboot();
+}
+
+/// Applies the panic= and watchdog= command line options, which take a number
+/// of seconds
+pub fn init() {
+    if let Some(seconds) = cmdline::value("panic") {
+        set_panic_timeout(seconds);
+    }
+    if let Some(seconds) = cmdline::value::<u64>("watchdog") {
+        if seconds > 0 {
+            let timeout = watchdog_start(Duration::from_secs(seconds));
+            info!("Watchdog started with a {}ms timeout", timeout.as_millis());
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// The BCM2837 hardware random number generator. It isn't documented in the
+// datasheet, the registers used here are the ones the Linux bcm2835-rng
+// driver uses. The generated words queue up in a FIFO.
+
+use crate::drivers::{peripheral_switch_in, MMIORegisters, PERIPHERALS_BASE};
+use crate::time::Instant;
+use core::time::Duration;
+use tock_registers::interfaces::{Readable, Writeable};
+use tock_registers::register_structs;
+use tock_registers::registers::{ReadOnly, ReadWrite};
+
+// SAFETY: There should be a RNG behind that address
+const REGS: MMIORegisters<RNGRegisters> =
+    unsafe { MMIORegisters::<RNGRegisters>::new(PERIPHERALS_BASE.add(0x10_4000)) };
+
+const RNG_CTRL_RBGEN: u32 = 1 << 0;
+const RNG_INT_OFF: u32 = 1 << 0;
+
+// The first numbers generated aren't very random, so that many are thrown
+// away before any reach the FIFO
+const RNG_WARMUP_COUNT: u32 = 0x40000;
+
+// The number of words in the FIFO is in the top byte of RNG_STATUS
+const RNG_STATUS_WORDS_SHIFT: u32 = 24;
+
+register_structs! {
+    #[allow(non_snake_case)]
+    RNGRegisters {
+        (0x00 => RNG_CTRL: ReadWrite<u32>),
+        (0x04 => RNG_STATUS: ReadWrite<u32>),
+        (0x08 => RNG_DATA: ReadOnly<u32>),
+        (0x0c => RNG_FF_THRES: ReadWrite<u32>),
+        (0x10 => RNG_INT_MASK: ReadWrite<u32>),
+        (0x14 => @END),
+    }
+}
+
+/// Starts the generator. The first words become available once the warm-up
+/// is done, which takes a few milliseconds.
+pub fn init() {
+    peripheral_switch_in();
+    REGS.RNG_STATUS.set(RNG_WARMUP_COUNT);
+    // Nobody waits for the FIFO to fill up, so there's no use for interrupts
+    REGS.RNG_INT_MASK.set(REGS.RNG_INT_MASK.get() | RNG_INT_OFF);
+    REGS.RNG_CTRL.set(REGS.RNG_CTRL.get() | RNG_CTRL_RBGEN);
+}
+
+/// Returns the number of words waiting in the FIFO
+pub fn available_words() -> u32 {
+    peripheral_switch_in();
+    REGS.RNG_STATUS.get() >> RNG_STATUS_WORDS_SHIFT
+}
+
+/// Returns a word if one is waiting in the FIFO
+pub fn try_read() -> Option<u32> {
+    if available_words() == 0 {
+        return None;
+    }
+    peripheral_switch_in();
+    Some(REGS.RNG_DATA.get())
+}
+
+/// Waits for a word for up to `timeout`
+pub fn read(timeout: Duration) -> Option<u32> {
+    let deadline = Instant::now().saturating_add(timeout);
+    loop {
+        if let Some(word) = try_read() {
+            return Some(word);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
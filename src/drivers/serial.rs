@@ -0,0 +1,249 @@
+// The parts the UART drivers have in common: the RX and TX ring buffers, the
+// buffered TX path with its synchronous fallback, and routing to GPIO pins.
+
+use crate::drivers::gpio::{self, GPIOPin, GpioError};
+use crate::locking::IRQSpinLock;
+use crate::warn;
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use heapless::Vec;
+
+// What the TX path needs from a UART
+pub trait SerialPort {
+    // Whether the TX FIFO can take another byte
+    fn tx_ready() -> bool;
+    fn write_byte(byte: u8);
+    // Called once bytes are left in the TX buffer, the TX interrupt must then
+    // fire once there is room in the TX FIFO
+    fn enable_tx_irq();
+}
+
+// The size of the RX buffers. The uart-large-rx-buffer feature makes room for
+// bulk transfers, e.g. when flow control isn't available.
+pub const RX_BUFFER_LEN: usize = if cfg!(feature = "uart-large-rx-buffer") {
+    4096
+} else {
+    128
+};
+
+pub struct RxBuffer<const N: usize> {
+    // Circular buffer.
+    // Old characters will be dropped if we can't process them fast enough.
+    buffer: [char; N],
+    // Index pointing to the slot where the next character will go to
+    tail: usize,
+    // Number of characters in the buffer
+    num_chars: usize,
+}
+
+impl<const N: usize> RxBuffer<N> {
+    pub const fn new() -> Self {
+        RxBuffer {
+            buffer: ['\0'; N],
+            tail: 0,
+            num_chars: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_chars
+    }
+
+    /// Adds a character, dropping the oldest one if the buffer is full.
+    /// Returns false if a character was dropped.
+    pub fn push(&mut self, c: char) -> bool {
+        let full = self.num_chars == N;
+        self.buffer[self.tail] = c;
+        self.tail = (self.tail + 1) % N;
+        self.num_chars = (self.num_chars + 1).min(N);
+        !full
+    }
+
+    pub fn pop(&mut self) -> Option<char> {
+        if self.num_chars == 0 {
+            return None;
+        }
+        let head = (self.tail + N - self.num_chars) % N;
+        self.num_chars -= 1;
+        Some(self.buffer[head])
+    }
+}
+
+pub struct RxStats {
+    pub received: u64,
+    pub overruns: u64,
+    pub dropped: u64,
+}
+
+/// What happened to the characters a UART received
+pub struct RxCounters {
+    // Characters taken out of the RX FIFO
+    received: AtomicU64,
+    // Times the RX FIFO overflowed and the UART lost characters
+    overruns: AtomicU64,
+    // Characters lost because the RX buffer was full
+    dropped: AtomicU64,
+}
+
+impl RxCounters {
+    pub const fn new() -> Self {
+        RxCounters {
+            received: AtomicU64::new(0),
+            overruns: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn count_received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_overrun(&self) {
+        self.overruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> RxStats {
+        RxStats {
+            received: self.received.load(Ordering::Relaxed),
+            overruns: self.overruns.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Circular buffer of bytes waiting to be transmitted. The TX interrupt moves
+// them to the TX FIFO whenever there's space in it.
+struct TxBuffer<const N: usize> {
+    buffer: [u8; N],
+    // Index of the next byte to transmit
+    head: usize,
+    // Number of bytes in the buffer
+    len: usize,
+}
+
+impl<const N: usize> TxBuffer<N> {
+    // Moves bytes to the TX FIFO until either is exhausted
+    fn fill_tx_fifo<P: SerialPort>(&mut self) {
+        while self.len > 0 && P::tx_ready() {
+            P::write_byte(self.buffer[self.head]);
+            self.head = (self.head + 1) % N;
+            self.len -= 1;
+        }
+    }
+}
+
+/// The TX side of a UART. Until enable_buffering() is called, and after
+/// disable_buffering(), every byte is written synchronously.
+pub struct SerialTx<P: SerialPort, const N: usize> {
+    buffer: IRQSpinLock<TxBuffer<N>>,
+    buffered: AtomicBool,
+    port: PhantomData<P>,
+}
+
+impl<P: SerialPort, const N: usize> SerialTx<P, N> {
+    pub const fn new() -> Self {
+        SerialTx {
+            buffer: IRQSpinLock::new(TxBuffer {
+                buffer: [0; N],
+                head: 0,
+                len: 0,
+            }),
+            buffered: AtomicBool::new(false),
+            port: PhantomData,
+        }
+    }
+
+    pub fn enable_buffering(&self) {
+        self.buffered.store(true, Ordering::Relaxed);
+    }
+
+    /// Makes put_char() synchronous again, for when interrupts can't be
+    /// relied on anymore like after a panic
+    pub fn disable_buffering(&self) {
+        self.buffered.store(false, Ordering::Relaxed);
+    }
+
+    /// Queues a character for transmission. If the TX buffer is full this
+    /// waits for space in it.
+    pub fn put_char(&self, c: char) {
+        if !self.buffered.load(Ordering::Relaxed) {
+            self.put_char_sync(c);
+            return;
+        }
+
+        let mut tx_buffer = self.buffer.lock();
+        // Interrupts could be disabled, so make room without relying on them
+        while tx_buffer.len == N {
+            tx_buffer.fill_tx_fifo::<P>();
+        }
+
+        let tail = (tx_buffer.head + tx_buffer.len) % N;
+        tx_buffer.buffer[tail] = c as u8;
+        tx_buffer.len += 1;
+        tx_buffer.fill_tx_fifo::<P>();
+
+        if tx_buffer.len > 0 {
+            P::enable_tx_irq();
+        }
+    }
+
+    /// Writes a character, waiting until the UART accepts it. Anything still
+    /// in the TX buffer is sent first unless the buffer is locked, e.g. by the
+    /// code that panicked.
+    pub fn put_char_sync(&self, c: char) {
+        if let Some(mut tx_buffer) = self.buffer.try_lock() {
+            while tx_buffer.len > 0 {
+                tx_buffer.fill_tx_fifo::<P>();
+            }
+        }
+
+        while !P::tx_ready() {
+            // Wait until we can transmit
+        }
+        P::write_byte(c as u8);
+    }
+
+    /// Called from the TX interrupt. `on_empty` runs once the buffer is
+    /// drained, with the buffer still locked so that put_char() can't queue
+    /// more in the meantime.
+    pub fn process_irq(&self, on_empty: impl FnOnce()) {
+        let mut tx_buffer = self.buffer.lock();
+        tx_buffer.fill_tx_fifo::<P>();
+        if tx_buffer.len == 0 {
+            on_empty();
+        }
+    }
+}
+
+// TXD, RXD, CTS and RTS
+pub const MAX_PINS: usize = 4;
+
+/// Routes a UART to the given pins and adds their handles to `claimed`, where
+/// the driver keeps them for as long as it uses the pins. Pins that another
+/// driver, like the other UART, has claimed are left alone and the UART isn't
+/// connected to them.
+pub fn claim_pins(
+    owner: &'static str,
+    pins: &[u8],
+    mode: gpio::PinMode,
+    claimed: &mut Vec<GPIOPin, MAX_PINS>,
+) {
+    for &pin in pins {
+        match GPIOPin::claim(pin, owner) {
+            Ok(pin) => {
+                pin.select_mode(mode);
+                if claimed.push(pin).is_err() {
+                    panic!("{owner} claimed too many pins");
+                }
+            }
+            Err(GpioError::AlreadyClaimed(other)) => {
+                warn!("{owner} not routed to GPIO{pin}, it belongs to {other}")
+            }
+            Err(e) => warn!("{owner} not routed to GPIO{pin}: {e:?}"),
+        }
+    }
+}
@@ -0,0 +1,144 @@
+// The BCM2837 system timer: a free-running 64-bit counter that ticks at 1MHz
+// regardless of the ARM and core clocks, plus 4 compare registers that raise
+// an interrupt when the low 32 bits of the counter match them. Channels 0 and
+// 2 are used by the VideoCore firmware, channels 1 and 3 are ours.
+
+use crate::drivers::{peripheral_switch_in, MMIORegisters, PERIPHERALS_BASE};
+use crate::irq::{disable_irq, enable_irq, GpuIrq, Irq};
+use crate::locking::IRQSpinLock;
+use crate::warn;
+use tock_registers::interfaces::{Readable, Writeable};
+use tock_registers::register_structs;
+use tock_registers::registers::{ReadOnly, ReadWrite};
+
+pub const FREQUENCY_HZ: u64 = 1_000_000;
+
+// SAFETY: There should be a system timer behind that address as per BCM2837
+const REGS: MMIORegisters<SystemTimerRegisters> =
+    unsafe { MMIORegisters::<SystemTimerRegisters>::new(PERIPHERALS_BASE.add(0x3000)) };
+
+register_structs! {
+    #[allow(non_snake_case)]
+    SystemTimerRegisters {
+        // Bit n is set when channel n matched, writing 1 clears it
+        (0x00 => CS: ReadWrite<u32>),
+        (0x04 => CLO: ReadOnly<u32>),
+        (0x08 => CHI: ReadOnly<u32>),
+        (0x0c => C: [ReadWrite<u32>; 4]),
+        (0x1c => @END),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    One = 1,
+    Three = 3,
+}
+
+impl Channel {
+    fn irq(self) -> GpuIrq {
+        match self {
+            Self::One => GpuIrq::SystemTimer1,
+            Self::Three => GpuIrq::SystemTimer3,
+        }
+    }
+}
+
+// Called in interrupt context with the channel that matched. The handler can
+// set a new alarm on the channel, e.g. to make it periodic.
+pub type AlarmHandler = fn(Channel);
+
+#[derive(Debug)]
+pub enum TimerError {
+    // The deadline passed before the alarm was set
+    Expired,
+    // The compare registers only hold the low 32 bits of the counter, so
+    // alarms can be at most ~71 minutes away
+    TooFar,
+}
+
+static HANDLERS: IRQSpinLock<[Option<AlarmHandler>; 4]> = IRQSpinLock::new([None; 4]);
+
+/// Returns the counter, in microseconds since the timer was reset
+pub fn now() -> u64 {
+    peripheral_switch_in();
+    // The two halves can't be read atomically. If the high half changed while
+    // reading the low half, the low half wrapped around and is read again.
+    loop {
+        let high = REGS.CHI.get();
+        let low = REGS.CLO.get();
+        if REGS.CHI.get() == high {
+            return ((high as u64) << 32) | low as u64;
+        }
+    }
+}
+
+/// Calls `handler` once the counter reaches `deadline`
+pub fn set_alarm(channel: Channel, deadline: u64, handler: AlarmHandler) -> Result<(), TimerError> {
+    if deadline.saturating_sub(now()) > u32::MAX as u64 {
+        return Err(TimerError::TooFar);
+    }
+
+    let index = channel as usize;
+    // Holding the lock masks interrupts, so the match can't be handled
+    // between enabling the IRQ and checking whether the deadline was missed
+    let mut handlers = HANDLERS.lock();
+    handlers[index] = Some(handler);
+
+    peripheral_switch_in();
+    // A match from a previous alarm must not fire this one
+    REGS.CS.set(1 << index);
+    REGS.C[index].set(deadline as u32);
+    enable_irq(Irq::Gpu(channel.irq()));
+
+    // The comparison is for equality, so if the counter went past the
+    // deadline without matching, the alarm would only fire after the counter
+    // wraps around
+    if now() > deadline && REGS.CS.get() & (1 << index) == 0 {
+        disable_irq(Irq::Gpu(channel.irq()));
+        handlers[index] = None;
+        return Err(TimerError::Expired);
+    }
+    Ok(())
+}
+
+/// Calls `handler` in `us` microseconds
+pub fn set_alarm_in(channel: Channel, us: u64, handler: AlarmHandler) -> Result<(), TimerError> {
+    set_alarm(channel, now() + us, handler)
+}
+
+pub fn cancel_alarm(channel: Channel) {
+    let index = channel as usize;
+    disable_irq(Irq::Gpu(channel.irq()));
+    peripheral_switch_in();
+    REGS.CS.set(1 << index);
+    HANDLERS.lock()[index] = None;
+}
+
+/// Acknowledges the match of the channel of `irq` and calls its handler
+pub fn process_irq(irq: GpuIrq) {
+    let channel = match irq {
+        GpuIrq::SystemTimer1 => Channel::One,
+        GpuIrq::SystemTimer3 => Channel::Three,
+        _ => return,
+    };
+    let index = channel as usize;
+
+    peripheral_switch_in();
+    REGS.CS.set(1 << index);
+
+    // Alarms are one-shot, the handler sets the next one if it wants to
+    let handler = HANDLERS.lock()[index].take();
+    match handler {
+        Some(handler) => handler(channel),
+        None => warn!("System timer channel {index} matched without a handler"),
+    }
+
+    // Otherwise the channel would match again once the counter wraps around.
+    // This is done under the lock so that it can't undo a concurrent
+    // set_alarm(), which enables the interrupt after setting the handler.
+    let handlers = HANDLERS.lock();
+    if handlers[index].is_none() {
+        disable_irq(Irq::Gpu(channel.irq()));
+    }
+}
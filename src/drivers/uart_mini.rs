@@ -2,10 +2,17 @@
 // However as far as possible the first 8 control and status registers are laid
 // out like a 16550 UART and the UART core is build to emulate 16550 behaviour.
 
-use crate::drivers::{gpio, gpio::GPIOPin, peripheral_switch_in, MMIORegisters, PERIPHERALS_BASE};
+use crate::drivers::gpio::{self, GPIOPin};
+use crate::drivers::mailbox::{self, ClockId};
+use crate::drivers::serial::{self, RxBuffer, RxCounters, RxStats, SerialPort, SerialTx};
+use crate::drivers::{peripheral_switch_in, MMIORegisters, PERIPHERALS_BASE};
 use crate::irq::{enable_irq, GpuIrq, Irq};
-use crate::locking::IRQSpinLock;
+use crate::locking::{IRQSpinLock, SpinLock};
+#[cfg(not(feature = "gdb"))]
+use crate::tty;
 use crate::{ACTIONS, PENDING_ACTIONS};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use heapless::Vec;
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 use tock_registers::registers::{Aliased, ReadOnly, ReadWrite};
 use tock_registers::{register_bitfields, register_structs};
@@ -14,24 +21,55 @@ use tock_registers::{register_bitfields, register_structs};
 const REGS: MMIORegisters<AuxRegisters> =
     unsafe { MMIORegisters::<AuxRegisters>::new(PERIPHERALS_BASE.add(0x21_5000)) };
 
-const RX_BUFFER_LEN: usize = 128;
-
-#[derive(Copy, Clone)]
-struct RxBuffer {
-    // Circular buffer.
-    // Old characters will be dropped if we can't process them fast enough.
-    buffer: [char; RX_BUFFER_LEN],
-    // Index pointing to the slot where the next character will go to
-    tail: usize,
-    // Number of characters in the buffer
-    num_chars: usize,
+// With flow control, characters are left in the RX FIFO once the RX buffer is
+// this full. The FIFO then fills up and the UART deasserts RTS until the
+// bottom half makes room.
+const RX_HIGH_WATERMARK: usize = serial::RX_BUFFER_LEN * 3 / 4;
+
+// The FIFO holds 8 characters
+const _: () = assert!(serial::RX_BUFFER_LEN >= 8);
+
+// The mini UART is clocked by the core clock. This is its default rate, used
+// if the firmware can't be asked for the real one.
+const DEFAULT_CORE_CLOCK_HZ: u32 = 250_000_000;
+
+static BAUD_RATE: AtomicU32 = AtomicU32::new(0);
+
+static RX_BUFFER: IRQSpinLock<RxBuffer<{ serial::RX_BUFFER_LEN }>> =
+    IRQSpinLock::new(RxBuffer::new());
+
+static RX_COUNTERS: RxCounters = RxCounters::new();
+
+pub fn rx_stats() -> RxStats {
+    RX_COUNTERS.stats()
+}
+
+// Whether init() enabled hardware flow control
+static FLOW_CONTROL: AtomicBool = AtomicBool::new(false);
+
+const TX_BUFFER_LEN: usize = 1024;
+
+struct MiniUart;
+
+impl SerialPort for MiniUart {
+    fn tx_ready() -> bool {
+        peripheral_switch_in();
+        REGS.AUX_MU_LSR.is_set(AUX_MU_LSR::TX_READY)
+    }
+
+    fn write_byte(byte: u8) {
+        REGS.AUX_MU_IO_DATA.set(byte);
+    }
+
+    fn enable_tx_irq() {
+        REGS.AUX_MU_IER.modify(AUX_MU_IER::ENABLE_TX_IRQ::SET);
+    }
 }
 
-static RX_BUFFER: IRQSpinLock<RxBuffer> = IRQSpinLock::new(RxBuffer {
-    buffer: ['\0'; RX_BUFFER_LEN],
-    tail: 0,
-    num_chars: 0,
-});
+// The pins the UART is routed to
+static PINS: SpinLock<Vec<GPIOPin, { serial::MAX_PINS }>> = SpinLock::new(Vec::new());
+
+static TX: SerialTx<MiniUart, TX_BUFFER_LEN> = SerialTx::new();
 
 register_bitfields! {
     u32,
@@ -156,8 +194,44 @@ register_structs! {
     }
 }
 
-/// Configure UART for 8N1 (1 start bit, 8 data bits, no parity, 1 stop bit)
-pub fn init(baud_rate: u32) {
+fn set_baud_divisor(core_clock_hz: u32, baud_rate: u32) {
+    // baudrate = (clock_freq) / (8 * (aux_mu_baud + 1))
+    let Some(divisor) = core_clock_hz.checked_div(baud_rate.saturating_mul(8)) else {
+        return;
+    };
+    // Rates the 16-bit register can't express get the closest one it can
+    let reg_val = divisor.saturating_sub(1).min(u16::MAX as u32);
+    REGS.AUX_MU_BAUD.set(reg_val as u16);
+}
+
+/// Re-programs the baud rate divisor for a new core clock rate. This must be
+/// called whenever the core clock changes, otherwise the baud rate changes
+/// with it.
+pub fn core_clock_changed(core_clock_hz: u32) {
+    let baud_rate = BAUD_RATE.load(Ordering::Relaxed);
+    // Nothing to do before init()
+    if baud_rate == 0 {
+        return;
+    }
+
+    peripheral_switch_in();
+    // Don't change the rate in the middle of a character
+    while !REGS.AUX_MU_LSR.is_set(AUX_MU_LSR::TX_IDLE) {}
+    set_baud_divisor(core_clock_hz, baud_rate);
+}
+
+/// Changes the baud rate, after waiting for the character being transmitted
+/// to go out
+pub fn set_baud_rate(baud_rate: u32) {
+    let core_clock_hz = mailbox::get_clock_rate(ClockId::Core).unwrap_or(DEFAULT_CORE_CLOCK_HZ);
+    BAUD_RATE.store(baud_rate, Ordering::Relaxed);
+    core_clock_changed(core_clock_hz);
+}
+
+/// Configure UART for 8N1 (1 start bit, 8 data bits, no parity, 1 stop bit).
+/// With flow_control the UART only transmits while CTS is asserted and
+/// deasserts RTS when it can't take more characters.
+pub fn init(baud_rate: u32, flow_control: bool) {
     peripheral_switch_in();
     // The enable bit must be set first, otherwise we cannot even access the
     // rest of the registers.
@@ -171,47 +245,105 @@ pub fn init(baud_rate: u32) {
 
     REGS.AUX_MU_LCR.write(AUX_MU_LCR::DATA_SIZE::EIGHT_BITS);
 
-    // baudrate = (clock_freq) / (8 * (aux_mu_baud + 1))
-    // TODO: Get the system clock frequency from the video core.
-    // For now it's assumed to be 250 MHz.
-    let reg_val: u32 = (250_000_000 / (8 * baud_rate)) - 1;
-    REGS.AUX_MU_BAUD.set(reg_val as u16);
-
-    // TXD1
-    GPIOPin::new(14).select_mode(gpio::PinMode::Alt5);
-    // RXD1
-    GPIOPin::new(15).select_mode(gpio::PinMode::Alt5);
+    BAUD_RATE.store(baud_rate, Ordering::Relaxed);
+    let core_clock_hz = mailbox::get_clock_rate(ClockId::Core).unwrap_or(DEFAULT_CORE_CLOCK_HZ);
+    set_baud_divisor(core_clock_hz, baud_rate);
+
+    // TXD1 and RXD1, then CTS1 and RTS1
+    let mut pins = PINS.lock();
+    // Give back the pins of a previous init() first
+    pins.clear();
+    serial::claim_pins("uart_mini", &[14, 15], gpio::PinMode::Alt5, &mut pins);
+    if flow_control {
+        serial::claim_pins("uart_mini", &[16, 17], gpio::PinMode::Alt5, &mut pins);
+    }
+    drop(pins);
+    FLOW_CONTROL.store(flow_control, Ordering::Relaxed);
 
     REGS.AUX_MU_IER.modify(AUX_MU_IER::ENABLE_RX_IRQ::SET);
     enable_irq(Irq::Gpu(GpuIrq::Aux));
 
     // Setup is complete, enable RX/TX
+    let flow_control = if flow_control {
+        AUX_MU_CNTL::RX_AUTOFLOW_ENABLE::SET + AUX_MU_CNTL::TX_AUTOFLOW_ENABLE::SET
+    } else {
+        AUX_MU_CNTL::RX_AUTOFLOW_ENABLE::CLEAR + AUX_MU_CNTL::TX_AUTOFLOW_ENABLE::CLEAR
+    };
     REGS.AUX_MU_CNTL
-        .modify(AUX_MU_CNTL::RX_ENABLE::SET + AUX_MU_CNTL::TX_ENABLE::SET);
+        .modify(AUX_MU_CNTL::RX_ENABLE::SET + AUX_MU_CNTL::TX_ENABLE::SET + flow_control);
+
+    TX.enable_buffering();
 }
 
+/// Queues a character for transmission. If the TX buffer is full this waits
+/// for space in it, and before init() or after disable_tx_buffering() the
+/// character is written synchronously.
 pub fn put_char(c: char) {
+    TX.put_char(c);
+}
+
+/// Writes a character, waiting until the UART accepts it. Anything still in
+/// the TX buffer is sent first unless the buffer is locked, e.g. by the code
+/// that panicked.
+#[cfg(feature = "gdb")]
+pub fn put_char_sync(c: char) {
+    TX.put_char_sync(c);
+}
+
+/// Makes put_char() synchronous again, for when interrupts can't be relied on
+/// anymore like after a panic
+pub fn disable_tx_buffering() {
+    TX.disable_buffering();
+}
+
+/// Reads a byte without waiting, bypassing the RX buffer. Only meant for
+/// polling with interrupts masked.
+#[cfg(feature = "gdb")]
+pub fn poll_byte() -> Option<u8> {
     peripheral_switch_in();
-    while !REGS.AUX_MU_LSR.is_set(AUX_MU_LSR::TX_READY) {
-        // Wait until we can transmit
+    if !REGS.AUX_MU_LSR.is_set(AUX_MU_LSR::DATA_READY) {
+        return None;
     }
-    REGS.AUX_MU_IO_DATA.set(c as u8);
+    Some(REGS.AUX_MU_IO_DATA.get())
 }
 
-fn get_char() -> char {
-    REGS.AUX_MU_IO_DATA.get() as char
+pub fn process_irq() {
+    peripheral_switch_in();
+
+    if REGS.AUX_MU_STAT.read(AUX_MU_STAT::RX_FIFO_FILL_LVL) > 0 {
+        process_rx_irq();
+    }
+
+    // The TX interrupt stays asserted for as long as the FIFO is empty, so it
+    // must be disabled when there is nothing left to send
+    TX.process_irq(|| REGS.AUX_MU_IER.modify(AUX_MU_IER::ENABLE_TX_IRQ::CLEAR));
 }
 
-pub fn process_rx_irq() {
-    peripheral_switch_in();
-    let pending_rx_chars = REGS.AUX_MU_STAT.read(AUX_MU_STAT::RX_FIFO_FILL_LVL);
+fn process_rx_irq() {
+    let flow_control = FLOW_CONTROL.load(Ordering::Relaxed);
     let mut rx_buffer = RX_BUFFER.lock();
 
-    for _ in 0..pending_rx_chars {
-        let tail = rx_buffer.tail;
-        rx_buffer.buffer[tail] = get_char();
-        rx_buffer.tail = (tail + 1) % RX_BUFFER_LEN;
-        rx_buffer.num_chars = (rx_buffer.num_chars + 1).min(RX_BUFFER_LEN);
+    loop {
+        // Reading LSR clears the overrun flag
+        let lsr = REGS.AUX_MU_LSR.extract();
+        if lsr.is_set(AUX_MU_LSR::RX_OVERRUN) {
+            RX_COUNTERS.count_overrun();
+        }
+        if !lsr.is_set(AUX_MU_LSR::DATA_READY) {
+            break;
+        }
+
+        if flow_control && rx_buffer.len() >= RX_HIGH_WATERMARK {
+            // The RX interrupt would keep firing for the characters left in
+            // the FIFO, the bottom half enables it again
+            REGS.AUX_MU_IER.modify(AUX_MU_IER::ENABLE_RX_IRQ::CLEAR);
+            break;
+        }
+
+        if !rx_buffer.push(REGS.AUX_MU_IO_DATA.get() as char) {
+            RX_COUNTERS.count_dropped();
+        }
+        RX_COUNTERS.count_received();
     }
 
     drop(rx_buffer);
@@ -223,27 +355,27 @@ pub fn process_rx_irq() {
 // This is the bottom half of the RX IRQ handler that runs outside interrupt context
 pub fn process_pending_chars() {
     peripheral_switch_in();
-    let mut rx_buffer = RX_BUFFER.lock();
-    // Copy and reset the buffer
-    let buffer = *rx_buffer;
-    rx_buffer.num_chars = 0;
-    // And release the lock before processing it so that interrupts are enabled again
-    drop(rx_buffer);
-
-    let mut start = if buffer.num_chars <= buffer.tail {
-        buffer.tail - buffer.num_chars
-    } else {
-        RX_BUFFER_LEN - (buffer.num_chars - buffer.tail)
-    };
-
-    let mut num_chars = buffer.num_chars;
-    while num_chars > 0 {
-        let c = buffer.buffer[start];
-        put_char(c);
-        if c == '\r' {
-            put_char('\n');
+    loop {
+        // Take one character at a time and release the lock before
+        // processing it so that interrupts are enabled again
+        let Some(c) = RX_BUFFER.lock().pop() else {
+            break;
+        };
+
+        // The UART belongs to GDB, the console is on the PL011. A Ctrl-C
+        // asks to stop.
+        #[cfg(feature = "gdb")]
+        if c == '\x03' && crate::gdb::is_attached() {
+            crate::gdb::breakpoint();
         }
-        start = (start + 1) % RX_BUFFER_LEN;
-        num_chars -= 1;
+        #[cfg(not(feature = "gdb"))]
+        tty::receive(c);
+    }
+
+    if FLOW_CONTROL.load(Ordering::Relaxed) {
+        // There is room in the RX buffer again. Interrupts must be disabled
+        // so that the IRQ handler can't modify AUX_MU_IER in the meantime.
+        let _rx_buffer = RX_BUFFER.lock();
+        REGS.AUX_MU_IER.modify(AUX_MU_IER::ENABLE_RX_IRQ::SET);
     }
 }
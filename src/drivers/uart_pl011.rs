@@ -0,0 +1,306 @@
+// The PL011 UART (UART0), see BCM2837 chapter 13 and the ARM PrimeCell UART
+// (PL011) Technical Reference Manual.
+//
+// NOTE: On the Raspberry Pi 3 the PL011 is wired to the Bluetooth module by
+// default. It only reaches GPIO14/15 with "dtoverlay=disable-bt" (or
+// "dtoverlay=miniuart-bt") in config.txt, and then only one of the two UARTs
+// can use those pins.
+
+use crate::drivers::gpio::{self, GPIOPin};
+use crate::drivers::mailbox::{self, ClockId};
+use crate::drivers::serial::{self, RxBuffer, RxCounters, RxStats, SerialPort, SerialTx};
+use crate::drivers::{peripheral_switch_in, MMIORegisters, PERIPHERALS_BASE};
+use crate::irq::{enable_irq, GpuIrq, Irq};
+use crate::locking::{IRQSpinLock, SpinLock};
+use crate::tty;
+use crate::{ACTIONS, PENDING_ACTIONS};
+use heapless::Vec;
+use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
+use tock_registers::registers::{ReadOnly, ReadWrite, WriteOnly};
+use tock_registers::{register_bitfields, register_structs};
+
+// SAFETY: There should a be a PL011 UART behind that address as per BMC2837
+const REGS: MMIORegisters<PL011Registers> =
+    unsafe { MMIORegisters::<PL011Registers>::new(PERIPHERALS_BASE.add(0x20_1000)) };
+
+// The rate the firmware sets the UART clock to, used if it can't be asked for
+// the real one
+const DEFAULT_UART_CLOCK_HZ: u32 = 48_000_000;
+
+static RX_BUFFER: IRQSpinLock<RxBuffer<{ serial::RX_BUFFER_LEN }>> =
+    IRQSpinLock::new(RxBuffer::new());
+
+static RX_COUNTERS: RxCounters = RxCounters::new();
+
+pub fn rx_stats() -> RxStats {
+    RX_COUNTERS.stats()
+}
+
+const TX_BUFFER_LEN: usize = 1024;
+
+struct Pl011;
+
+impl SerialPort for Pl011 {
+    fn tx_ready() -> bool {
+        peripheral_switch_in();
+        !REGS.FR.is_set(FR::TXFF)
+    }
+
+    fn write_byte(byte: u8) {
+        REGS.DR.write(DR::DATA.val(byte as u32));
+    }
+
+    // Unlike the mini UART's, the TX interrupt only fires when the TX FIFO
+    // drains below the trigger level. Bytes are only left in the TX buffer
+    // when they didn't fit in the FIFO, so it's full and the interrupt will
+    // fire.
+    fn enable_tx_irq() {
+        REGS.IMSC.modify(INT::TX::SET);
+    }
+}
+
+// The pins the UART is routed to
+static PINS: SpinLock<Vec<GPIOPin, { serial::MAX_PINS }>> = SpinLock::new(Vec::new());
+
+static TX: SerialTx<Pl011, TX_BUFFER_LEN> = SerialTx::new();
+
+register_bitfields! {
+    u32,
+
+    DR [
+        DATA OFFSET(0)  NUMBITS(8) [],
+        FE   OFFSET(8)  NUMBITS(1) [],
+        PE   OFFSET(9)  NUMBITS(1) [],
+        BE   OFFSET(10) NUMBITS(1) [],
+        OE   OFFSET(11) NUMBITS(1) [],
+    ],
+
+    FR [
+        CTS  OFFSET(0) NUMBITS(1) [],
+        BUSY OFFSET(3) NUMBITS(1) [],
+        RXFE OFFSET(4) NUMBITS(1) [],
+        TXFF OFFSET(5) NUMBITS(1) [],
+        RXFF OFFSET(6) NUMBITS(1) [],
+        TXFE OFFSET(7) NUMBITS(1) [],
+    ],
+
+    IBRD [
+        DIVINT OFFSET(0) NUMBITS(16) [],
+    ],
+
+    FBRD [
+        DIVFRAC OFFSET(0) NUMBITS(6) [],
+    ],
+
+    LCRH [
+        BRK  OFFSET(0) NUMBITS(1) [],
+        PEN  OFFSET(1) NUMBITS(1) [],
+        EPS  OFFSET(2) NUMBITS(1) [],
+        STP2 OFFSET(3) NUMBITS(1) [],
+        FEN  OFFSET(4) NUMBITS(1) [],
+        WLEN OFFSET(5) NUMBITS(2) [
+            FIVE_BITS = 0,
+            SIX_BITS = 1,
+            SEVEN_BITS = 2,
+            EIGHT_BITS = 3,
+        ],
+        SPS  OFFSET(7) NUMBITS(1) [],
+    ],
+
+    CR [
+        UARTEN OFFSET(0)  NUMBITS(1) [],
+        LBE    OFFSET(7)  NUMBITS(1) [],
+        TXE    OFFSET(8)  NUMBITS(1) [],
+        RXE    OFFSET(9)  NUMBITS(1) [],
+        RTS    OFFSET(11) NUMBITS(1) [],
+        RTSEN  OFFSET(14) NUMBITS(1) [],
+        CTSEN  OFFSET(15) NUMBITS(1) [],
+    ],
+
+    IFLS [
+        TXIFLSEL OFFSET(0) NUMBITS(3) [
+            ONE_EIGHTH = 0,
+            ONE_QUARTER = 1,
+            ONE_HALF = 2,
+            THREE_QUARTERS = 3,
+            SEVEN_EIGHTHS = 4,
+        ],
+        RXIFLSEL OFFSET(3) NUMBITS(3) [
+            ONE_EIGHTH = 0,
+            ONE_QUARTER = 1,
+            ONE_HALF = 2,
+            THREE_QUARTERS = 3,
+            SEVEN_EIGHTHS = 4,
+        ],
+    ],
+
+    // Shared by IMSC, RIS, MIS and ICR
+    INT [
+        CTSM OFFSET(1)  NUMBITS(1) [],
+        RX   OFFSET(4)  NUMBITS(1) [],
+        TX   OFFSET(5)  NUMBITS(1) [],
+        RT   OFFSET(6)  NUMBITS(1) [],
+        FE   OFFSET(7)  NUMBITS(1) [],
+        PE   OFFSET(8)  NUMBITS(1) [],
+        BE   OFFSET(9)  NUMBITS(1) [],
+        OE   OFFSET(10) NUMBITS(1) [],
+    ],
+}
+
+register_structs! {
+    #[allow(non_snake_case)]
+    PL011Registers {
+        (0x00 => DR: ReadWrite<u32, DR::Register>),
+        (0x04 => RSRECR: ReadWrite<u32>),
+        (0x08 => _reserved0),
+        (0x18 => FR: ReadOnly<u32, FR::Register>),
+        (0x1c => _reserved1),
+        (0x24 => IBRD: ReadWrite<u32, IBRD::Register>),
+        (0x28 => FBRD: ReadWrite<u32, FBRD::Register>),
+        (0x2c => LCRH: ReadWrite<u32, LCRH::Register>),
+        (0x30 => CR: ReadWrite<u32, CR::Register>),
+        (0x34 => IFLS: ReadWrite<u32, IFLS::Register>),
+        (0x38 => IMSC: ReadWrite<u32, INT::Register>),
+        (0x3c => RIS: ReadOnly<u32, INT::Register>),
+        (0x40 => MIS: ReadOnly<u32, INT::Register>),
+        (0x44 => ICR: WriteOnly<u32, INT::Register>),
+        (0x48 => @END),
+    }
+}
+
+fn set_baud_divisor(uart_clock_hz: u32, baud_rate: u32) {
+    // divisor = uart_clock / (16 * baud_rate), with IBRD holding the integer
+    // part and FBRD the fractional part in 64ths. Compute 64 * divisor,
+    // rounded to the nearest integer.
+    let divisor = (4 * uart_clock_hz as u64 + baud_rate as u64 / 2) / baud_rate as u64;
+    REGS.IBRD.write(IBRD::DIVINT.val((divisor >> 6) as u32));
+    REGS.FBRD.write(FBRD::DIVFRAC.val((divisor & 0x3f) as u32));
+}
+
+// The divisor registers are only latched by a write to LCRH
+fn write_line_control() {
+    REGS.LCRH
+        .write(LCRH::WLEN::EIGHT_BITS + LCRH::FEN::SET + LCRH::STP2::CLEAR + LCRH::PEN::CLEAR);
+}
+
+/// Changes the baud rate, after waiting for the character being transmitted
+/// to go out
+pub fn set_baud_rate(baud_rate: u32) {
+    peripheral_switch_in();
+    let uart_clock_hz = mailbox::get_clock_rate(ClockId::Uart).unwrap_or(DEFAULT_UART_CLOCK_HZ);
+
+    while REGS.FR.is_set(FR::BUSY) {}
+    // The control registers must not be changed while the UART is enabled
+    REGS.CR.modify(CR::UARTEN::CLEAR);
+    set_baud_divisor(uart_clock_hz, baud_rate);
+    write_line_control();
+    REGS.CR.modify(CR::UARTEN::SET);
+}
+
+/// Configure UART for 8N1 (1 start bit, 8 data bits, no parity, 1 stop bit).
+/// With flow_control the UART only transmits while CTS is asserted and
+/// deasserts RTS when its RX FIFO fills up.
+pub fn init(baud_rate: u32, flow_control: bool) {
+    peripheral_switch_in();
+    REGS.CR.set(0);
+    while REGS.FR.is_set(FR::BUSY) {
+        // Wait until the current character is out before reconfiguring
+    }
+    // Flush the FIFOs
+    REGS.LCRH.modify(LCRH::FEN::CLEAR);
+
+    let uart_clock_hz = mailbox::get_clock_rate(ClockId::Uart).unwrap_or(DEFAULT_UART_CLOCK_HZ);
+    set_baud_divisor(uart_clock_hz, baud_rate);
+    write_line_control();
+
+    // TXD0 and RXD0, then CTS0 and RTS0
+    let mut pins = PINS.lock();
+    // Give back the pins of a previous init() first
+    pins.clear();
+    serial::claim_pins("uart_pl011", &[14, 15], gpio::PinMode::Alt0, &mut pins);
+    if flow_control {
+        serial::claim_pins("uart_pl011", &[16, 17], gpio::PinMode::Alt3, &mut pins);
+    }
+    drop(pins);
+
+    // The RX interrupt fires when the RX FIFO reaches the trigger level and
+    // the RX timeout interrupt takes care of anything left below it
+    REGS.IFLS
+        .write(IFLS::RXIFLSEL::ONE_HALF + IFLS::TXIFLSEL::ONE_EIGHTH);
+    REGS.ICR.set(0x7ff);
+    REGS.IMSC.write(INT::RX::SET + INT::RT::SET);
+    enable_irq(Irq::Gpu(GpuIrq::Uart));
+
+    // Setup is complete, enable RX/TX
+    let flow_control = if flow_control {
+        CR::RTSEN::SET + CR::CTSEN::SET
+    } else {
+        CR::RTSEN::CLEAR + CR::CTSEN::CLEAR
+    };
+    REGS.CR
+        .write(CR::UARTEN::SET + CR::TXE::SET + CR::RXE::SET + flow_control);
+
+    TX.enable_buffering();
+}
+
+/// Queues a character for transmission. If the TX buffer is full this waits
+/// for space in it, and before init() or after disable_tx_buffering() the
+/// character is written synchronously.
+pub fn put_char(c: char) {
+    TX.put_char(c);
+}
+
+/// Makes put_char() synchronous again, for when interrupts can't be relied on
+/// anymore like after a panic
+pub fn disable_tx_buffering() {
+    TX.disable_buffering();
+}
+
+pub fn process_irq() {
+    peripheral_switch_in();
+
+    if REGS.MIS.matches_any(&[INT::RX::SET, INT::RT::SET]) {
+        process_rx_irq();
+    }
+
+    TX.process_irq(|| REGS.IMSC.modify(INT::TX::CLEAR));
+    REGS.ICR.write(INT::TX::SET);
+}
+
+fn process_rx_irq() {
+    let mut rx_buffer = RX_BUFFER.lock();
+
+    // Reading the data register clears the RX interrupts once the FIFO level
+    // drops below the trigger level
+    while !REGS.FR.is_set(FR::RXFE) {
+        // Characters received with framing, parity or break errors are
+        // passed through as they are. OE is set on the character received
+        // while the FIFO was full, the ones after it were lost.
+        let data = REGS.DR.extract();
+        if data.is_set(DR::OE) {
+            RX_COUNTERS.count_overrun();
+        }
+        if !rx_buffer.push(data.read(DR::DATA) as u8 as char) {
+            RX_COUNTERS.count_dropped();
+        }
+        RX_COUNTERS.count_received();
+    }
+    REGS.ICR.write(INT::RT::SET);
+
+    drop(rx_buffer);
+
+    let mut actions = PENDING_ACTIONS.lock();
+    *actions |= 1 << (ACTIONS::Pl011Action as u64);
+}
+
+// This is the bottom half of the RX IRQ handler that runs outside interrupt context
+pub fn process_pending_chars() {
+    loop {
+        // Take one character at a time and release the lock before
+        // processing it so that interrupts are enabled again
+        let Some(c) = RX_BUFFER.lock().pop() else {
+            break;
+        };
+        tty::receive(c);
+    }
+}
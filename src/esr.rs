@@ -0,0 +1,504 @@
+// Decoder for the Exception Syndrome Register. See the ARMv8-A Architecture
+// Reference Manual, section D17.2.37 ESR_EL1 for the meaning of the fields.
+
+use core::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExceptionClass {
+    Unknown,
+    TrappedWfx,
+    TrappedMcrMrcCp15,
+    TrappedMcrrMrrcCp15,
+    TrappedMcrMrcCp14,
+    TrappedLdcStc,
+    TrappedFpAccess,
+    TrappedLd64bSt64b,
+    TrappedMrrcCp14,
+    BranchTarget,
+    IllegalExecutionState,
+    Svc32,
+    Svc64,
+    Hvc64,
+    Smc64,
+    TrappedMsrMrs,
+    TrappedSve,
+    PointerAuthFailure,
+    InstrAbortLowerEL,
+    InstrAbortCurrentEL,
+    PcAlignmentFault,
+    DataAbortLowerEL,
+    DataAbortCurrentEL,
+    SpAlignmentFault,
+    TrappedFpException32,
+    TrappedFpException64,
+    SError,
+    BreakpointLowerEL,
+    BreakpointCurrentEL,
+    SoftwareStepLowerEL,
+    SoftwareStepCurrentEL,
+    WatchpointLowerEL,
+    WatchpointCurrentEL,
+    Bkpt32,
+    Brk64,
+    Reserved(u8),
+}
+
+impl ExceptionClass {
+    pub fn from_ec(ec: u8) -> Self {
+        match ec {
+            0x00 => Self::Unknown,
+            0x01 => Self::TrappedWfx,
+            0x03 => Self::TrappedMcrMrcCp15,
+            0x04 => Self::TrappedMcrrMrrcCp15,
+            0x05 => Self::TrappedMcrMrcCp14,
+            0x06 => Self::TrappedLdcStc,
+            0x07 => Self::TrappedFpAccess,
+            0x0a => Self::TrappedLd64bSt64b,
+            0x0c => Self::TrappedMrrcCp14,
+            0x0d => Self::BranchTarget,
+            0x0e => Self::IllegalExecutionState,
+            0x11 => Self::Svc32,
+            0x15 => Self::Svc64,
+            0x16 => Self::Hvc64,
+            0x17 => Self::Smc64,
+            0x18 => Self::TrappedMsrMrs,
+            0x19 => Self::TrappedSve,
+            0x1c => Self::PointerAuthFailure,
+            0x20 => Self::InstrAbortLowerEL,
+            0x21 => Self::InstrAbortCurrentEL,
+            0x22 => Self::PcAlignmentFault,
+            0x24 => Self::DataAbortLowerEL,
+            0x25 => Self::DataAbortCurrentEL,
+            0x26 => Self::SpAlignmentFault,
+            0x28 => Self::TrappedFpException32,
+            0x2c => Self::TrappedFpException64,
+            0x2f => Self::SError,
+            0x30 => Self::BreakpointLowerEL,
+            0x31 => Self::BreakpointCurrentEL,
+            0x32 => Self::SoftwareStepLowerEL,
+            0x33 => Self::SoftwareStepCurrentEL,
+            0x34 => Self::WatchpointLowerEL,
+            0x35 => Self::WatchpointCurrentEL,
+            0x38 => Self::Bkpt32,
+            0x3c => Self::Brk64,
+            ec => Self::Reserved(ec),
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Self::Unknown => "Unknown reason",
+            Self::TrappedWfx => "Trapped WFI or WFE instruction",
+            Self::TrappedMcrMrcCp15 => "Trapped MCR or MRC access (coproc=0b1111)",
+            Self::TrappedMcrrMrrcCp15 => "Trapped MCRR or MRRC access (coproc=0b1111)",
+            Self::TrappedMcrMrcCp14 => "Trapped MCR or MRC access (coproc=0b1110)",
+            Self::TrappedLdcStc => "Trapped LDC or STC access",
+            Self::TrappedFpAccess => "Trapped access to SVE, Advanced SIMD or floating-point",
+            Self::TrappedLd64bSt64b => "Trapped LD64B or ST64B instruction",
+            Self::TrappedMrrcCp14 => "Trapped MRRC access (coproc=0b1110)",
+            Self::BranchTarget => "Branch Target Exception",
+            Self::IllegalExecutionState => "Illegal Execution state",
+            Self::Svc32 => "SVC instruction execution in AArch32 state",
+            Self::Svc64 => "SVC instruction execution in AArch64 state",
+            Self::Hvc64 => "HVC instruction execution in AArch64 state",
+            Self::Smc64 => "SMC instruction execution in AArch64 state",
+            Self::TrappedMsrMrs => "Trapped MSR, MRS or System instruction",
+            Self::TrappedSve => "Trapped access to SVE functionality",
+            Self::PointerAuthFailure => "Pointer authentication failure",
+            Self::InstrAbortLowerEL => "Instruction Abort from a lower EL",
+            Self::InstrAbortCurrentEL => "Instruction Abort from the current EL",
+            Self::PcAlignmentFault => "PC alignment fault",
+            Self::DataAbortLowerEL => "Data Abort from a lower EL",
+            Self::DataAbortCurrentEL => "Data Abort from the current EL",
+            Self::SpAlignmentFault => "SP alignment fault",
+            Self::TrappedFpException32 => "Trapped floating-point exception (AArch32)",
+            Self::TrappedFpException64 => "Trapped floating-point exception (AArch64)",
+            Self::SError => "SError interrupt",
+            Self::BreakpointLowerEL => "Breakpoint from a lower EL",
+            Self::BreakpointCurrentEL => "Breakpoint from the current EL",
+            Self::SoftwareStepLowerEL => "Software Step from a lower EL",
+            Self::SoftwareStepCurrentEL => "Software Step from the current EL",
+            Self::WatchpointLowerEL => "Watchpoint from a lower EL",
+            Self::WatchpointCurrentEL => "Watchpoint from the current EL",
+            Self::Bkpt32 => "BKPT instruction execution in AArch32 state",
+            Self::Brk64 => "BRK instruction execution in AArch64 state",
+            Self::Reserved(_) => "Reserved exception class",
+        }
+    }
+}
+
+/// The Data/Instruction Fault Status Code reported by aborts and watchpoints
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultStatus {
+    AddressSize(u8),
+    Translation(u8),
+    AccessFlag(u8),
+    Permission(u8),
+    SyncExternal,
+    SyncTagCheck,
+    SyncExternalOnWalk(u8),
+    SyncParity,
+    SyncParityOnWalk(u8),
+    Alignment,
+    Debug,
+    TlbConflict,
+    UnsupportedAtomicUpdate,
+    Lockdown,
+    UnsupportedExclusive,
+    Other(u8),
+}
+
+impl FaultStatus {
+    pub fn from_fsc(fsc: u8) -> Self {
+        let level = fsc & 0b11;
+        match fsc & 0x3f {
+            0b00_0000..=0b00_0011 => Self::AddressSize(level),
+            0b00_0100..=0b00_0111 => Self::Translation(level),
+            0b00_1000..=0b00_1011 => Self::AccessFlag(level),
+            0b00_1100..=0b00_1111 => Self::Permission(level),
+            0b01_0000 => Self::SyncExternal,
+            0b01_0001 => Self::SyncTagCheck,
+            0b01_0100..=0b01_0111 => Self::SyncExternalOnWalk(level),
+            0b01_1000 => Self::SyncParity,
+            0b01_1100..=0b01_1111 => Self::SyncParityOnWalk(level),
+            0b10_0001 => Self::Alignment,
+            0b10_0010 => Self::Debug,
+            0b11_0000 => Self::TlbConflict,
+            0b11_0001 => Self::UnsupportedAtomicUpdate,
+            0b11_0100 => Self::Lockdown,
+            0b11_0101 => Self::UnsupportedExclusive,
+            fsc => Self::Other(fsc),
+        }
+    }
+}
+
+impl fmt::Display for FaultStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AddressSize(l) => write!(f, "address size fault, level {l}"),
+            Self::Translation(l) => write!(f, "translation fault, level {l}"),
+            Self::AccessFlag(l) => write!(f, "access flag fault, level {l}"),
+            Self::Permission(l) => write!(f, "permission fault, level {l}"),
+            Self::SyncExternal => write!(f, "synchronous external abort"),
+            Self::SyncTagCheck => write!(f, "synchronous tag check fault"),
+            Self::SyncExternalOnWalk(l) => {
+                write!(f, "synchronous external abort on table walk, level {l}")
+            }
+            Self::SyncParity => write!(f, "synchronous parity or ECC error"),
+            Self::SyncParityOnWalk(l) => {
+                write!(
+                    f,
+                    "synchronous parity or ECC error on table walk, level {l}"
+                )
+            }
+            Self::Alignment => write!(f, "alignment fault"),
+            Self::Debug => write!(f, "debug event"),
+            Self::TlbConflict => write!(f, "TLB conflict abort"),
+            Self::UnsupportedAtomicUpdate => write!(f, "unsupported atomic hardware update"),
+            Self::Lockdown => write!(f, "lockdown"),
+            Self::UnsupportedExclusive => write!(f, "unsupported exclusive or atomic access"),
+            Self::Other(fsc) => write!(f, "unknown fault status {fsc:#04x}"),
+        }
+    }
+}
+
+/// The Instruction Specific Syndrome, decoded according to the exception class
+#[derive(Clone, Copy, Debug)]
+pub enum Iss {
+    DataAbort {
+        // The fields from sas to ar are only valid if isv is set
+        isv: bool,
+        sas: u8,
+        sse: bool,
+        srt: u8,
+        sf: bool,
+        ar: bool,
+        fnv: bool,
+        ea: bool,
+        cm: bool,
+        s1ptw: bool,
+        wnr: bool,
+        dfsc: FaultStatus,
+    },
+    InstrAbort {
+        fnv: bool,
+        ea: bool,
+        s1ptw: bool,
+        ifsc: FaultStatus,
+    },
+    /// SVC, HVC and SMC
+    Call {
+        imm16: u16,
+    },
+    /// BRK and BKPT
+    Breakpoint {
+        comment: u16,
+    },
+    MsrMrs {
+        op0: u8,
+        op1: u8,
+        op2: u8,
+        crn: u8,
+        crm: u8,
+        rt: u8,
+        read: bool,
+    },
+    Wfx {
+        wfe: bool,
+    },
+    FpException {
+        // The exception flags are only valid if tfv is set
+        tfv: bool,
+        idf: bool,
+        ixf: bool,
+        uff: bool,
+        off: bool,
+        dzf: bool,
+        iof: bool,
+    },
+    SError {
+        ids: bool,
+        iesb: bool,
+        aet: u8,
+        ea: bool,
+        dfsc: FaultStatus,
+    },
+    SoftwareStep {
+        isv: bool,
+        ex: bool,
+    },
+    Watchpoint {
+        cm: bool,
+        wnr: bool,
+        dfsc: FaultStatus,
+    },
+    Other(u32),
+}
+
+/// A decoded ESR_EL1 value
+#[derive(Clone, Copy, Debug)]
+pub struct Syndrome {
+    pub raw: u64,
+    pub class: ExceptionClass,
+    /// Set for 32-bit instructions
+    pub il: bool,
+    pub iss: Iss,
+}
+
+fn bit(value: u32, n: u32) -> bool {
+    (value >> n) & 1 == 1
+}
+
+fn bits(value: u32, offset: u32, num: u32) -> u8 {
+    ((value >> offset) & ((1 << num) - 1)) as u8
+}
+
+pub fn decode(esr: u64) -> Syndrome {
+    let class = ExceptionClass::from_ec(((esr >> 26) & 0x3f) as u8);
+    let iss = (esr & 0x1ff_ffff) as u32;
+
+    let iss = match class {
+        ExceptionClass::DataAbortLowerEL | ExceptionClass::DataAbortCurrentEL => Iss::DataAbort {
+            isv: bit(iss, 24),
+            sas: bits(iss, 22, 2),
+            sse: bit(iss, 21),
+            srt: bits(iss, 16, 5),
+            sf: bit(iss, 15),
+            ar: bit(iss, 14),
+            fnv: bit(iss, 10),
+            ea: bit(iss, 9),
+            cm: bit(iss, 8),
+            s1ptw: bit(iss, 7),
+            wnr: bit(iss, 6),
+            dfsc: FaultStatus::from_fsc(bits(iss, 0, 6)),
+        },
+        ExceptionClass::InstrAbortLowerEL | ExceptionClass::InstrAbortCurrentEL => {
+            Iss::InstrAbort {
+                fnv: bit(iss, 10),
+                ea: bit(iss, 9),
+                s1ptw: bit(iss, 7),
+                ifsc: FaultStatus::from_fsc(bits(iss, 0, 6)),
+            }
+        }
+        ExceptionClass::Svc32
+        | ExceptionClass::Svc64
+        | ExceptionClass::Hvc64
+        | ExceptionClass::Smc64 => Iss::Call {
+            imm16: (iss & 0xffff) as u16,
+        },
+        ExceptionClass::Brk64 | ExceptionClass::Bkpt32 => Iss::Breakpoint {
+            comment: (iss & 0xffff) as u16,
+        },
+        ExceptionClass::TrappedMsrMrs => Iss::MsrMrs {
+            op0: bits(iss, 20, 2),
+            op2: bits(iss, 17, 3),
+            op1: bits(iss, 14, 3),
+            crn: bits(iss, 10, 4),
+            rt: bits(iss, 5, 5),
+            crm: bits(iss, 1, 4),
+            read: bit(iss, 0),
+        },
+        ExceptionClass::TrappedWfx => Iss::Wfx { wfe: bit(iss, 0) },
+        ExceptionClass::TrappedFpException32 | ExceptionClass::TrappedFpException64 => {
+            Iss::FpException {
+                tfv: bit(iss, 23),
+                idf: bit(iss, 7),
+                ixf: bit(iss, 4),
+                uff: bit(iss, 3),
+                off: bit(iss, 2),
+                dzf: bit(iss, 1),
+                iof: bit(iss, 0),
+            }
+        }
+        ExceptionClass::SError => Iss::SError {
+            ids: bit(iss, 24),
+            iesb: bit(iss, 13),
+            aet: bits(iss, 10, 3),
+            ea: bit(iss, 9),
+            dfsc: FaultStatus::from_fsc(bits(iss, 0, 6)),
+        },
+        ExceptionClass::SoftwareStepLowerEL | ExceptionClass::SoftwareStepCurrentEL => {
+            Iss::SoftwareStep {
+                isv: bit(iss, 24),
+                ex: bit(iss, 6),
+            }
+        }
+        ExceptionClass::WatchpointLowerEL | ExceptionClass::WatchpointCurrentEL => {
+            Iss::Watchpoint {
+                cm: bit(iss, 8),
+                wnr: bit(iss, 6),
+                dfsc: FaultStatus::from_fsc(bits(iss, 0, 6)),
+            }
+        }
+        _ => Iss::Other(iss),
+    };
+
+    Syndrome {
+        raw: esr,
+        class,
+        il: (esr >> 25) & 1 == 1,
+        iss,
+    }
+}
+
+impl fmt::Display for Syndrome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ec = (self.raw >> 26) & 0x3f;
+        write!(
+            f,
+            "EC={ec:#04x} ({}) IL={}",
+            self.class.description(),
+            self.il as u8
+        )?;
+
+        let b = |v: bool| v as u8;
+        match self.iss {
+            Iss::DataAbort {
+                isv,
+                sas,
+                sse,
+                srt,
+                sf,
+                ar,
+                fnv,
+                ea,
+                cm,
+                s1ptw,
+                wnr,
+                dfsc,
+            } => {
+                write!(
+                    f,
+                    " ISV={} FnV={} EA={} CM={} S1PTW={} WnR={} DFSC=({dfsc})",
+                    b(isv),
+                    b(fnv),
+                    b(ea),
+                    b(cm),
+                    b(s1ptw),
+                    b(wnr)
+                )?;
+                if isv {
+                    write!(
+                        f,
+                        " SAS={sas} SSE={} SRT={srt} SF={} AR={}",
+                        b(sse),
+                        b(sf),
+                        b(ar)
+                    )?;
+                }
+                Ok(())
+            }
+            Iss::InstrAbort {
+                fnv,
+                ea,
+                s1ptw,
+                ifsc,
+            } => write!(
+                f,
+                " FnV={} EA={} S1PTW={} IFSC=({ifsc})",
+                b(fnv),
+                b(ea),
+                b(s1ptw)
+            ),
+            Iss::Call { imm16 } => write!(f, " imm16={imm16:#06x}"),
+            Iss::Breakpoint { comment } => write!(f, " comment={comment:#06x}"),
+            Iss::MsrMrs {
+                op0,
+                op1,
+                op2,
+                crn,
+                crm,
+                rt,
+                read,
+            } => write!(
+                f,
+                " {} S{op0}_{op1}_C{crn}_C{crm}_{op2} Rt=x{rt}",
+                if read { "MRS" } else { "MSR" }
+            ),
+            Iss::Wfx { wfe } => write!(f, " {}", if wfe { "WFE" } else { "WFI" }),
+            Iss::FpException {
+                tfv,
+                idf,
+                ixf,
+                uff,
+                off,
+                dzf,
+                iof,
+            } => write!(
+                f,
+                " TFV={} IDF={} IXF={} UFF={} OFF={} DZF={} IOF={}",
+                b(tfv),
+                b(idf),
+                b(ixf),
+                b(uff),
+                b(off),
+                b(dzf),
+                b(iof)
+            ),
+            Iss::SError {
+                ids,
+                iesb,
+                aet,
+                ea,
+                dfsc,
+            } => {
+                write!(f, " IDS={}", b(ids))?;
+                if ids {
+                    // The rest of the syndrome is IMPLEMENTATION DEFINED
+                    return Ok(());
+                }
+                write!(
+                    f,
+                    " IESB={} AET={aet:#05b} EA={} DFSC=({dfsc})",
+                    b(iesb),
+                    b(ea)
+                )
+            }
+            Iss::SoftwareStep { isv, ex } => write!(f, " ISV={} EX={}", b(isv), b(ex)),
+            Iss::Watchpoint { cm, wnr, dfsc } => {
+                write!(f, " CM={} WnR={} DFSC=({dfsc})", b(cm), b(wnr))
+            }
+            Iss::Other(iss) => write!(f, " ISS={iss:#09x}"),
+        }
+    }
+}
@@ -1,7 +1,12 @@
-use crate::irq;
-use aarch64_cpu::registers::{ESR_EL1, FAR_EL1, VBAR_EL1};
+use crate::address::{KSTACK_REGION_SIZE, KSTACK_REGION_START, KSTACK_SIZE};
+use crate::cpu::NUM_CPUS;
+use crate::esr::{self, Iss};
+use crate::kstack::{self, EMERGENCY_STACKS, EMERGENCY_STACK_SIZE};
+use crate::{backtrace, cpu, debug, fpsimd, irq, print, println};
+use aarch64_cpu::registers::{SPSR_EL1, VBAR_EL1};
 use core::arch::global_asm;
-use tock_registers::interfaces::{Readable, Writeable};
+use core::sync::atomic::{AtomicPtr, Ordering};
+use tock_registers::interfaces::Writeable;
 use tock_registers::LocalRegisterCopy;
 
 #[repr(C)]
@@ -9,23 +14,142 @@ use tock_registers::LocalRegisterCopy;
 // the struct. If anything changes here the assembly routines will need to be
 // updated too. The size assertion below just serves as a reminder in case the
 // struct is extended.
-struct ExceptionFrame {
-    regs: [u64; 31],
-    spsr_el1: u64,
-    elr_el1: u64,
-    esr_el1: u64,
+pub struct ExceptionFrame {
+    pub regs: [u64; 31],
+    pub spsr_el1: u64,
+    pub elr_el1: u64,
+    pub esr_el1: u64,
+    // Only valid for exceptions that report a faulting address
+    pub far_el1: u64,
+    // The SP of the context that took the exception
+    sp: u64,
+}
+
+impl ExceptionFrame {
+    pub fn fp(&self) -> u64 {
+        self.regs[29]
+    }
+
+    pub fn sp(&self) -> u64 {
+        self.sp
+    }
+
+    fn dump(&self) {
+        println!("Exception frame:");
+        for (i, row) in self.regs.chunks(4).enumerate() {
+            for (j, reg) in row.iter().enumerate() {
+                let n = i * 4 + j;
+                let name_pad = if n < 10 { " " } else { "" };
+                print!("{name_pad}x{n}: {reg:#018x} ");
+            }
+            println!();
+        }
+        println!(" sp: {:#018x} elr: {:#018x}", self.sp(), self.elr_el1);
+        println!(
+            "SPSR_EL1: {:#010x} {}",
+            self.spsr_el1,
+            SpsrDisplay(self.spsr_el1)
+        );
+        println!(
+            " ESR_EL1: {:#010x} [{}]",
+            self.esr_el1,
+            esr::decode(self.esr_el1)
+        );
+        println!(" FAR_EL1: {:#018x}", self.far_el1);
+    }
+}
+
+// Decodes the mode, DAIF and NZCV bits of a SPSR value. Set flags are printed
+// in upper case and clear flags in lower case.
+struct SpsrDisplay(u64);
+
+impl core::fmt::Display for SpsrDisplay {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let spsr = LocalRegisterCopy::<u64, SPSR_EL1::Register>::new(self.0);
+        let flag = |set: bool, c: char| if set { c.to_ascii_uppercase() } else { c };
+
+        match spsr.read_as_enum::<SPSR_EL1::M::Value>(SPSR_EL1::M) {
+            Some(SPSR_EL1::M::Value::EL0t) => write!(f, "[EL0t ")?,
+            Some(SPSR_EL1::M::Value::EL1t) => write!(f, "[EL1t ")?,
+            Some(SPSR_EL1::M::Value::EL1h) => write!(f, "[EL1h ")?,
+            _ => write!(f, "[M={:#06b} ", spsr.read(SPSR_EL1::M))?,
+        }
+
+        write!(
+            f,
+            "{}{}{}{} {}{}{}{}]",
+            flag(spsr.is_set(SPSR_EL1::D), 'd'),
+            flag(spsr.is_set(SPSR_EL1::A), 'a'),
+            flag(spsr.is_set(SPSR_EL1::I), 'i'),
+            flag(spsr.is_set(SPSR_EL1::F), 'f'),
+            flag(spsr.is_set(SPSR_EL1::N), 'n'),
+            flag(spsr.is_set(SPSR_EL1::Z), 'z'),
+            flag(spsr.is_set(SPSR_EL1::C), 'c'),
+            flag(spsr.is_set(SPSR_EL1::V), 'v'),
+        )
+    }
 }
 
 // TODO: Define the size in assembly and write a build script that generates
 // a Rust file with a const variable.
 const _: () = {
     assert!(
-        core::mem::size_of::<ExceptionFrame>() == 34 * 8,
+        core::mem::size_of::<ExceptionFrame>() == 36 * 8,
         "Exception frame size is wrong"
     );
 };
 
-global_asm!(include_str!("exceptions.s"));
+// The stack overflow check in exceptions.s compares the faulting address
+// shifted right by KSTACK_REGION_SHIFT against an immediate, so the shifted
+// region base must fit in a CMN immediate.
+const KSTACK_REGION_SHIFT: u32 = KSTACK_REGION_SIZE.trailing_zeros();
+const KSTACK_REGION_INDEX_NEG: i64 =
+    -((KSTACK_REGION_START.as_u64() as i64) >> KSTACK_REGION_SHIFT);
+
+const _: () = {
+    assert!(KSTACK_REGION_INDEX_NEG > 0 && KSTACK_REGION_INDEX_NEG < 4096);
+    assert!(EMERGENCY_STACK_SIZE.is_power_of_two());
+};
+
+global_asm!(
+    include_str!("exceptions.s"),
+    KSTACK_SLOT_GUARD_BIT = const KSTACK_SIZE.trailing_zeros(),
+    KSTACK_REGION_SHIFT = const KSTACK_REGION_SHIFT,
+    KSTACK_REGION_INDEX_NEG = const KSTACK_REGION_INDEX_NEG,
+    CPU_ID_MASK = const NUM_CPUS - 1,
+    EMERGENCY_STACK_SHIFT = const EMERGENCY_STACK_SIZE.trailing_zeros(),
+    EMERGENCY_STACKS = sym EMERGENCY_STACKS,
+);
+
+// The frame of the exception that caused the ongoing panic on each CPU, if
+// any. This lets the panic handler report the context that faulted instead of
+// its own.
+static FATAL_FRAMES: [AtomicPtr<ExceptionFrame>; NUM_CPUS] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; NUM_CPUS];
+
+/// Panics after recording the exception frame for the panic handler
+macro_rules! fatal {
+    ($eframe:expr, $($arg:tt)*) => {{
+        FATAL_FRAMES[cpu::current_id()].store($eframe, Ordering::Relaxed);
+        panic!($($arg)*)
+    }};
+}
+
+/// Called by the panic handler. If the panic was caused by an exception this
+/// prints the registers and the backtrace of the faulting context and returns
+/// true.
+pub fn report_fatal_exception() -> bool {
+    let eframe = FATAL_FRAMES[cpu::current_id()].load(Ordering::Relaxed);
+    // SAFETY: The frame lives on the stack of the exception handler that
+    // panicked, and the panic handler never returns to it
+    let Some(eframe) = (unsafe { eframe.as_ref() }) else {
+        return false;
+    };
+
+    eframe.dump();
+    backtrace::print(eframe.fp(), eframe.elr_el1);
+    true
+}
 
 pub fn install_exception_table() {
     extern "C" {
@@ -36,91 +160,167 @@ pub fn install_exception_table() {
 }
 
 #[no_mangle]
-extern "C" fn el1_sp0_sync_handler(_eframe: &mut ExceptionFrame) {
-    panic!("Unexpected synchronous exception from the current EL while using SP_EL0");
+extern "C" fn el1_sp0_sync_handler(eframe: &mut ExceptionFrame) {
+    fatal!(
+        eframe,
+        "Unexpected synchronous exception from the current EL while using SP_EL0: {}",
+        esr::decode(eframe.esr_el1).class.description()
+    );
 }
 
 #[no_mangle]
-extern "C" fn el1_sp0_irq_handler(_eframe: &mut ExceptionFrame) {
-    panic!("Unexpected IRQ from the current EL while using SP_EL0");
+extern "C" fn el1_sp0_irq_handler(eframe: &mut ExceptionFrame) {
+    fatal!(
+        eframe,
+        "Unexpected IRQ from the current EL while using SP_EL0"
+    );
 }
 
 #[no_mangle]
-extern "C" fn el1_sp0_fiq_handler(_eframe: &mut ExceptionFrame) {
-    panic!("Unexpected FIQ from the current EL while using SP_EL0");
+extern "C" fn el1_sp0_fiq_handler(eframe: &mut ExceptionFrame) {
+    fatal!(
+        eframe,
+        "Unexpected FIQ from the current EL while using SP_EL0"
+    );
 }
 
 #[no_mangle]
-extern "C" fn el1_sp0_serror_handler(_eframe: &mut ExceptionFrame) {
-    panic!("Unexpected SError exception from the current EL while using SP_EL0");
+extern "C" fn el1_sp0_serror_handler(eframe: &mut ExceptionFrame) {
+    fatal!(
+        eframe,
+        "Unexpected SError exception from the current EL while using SP_EL0"
+    );
 }
 
 #[no_mangle]
 extern "C" fn el1_sp1_sync_handler(eframe: &mut ExceptionFrame) {
-    let esr = LocalRegisterCopy::<u64, ESR_EL1::Register>::new(eframe.esr_el1);
+    let syndrome = esr::decode(eframe.esr_el1);
 
-    match esr.read_as_enum::<ESR_EL1::EC::Value>(ESR_EL1::EC) {
-        Some(ESR_EL1::EC::Value::DataAbortCurrentEL) => {
-            panic!("Data abort at address {:#x}", FAR_EL1.get())
-        }
-        Some(ESR_EL1::EC::Value::InstrAbortCurrentEL) => {
-            panic!("Instruction abort at address {:#x}", FAR_EL1.get())
-        }
-        _ => panic!("Unexpected synchronous exception from the current EL while using SP_EL1, ESR_EL1.EC={:#b}", esr.read(ESR_EL1::EC)),
+    if debug::handle_exception(eframe) {
+        return;
+    }
+
+    if syndrome.class == esr::ExceptionClass::TrappedFpAccess {
+        fpsimd::handle_access_trap();
+        return;
+    }
+
+    #[cfg(feature = "gdb")]
+    if matches!(
+        syndrome.class,
+        esr::ExceptionClass::Brk64 | esr::ExceptionClass::SoftwareStepCurrentEL
+    ) {
+        crate::gdb::handle_exception(eframe);
+        return;
+    }
+
+    match syndrome.iss {
+        Iss::DataAbort { wnr, dfsc, .. } => fatal!(
+            eframe,
+            "Data abort ({dfsc}) {} address {:#x}",
+            if wnr { "writing" } else { "reading" },
+            eframe.far_el1
+        ),
+        Iss::InstrAbort { ifsc, .. } => fatal!(
+            eframe,
+            "Instruction abort ({ifsc}) at address {:#x}",
+            eframe.far_el1
+        ),
+        _ => fatal!(
+            eframe,
+            "Unexpected synchronous exception from the current EL while using SP_EL1: {}",
+            syndrome.class.description()
+        ),
     };
 }
 
+// Runs on the emergency stack, see exceptions.s
+#[no_mangle]
+extern "C" fn el1_sp1_stack_overflow_handler(eframe: &mut ExceptionFrame) -> ! {
+    let far = eframe.far_el1;
+    match kstack::guard_owner(far) {
+        Some(owner) => fatal!(
+            eframe,
+            "kernel stack overflow in thread {owner} (address {far:#x})"
+        ),
+        None => fatal!(
+            eframe,
+            "kernel stack overflow in unknown thread (address {far:#x})"
+        ),
+    }
+}
+
 #[no_mangle]
 extern "C" fn el1_sp1_irq_handler(_eframe: &mut ExceptionFrame) {
     irq::process_irqs();
 }
 
 #[no_mangle]
-extern "C" fn el1_sp1_fiq_handler(_eframe: &mut ExceptionFrame) {
-    panic!("Unexpected FIQ from the current EL while using SP_EL1");
+extern "C" fn el1_sp1_fiq_handler(eframe: &mut ExceptionFrame) {
+    fatal!(
+        eframe,
+        "Unexpected FIQ from the current EL while using SP_EL1"
+    );
 }
 
 #[no_mangle]
-extern "C" fn el1_sp1_serror_handler(_eframe: &mut ExceptionFrame) {
-    panic!("Unexpected SError exception from the current EL while using SP_EL1");
+extern "C" fn el1_sp1_serror_handler(eframe: &mut ExceptionFrame) {
+    fatal!(
+        eframe,
+        "Unexpected SError exception from the current EL while using SP_EL1"
+    );
 }
 
 #[no_mangle]
-extern "C" fn el0_64_sync_handler(_eframe: &mut ExceptionFrame) {
-    panic!("Unexpected synchronous exception from EL0 AArch64");
+extern "C" fn el0_64_sync_handler(eframe: &mut ExceptionFrame) {
+    let syndrome = esr::decode(eframe.esr_el1);
+    if syndrome.class == esr::ExceptionClass::TrappedFpAccess {
+        fpsimd::handle_access_trap();
+        return;
+    }
+
+    fatal!(
+        eframe,
+        "Unexpected synchronous exception from EL0 AArch64: {}",
+        syndrome.class.description()
+    );
 }
 
 #[no_mangle]
-extern "C" fn el0_64_irq_handler(_eframe: &mut ExceptionFrame) {
-    panic!("Unexpected IRQ from EL0 AArch64");
+extern "C" fn el0_64_irq_handler(eframe: &mut ExceptionFrame) {
+    fatal!(eframe, "Unexpected IRQ from EL0 AArch64");
 }
 
 #[no_mangle]
-extern "C" fn el0_64_fiq_handler(_eframe: &mut ExceptionFrame) {
-    panic!("Unexpected FIQ from EL0 AArch64");
+extern "C" fn el0_64_fiq_handler(eframe: &mut ExceptionFrame) {
+    fatal!(eframe, "Unexpected FIQ from EL0 AArch64");
 }
 
 #[no_mangle]
-extern "C" fn el0_64_serror_handler(_eframe: &mut ExceptionFrame) {
-    panic!("Unexpected SError exception from EL0 AArch64");
+extern "C" fn el0_64_serror_handler(eframe: &mut ExceptionFrame) {
+    fatal!(eframe, "Unexpected SError exception from EL0 AArch64");
 }
 
 #[no_mangle]
-extern "C" fn el0_32_sync_handler(_eframe: &mut ExceptionFrame) {
-    panic!("Unexpected synchronous exception from EL0 AArch32");
+extern "C" fn el0_32_sync_handler(eframe: &mut ExceptionFrame) {
+    fatal!(
+        eframe,
+        "Unexpected synchronous exception from EL0 AArch32: {}",
+        esr::decode(eframe.esr_el1).class.description()
+    );
 }
 
 #[no_mangle]
-extern "C" fn el0_32_irq_handler(_eframe: &mut ExceptionFrame) {
-    panic!("Unexpected IRQ from EL0 AArch32");
+extern "C" fn el0_32_irq_handler(eframe: &mut ExceptionFrame) {
+    fatal!(eframe, "Unexpected IRQ from EL0 AArch32");
 }
 
 #[no_mangle]
-extern "C" fn el0_32_fiq_handler(_eframe: &mut ExceptionFrame) {
-    panic!("Unexpected FIQ from EL0 AArch32");
+extern "C" fn el0_32_fiq_handler(eframe: &mut ExceptionFrame) {
+    fatal!(eframe, "Unexpected FIQ from EL0 AArch32");
 }
 
 #[no_mangle]
-extern "C" fn el0_32_serror_handler(_eframe: &mut ExceptionFrame) {
-    panic!("Unexpected SError exception from EL0 AArch32");
+extern "C" fn el0_32_serror_handler(eframe: &mut ExceptionFrame) {
+    fatal!(eframe, "Unexpected SError exception from EL0 AArch32");
 }
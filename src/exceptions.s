@@ -1,6 +1,8 @@
 // NOTE: The ExceptionFrame layout is defined in exceptions.rs
-.macro save_context
-	sub sp, sp, #8 * 34
+// The SP of the interrupted context is saved too. It's either in SP_EL0 or, for
+// exceptions taken from EL1h, just above the frame.
+.macro save_context, from_sp_el0
+	sub sp, sp, #8 * 36
 
 	stp x0,  x1,  [sp, #8 * 0]
 	stp x2,  x3,  [sp, #8 * 2]
@@ -24,11 +26,19 @@
 	mrs x0, elr_el1
 	mrs x1, esr_el1
 	stp x0, x1, [sp, #8 * 32]
+
+	mrs x0, far_el1
+.if \from_sp_el0
+	mrs x1, sp_el0
+.else
+	add x1, sp, #8 * 36
+.endif
+	stp x0, x1, [sp, #8 * 34]
 .endmacro
 
 // NOTE: This code must not exceed 0x80 bytes
-.macro exception_handler, handler
-	save_context
+.macro exception_handler, handler, from_sp_el0=1
+	save_context \from_sp_el0
 	// The SP now points to the ExceptionFrame.
 	// Pass it as an argument to the handler.
 	mov x0, sp
@@ -52,13 +62,13 @@ __exception_table:
 	exception_handler el1_sp0_serror_handler
 // Exception from the current EL while using SP_ELx
 .org 0x200
-	exception_handler el1_sp1_sync_handler
+	b el1_sp1_sync_entry
 .org 0x280
-	exception_handler el1_sp1_irq_handler
+	exception_handler el1_sp1_irq_handler, 0
 .org 0x300
-	exception_handler el1_sp1_fiq_handler
+	exception_handler el1_sp1_fiq_handler, 0
 .org 0x380
-	exception_handler el1_sp1_serror_handler
+	exception_handler el1_sp1_serror_handler, 0
 // Exception from a lower EL and at least one lower EL is AArch64
 .org 0x400
 	exception_handler el0_64_sync_handler
@@ -78,6 +88,56 @@ __exception_table:
 .org 0x780
 	exception_handler el0_32_serror_handler
 
+// A kernel stack overflow shows up as a data abort on the guard area below the
+// stack. Saving the context on the same stack would fault again and recurse
+// forever, so check for that before touching the stack and switch to the
+// per-CPU emergency stack instead. TPIDR_EL1 is used as a scratch register
+// since the kernel never uses it. SP_EL0 has to be preserved since it may hold
+// the SP of an interrupted lower EL.
+el1_sp1_sync_entry:
+	msr tpidr_el1, x0
+
+	// Is this a data abort from the current EL?
+	mrs x0, esr_el1
+	ubfx x0, x0, #26, #6
+	cmp x0, #0x25
+	b.ne 1f
+
+	// Is the faulting address in the lower (guard) half of a stack slot?
+	mrs x0, far_el1
+	tbnz x0, #{KSTACK_SLOT_GUARD_BIT}, 1f
+
+	// Is the faulting address in the kernel stack region?
+	asr x0, x0, #{KSTACK_REGION_SHIFT}
+	cmn x0, #{KSTACK_REGION_INDEX_NEG}
+	b.eq el1_sp1_stack_overflow_entry
+
+1:
+	mrs x0, tpidr_el1
+	exception_handler el1_sp1_sync_handler, 0
+
+el1_sp1_stack_overflow_entry:
+	// Keep the SP of the overflowing context for the exception frame. This
+	// path never returns so SP_EL0 doesn't need to be preserved.
+	mov x0, sp
+	msr sp_el0, x0
+
+	// SP = EMERGENCY_STACKS + (cpu_id + 1) * EMERGENCY_STACK_SIZE
+	mrs x0, mpidr_el1
+	and x0, x0, #{CPU_ID_MASK}
+	add x0, x0, #1
+	lsl x0, x0, #{EMERGENCY_STACK_SHIFT}
+	mov sp, x0
+	adrp x0, {EMERGENCY_STACKS}
+	add x0, x0, :lo12:{EMERGENCY_STACKS}
+	add sp, sp, x0
+
+	mrs x0, tpidr_el1
+	save_context 1
+	mov x0, sp
+	// This never returns
+	bl el1_sp1_stack_overflow_handler
+
 // This should reverse save_context.
 // It's not defined as a macro because then the exception handler would exceed 0x80 bytes.
 restore_context_and_eret:
@@ -104,6 +164,6 @@ restore_context_and_eret:
 	ldp x26, x27, [sp, #8 * 26]
 	ldp x28, x29, [sp, #8 * 28]
 
-	add sp, sp, #8 * 34
+	add sp, sp, #8 * 36
 
 	eret
@@ -0,0 +1,257 @@
+// A text console on the framebuffer, so that the board can be used without a
+// serial cable. It mirrors the kernel log: every record the log prints on the
+// serial console, including the ones replayed when the console is attached,
+// is also drawn here. The panic message is drawn too.
+//
+// The text is kept in a character grid as well. Scrolling redraws the cells
+// that changed from the grid instead of moving pixels around, since reading
+// back the non-cacheable framebuffer is slow.
+//
+// Drawing takes too long to do with interrupts disabled, and records can be
+// logged from interrupt context. So the log only queues the text, and the
+// kernel main loop draws it as a bottom half.
+
+use crate::drivers::framebuffer::{self, FramebufferError};
+use crate::font::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::graphics::{Color, Surface};
+use crate::locking::{IRQSpinLock, SpinLock};
+use crate::logging;
+use crate::{ACTIONS, PENDING_ACTIONS};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use heapless::Deque;
+
+const WIDTH: u32 = 1024;
+const HEIGHT: u32 = 768;
+
+// Every glyph row is drawn twice, which gives 8x16 cells that are readable on
+// a monitor
+const ROW_SCALE: u32 = 2;
+const CELL_WIDTH: u32 = GLYPH_WIDTH;
+const CELL_HEIGHT: u32 = GLYPH_HEIGHT * ROW_SCALE;
+
+// Enough for 1920x1200, in case the firmware picks the display's resolution
+const MAX_COLS: usize = 240;
+const MAX_ROWS: usize = 75;
+
+// The console only draws on the first buffer, the other two are there for
+// graphics code that wants to double buffer, see framebuffer::flip()
+const BUFFERS: u32 = 3;
+
+const FOREGROUND: Color = Color::rgb(0xAA, 0xAA, 0xAA);
+const BACKGROUND: Color = Color::BLACK;
+
+const TAB_WIDTH: usize = 8;
+
+// Text logged but not drawn yet. If the main loop falls this far behind the
+// oldest text is dropped.
+const PENDING_LEN: usize = 4096;
+
+struct FbConsole {
+    // The first buffer of the framebuffer, which is shown unless graphics code
+    // flipped to its own buffers
+    surface: Option<Surface>,
+    cols: usize,
+    rows: usize,
+    // Where the next character goes
+    col: usize,
+    row: usize,
+    text: [[u8; MAX_COLS]; MAX_ROWS],
+}
+
+static CONSOLE: SpinLock<FbConsole> = SpinLock::new(FbConsole {
+    surface: None,
+    cols: 0,
+    rows: 0,
+    col: 0,
+    row: 0,
+    text: [[b' '; MAX_COLS]; MAX_ROWS],
+});
+
+/// Text queued for the console by mirror()
+pub struct PendingText {
+    bytes: Deque<u8, PENDING_LEN>,
+}
+
+impl core::fmt::Write for PendingText {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            // Characters without a glyph are drawn as '?', queue them as such
+            let byte = match c {
+                ' '..='~' | '\n' | '\r' | '\t' => c as u8,
+                _ => b'?',
+            };
+            if self.bytes.is_full() {
+                self.bytes.pop_front();
+            }
+            self.bytes.push_back(byte).unwrap();
+        }
+        Ok(())
+    }
+}
+
+static PENDING: IRQSpinLock<PendingText> = IRQSpinLock::new(PendingText {
+    bytes: Deque::new(),
+});
+
+// Set once init() succeeded, until then mirror() doesn't queue anything
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+impl FbConsole {
+    fn draw_cell(&self, surface: &Surface, col: usize, row: usize) {
+        let glyph = font::glyph(self.text[row][col] as char);
+        let x = col as u32 * CELL_WIDTH;
+        let y = row as u32 * CELL_HEIGHT;
+        for (i, bits) in glyph.iter().enumerate() {
+            for dy in 0..ROW_SCALE {
+                for dx in 0..GLYPH_WIDTH {
+                    let color = if bits & (1 << dx) != 0 {
+                        FOREGROUND
+                    } else {
+                        BACKGROUND
+                    };
+                    surface.put_pixel(
+                        (x + dx) as i32,
+                        (y + i as u32 * ROW_SCALE + dy) as i32,
+                        color,
+                    );
+                }
+            }
+        }
+    }
+
+    fn scroll(&mut self, surface: &Surface) {
+        for row in 0..self.rows {
+            for col in 0..self.cols {
+                let c = if row + 1 < self.rows {
+                    self.text[row + 1][col]
+                } else {
+                    b' '
+                };
+                if self.text[row][col] != c {
+                    self.text[row][col] = c;
+                    self.draw_cell(surface, col, row);
+                }
+            }
+        }
+    }
+
+    fn new_line(&mut self, surface: &Surface) {
+        self.col = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll(surface);
+        }
+    }
+
+    fn put_char(&mut self, c: char) {
+        let Some(surface) = self.surface else {
+            return;
+        };
+
+        match c {
+            '\n' => self.new_line(&surface),
+            '\r' => self.col = 0,
+            '\t' => {
+                let next = (self.col / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.col < next.min(self.cols) {
+                    self.put_char(' ');
+                }
+            }
+            _ => {
+                if self.col == self.cols {
+                    self.new_line(&surface);
+                }
+                // Characters without a glyph are stored as '?' too
+                self.text[self.row][self.col] = match c {
+                    ' '..='~' => c as u8,
+                    _ => b'?',
+                };
+                self.draw_cell(&surface, self.col, self.row);
+                self.col += 1;
+            }
+        }
+    }
+}
+
+impl core::fmt::Write for FbConsole {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            self.put_char(c);
+        }
+        Ok(())
+    }
+}
+
+/// Allocates the framebuffer and starts mirroring the kernel log on it, after
+/// replaying the records logged so far
+pub fn init() -> Result<(), FramebufferError> {
+    let surface = framebuffer::init(WIDTH, HEIGHT, BUFFERS)?.buffer(0);
+    surface.clear(BACKGROUND);
+
+    let mut console = CONSOLE.lock();
+    console.cols = ((surface.width() / CELL_WIDTH) as usize).min(MAX_COLS);
+    console.rows = ((surface.height() / CELL_HEIGHT) as usize).min(MAX_ROWS);
+    console.surface = Some(surface);
+    // Enabled before the replay so that no record falls in between. One that
+    // is logged just before the replay starts may be drawn twice.
+    ENABLED.store(true, Ordering::Relaxed);
+    logging::replay(&mut *console);
+    Ok(())
+}
+
+/// Called by the log for every record it prints, possibly in interrupt
+/// context. Queues the text for process_pending(). Does nothing until init()
+/// succeeded.
+pub fn mirror(f: impl FnOnce(&mut PendingText)) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    f(&mut PENDING.lock());
+    *PENDING_ACTIONS.lock() |= 1 << (ACTIONS::FbConsoleAction as u64);
+}
+
+// Moves up to `N` queued characters to `chunk` and returns how many
+fn take_pending<const N: usize>(chunk: &mut [u8; N]) -> usize {
+    let mut pending = PENDING.lock();
+    let mut len = 0;
+    while len < N {
+        let Some(byte) = pending.bytes.pop_front() else {
+            break;
+        };
+        chunk[len] = byte;
+        len += 1;
+    }
+    len
+}
+
+/// Draws the text queued by mirror(). Called from the kernel main loop with
+/// interrupts enabled, which are only disabled briefly to take a chunk of the
+/// queue.
+pub fn process_pending() {
+    let mut console = CONSOLE.lock();
+    let mut chunk = [0; 64];
+    loop {
+        let len = take_pending(&mut chunk);
+        if len == 0 {
+            break;
+        }
+        for &byte in &chunk[..len] {
+            console.put_char(byte as char);
+        }
+    }
+}
+
+/// Prints on the framebuffer from the panic handler, after the text that was
+/// still queued, unless the panicking code was holding the console
+pub fn print_on_panic(args: core::fmt::Arguments) {
+    if let Some(mut console) = CONSOLE.try_lock() {
+        if let Some(mut pending) = PENDING.try_lock() {
+            while let Some(byte) = pending.bytes.pop_front() {
+                console.put_char(byte as char);
+            }
+        }
+        let _ = console.write_fmt(args);
+    }
+}
@@ -0,0 +1,135 @@
+// FP/SIMD register state is switched lazily. The kernel itself is built for a
+// soft-float target so only assembly code touches the FP/SIMD registers, and
+// later lower ELs will. On every context switch, accesses from EL0 and EL1
+// are trapped unless the thread being switched to is the one whose state is
+// already loaded on this CPU. The first access after a switch then takes the
+// trap, which saves the registers of the previous owner and loads the ones of
+// the current thread.
+
+use crate::allocator::AllocError;
+use crate::cpu::{self, NUM_CPUS};
+use crate::thread;
+use aarch64_cpu::asm::barrier;
+use aarch64_cpu::registers::CPACR_EL1;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use tock_registers::interfaces::Writeable;
+
+global_asm!(include_str!("fpsimd.s"));
+
+extern "C" {
+    fn fpsimd_save(state: *mut FpSimdState);
+    fn fpsimd_load(state: *const FpSimdState);
+    fn fpsimd_set_d0(value: u64);
+    fn fpsimd_get_d0() -> u64;
+}
+
+// NOTE: The layout of this struct is hardcoded in fpsimd.s
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+pub struct FpSimdState {
+    q: [u128; 32],
+    fpcr: u64,
+    fpsr: u64,
+}
+
+const _: () = assert!(core::mem::size_of::<FpSimdState>() == 33 * 16);
+
+impl FpSimdState {
+    pub const fn new() -> Self {
+        FpSimdState {
+            q: [0; 32],
+            fpcr: 0,
+            fpsr: 0,
+        }
+    }
+}
+
+const NO_OWNER: usize = usize::MAX;
+
+// The thread whose state is currently loaded in the registers of each CPU
+static OWNERS: [AtomicUsize; NUM_CPUS] = [const { AtomicUsize::new(NO_OWNER) }; NUM_CPUS];
+
+fn set_trap(trap: bool) {
+    if trap {
+        CPACR_EL1.write(CPACR_EL1::FPEN::TrapEl0El1);
+    } else {
+        CPACR_EL1.write(CPACR_EL1::FPEN::TrapNothing);
+    }
+    barrier::isb(barrier::SY);
+}
+
+pub fn init() {
+    set_trap(true);
+}
+
+/// Called when switching to thread `next` on the current CPU
+pub fn on_switch(next: usize) {
+    set_trap(OWNERS[cpu::current_id()].load(Ordering::Relaxed) != next);
+}
+
+/// Called when a thread exits so that its state is never saved again. The
+/// thread's slot could be reused by a new thread in the meantime.
+pub fn on_exit(thread: usize) {
+    for owner in &OWNERS {
+        let _ = owner.compare_exchange(thread, NO_OWNER, Ordering::Relaxed, Ordering::Relaxed);
+    }
+}
+
+/// Handles an FP/SIMD access trapped from EL0 or EL1
+pub fn handle_access_trap() {
+    // Saving and loading the registers would trap otherwise
+    set_trap(false);
+
+    let owner = &OWNERS[cpu::current_id()];
+    let current = thread::current();
+    let previous = owner.load(Ordering::Relaxed);
+
+    if previous != current {
+        if previous != NO_OWNER {
+            // SAFETY: The registers still hold the state of the previous
+            // owner since nothing else uses them
+            thread::with_fpsimd_state(previous, |state| unsafe { fpsimd_save(state) });
+        }
+        // SAFETY: The state is either zeroed or was saved by fpsimd_save()
+        thread::with_fpsimd_state(current, |state| unsafe { fpsimd_load(state) });
+        owner.store(current, Ordering::Relaxed);
+    }
+}
+
+const TEST_PENDING: u8 = 0;
+const TEST_PASSED: u8 = 1;
+const TEST_FAILED: u8 = 2;
+
+static TEST_RESULT: AtomicU8 = AtomicU8::new(TEST_PENDING);
+
+const TEST_VALUE_CALLER: u64 = 0x1111_2222_3333_4444;
+const TEST_VALUE_THREAD: u64 = 0x5555_6666_7777_8888;
+
+fn test_thread() {
+    // SAFETY: Only d0 is changed, which no Rust code uses
+    unsafe { fpsimd_set_d0(TEST_VALUE_THREAD) };
+    thread::yield_now();
+    // SAFETY: Reading d0 has no side effects
+    let passed = unsafe { fpsimd_get_d0() } == TEST_VALUE_THREAD;
+    TEST_RESULT.store(
+        if passed { TEST_PASSED } else { TEST_FAILED },
+        Ordering::Relaxed,
+    );
+}
+
+/// Checks that threads don't see each other's FP/SIMD registers: the calling
+/// thread and a new one put different values in d0 and check them after
+/// switching back and forth, which goes through the access trap every time.
+pub fn self_test() -> Result<bool, AllocError> {
+    TEST_RESULT.store(TEST_PENDING, Ordering::Relaxed);
+    // SAFETY: Only d0 is changed, which no Rust code uses
+    unsafe { fpsimd_set_d0(TEST_VALUE_CALLER) };
+    thread::spawn("fptest", test_thread)?;
+    while TEST_RESULT.load(Ordering::Relaxed) == TEST_PENDING {
+        thread::yield_now();
+    }
+    // SAFETY: Reading d0 has no side effects
+    let passed = unsafe { fpsimd_get_d0() } == TEST_VALUE_CALLER;
+    Ok(passed && TEST_RESULT.load(Ordering::Relaxed) == TEST_PASSED)
+}
@@ -0,0 +1,403 @@
+// A stub for the GDB remote serial protocol, see
+// https://sourceware.org/gdb/current/onlinedocs/gdb.html/Remote-Protocol.html
+//
+// The stub takes over the mini UART whenever the kernel stops on a BRK
+// instruction or after a single step and polls it, with interrupts masked,
+// until GDB lets the kernel continue. Software breakpoints are BRK
+// instructions patched into the kernel text and single stepping is done by
+// the debug module. While the kernel runs, the UART driver breaks into the
+// stub when GDB sends a Ctrl-C.
+//
+// NOTE: The stub runs in exception context on the stack of the stopped code.
+// Inserting breakpoints modifies the page tables, so stopping while the page
+// table lock is held and then inserting a breakpoint deadlocks.
+
+use crate::address::AddressVirtual;
+use crate::debug;
+use crate::drivers::uart_mini;
+use crate::esr::{self, ExceptionClass};
+use crate::exceptions::ExceptionFrame;
+use crate::locking::SpinLock;
+use crate::paging;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use heapless::String;
+
+// brk #0
+const BRK_INSN: u32 = 0xd420_0000;
+const MAX_PACKET_SIZE: usize = 1024;
+const MAX_BREAKPOINTS: usize = 16;
+const SIGTRAP: u8 = 5;
+
+// Error numbers sent in E replies
+const EFAULT: u8 = 14;
+const ENOSPC: u8 = 28;
+const EINVAL: u8 = 22;
+
+// GDB's aarch64 register numbering: x0-x30, sp, pc, cpsr
+const NUM_GPRS: usize = 31;
+const REGS_HEX_LEN: usize = (NUM_GPRS + 2) * 16 + 8;
+
+type Reply = String<MAX_PACKET_SIZE>;
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    addr: u64,
+    original: u32,
+}
+
+struct Stub {
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+}
+
+static STUB: SpinLock<Stub> = SpinLock::new(Stub {
+    breakpoints: [None; MAX_BREAKPOINTS],
+});
+
+// True after GDB has connected and until it detaches
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_attached() -> bool {
+    ATTACHED.load(Ordering::Relaxed)
+}
+
+/// Stops in the stub. With nobody attached yet this waits for GDB to connect.
+#[inline(always)]
+pub fn breakpoint() {
+    // SAFETY: BRK traps to el1_sp1_sync_handler which returns right after it
+    unsafe { core::arch::asm!("brk #0") };
+}
+
+/// Entry point from the synchronous exception handler for BRK exceptions and
+/// software steps requested by the stub
+pub fn handle_exception(eframe: &mut ExceptionFrame) {
+    let mut stub = STUB.lock();
+
+    // BRK reports the address of the BRK itself. Skip the ones that are
+    // compiled in rather than inserted by GDB or we would keep hitting them.
+    let class = esr::decode(eframe.esr_el1).class;
+    if class == ExceptionClass::Brk64 && stub.find_breakpoint(eframe.elr_el1).is_none() {
+        eframe.elr_el1 += 4;
+    }
+
+    // GDB is waiting for a stop reply after a continue or a step. Otherwise
+    // it will ask for the stop reason itself once it connects.
+    if is_attached() {
+        send_stop_reply();
+    }
+
+    stub.serve(eframe);
+}
+
+impl Stub {
+    fn serve(&mut self, eframe: &mut ExceptionFrame) {
+        let mut buffer = [0u8; MAX_PACKET_SIZE];
+        loop {
+            let packet = receive_packet(&mut buffer);
+            let Some((&command, args)) = packet.split_first() else {
+                send_packet("");
+                continue;
+            };
+
+            ATTACHED.store(true, Ordering::Relaxed);
+
+            let mut reply = Reply::new();
+            match command {
+                b'?' => write_stop_reply(&mut reply),
+                b'g' => read_registers(eframe, &mut reply),
+                b'G' => result_reply(&mut reply, write_registers(eframe, args)),
+                b'm' => {
+                    if let Err(e) = read_memory(args, &mut reply) {
+                        reply.clear();
+                        error_reply(&mut reply, e);
+                    }
+                }
+                b'M' => result_reply(&mut reply, write_memory(args)),
+                b'Z' | b'z' => match parse_breakpoint(args) {
+                    // Only software breakpoints are supported
+                    Some((0, addr)) if command == b'Z' => {
+                        result_reply(&mut reply, self.insert_breakpoint(addr))
+                    }
+                    Some((0, addr)) => result_reply(&mut reply, self.remove_breakpoint(addr)),
+                    Some(_) => {}
+                    None => error_reply(&mut reply, EINVAL),
+                },
+                b'c' | b's' => {
+                    if let Some(addr) = parse_hex(args) {
+                        eframe.elr_el1 = addr;
+                    }
+                    if command == b's' {
+                        debug::start_step_for_gdb(eframe);
+                    }
+                    // The stop reply is sent when the kernel stops again
+                    return;
+                }
+                b'D' | b'k' => {
+                    self.remove_all_breakpoints();
+                    ATTACHED.store(false, Ordering::Relaxed);
+                    if command == b'D' {
+                        send_packet("OK");
+                    }
+                    return;
+                }
+                b'H' => reply.push_str("OK").unwrap(),
+                b'q' => {
+                    if args.starts_with(b"Supported") {
+                        write!(reply, "PacketSize={MAX_PACKET_SIZE:x}").unwrap();
+                    } else if args.starts_with(b"Attached") {
+                        reply.push_str("1").unwrap();
+                    }
+                }
+                // Empty replies tell GDB the command isn't supported
+                _ => {}
+            }
+
+            send_packet(&reply);
+        }
+    }
+
+    fn find_breakpoint(&self, addr: u64) -> Option<usize> {
+        self.breakpoints
+            .iter()
+            .position(|bp| matches!(bp, Some(bp) if bp.addr == addr))
+    }
+
+    fn insert_breakpoint(&mut self, addr: u64) -> Result<(), u8> {
+        if self.find_breakpoint(addr).is_some() {
+            return Ok(());
+        }
+        if !addr.is_multiple_of(4) || !paging::is_readable(addr) {
+            return Err(EFAULT);
+        }
+        let slot = self
+            .breakpoints
+            .iter()
+            .position(|bp| bp.is_none())
+            .ok_or(ENOSPC)?;
+
+        // SAFETY: We checked that the address is readable and aligned
+        let original = unsafe { core::ptr::read_volatile(addr as *const u32) };
+        paging::patch_text(AddressVirtual::new(addr), BRK_INSN);
+        self.breakpoints[slot] = Some(Breakpoint { addr, original });
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, addr: u64) -> Result<(), u8> {
+        let slot = self.find_breakpoint(addr).ok_or(EINVAL)?;
+        let bp = self.breakpoints[slot].take().unwrap();
+        paging::patch_text(AddressVirtual::new(bp.addr), bp.original);
+        Ok(())
+    }
+
+    fn remove_all_breakpoints(&mut self) {
+        for bp in self.breakpoints.iter_mut().filter_map(|bp| bp.take()) {
+            paging::patch_text(AddressVirtual::new(bp.addr), bp.original);
+        }
+    }
+}
+
+fn read_registers(eframe: &ExceptionFrame, reply: &mut Reply) {
+    for reg in eframe
+        .regs
+        .iter()
+        .chain([eframe.sp(), eframe.elr_el1].iter())
+    {
+        write_hex(reply, &reg.to_le_bytes());
+    }
+    write_hex(reply, &(eframe.spsr_el1 as u32).to_le_bytes());
+}
+
+// The SP can't be changed since the exception frame lives right below it
+fn write_registers(eframe: &mut ExceptionFrame, args: &[u8]) -> Result<(), u8> {
+    if args.len() < REGS_HEX_LEN {
+        return Err(EINVAL);
+    }
+
+    let reg = |i: usize| -> Result<u64, u8> {
+        let mut bytes = [0u8; 8];
+        decode_hex(&args[i * 16..(i + 1) * 16], &mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    };
+
+    for i in 0..NUM_GPRS {
+        eframe.regs[i] = reg(i)?;
+    }
+    eframe.elr_el1 = reg(NUM_GPRS + 1)?;
+
+    let mut cpsr = [0u8; 4];
+    decode_hex(&args[(NUM_GPRS + 2) * 16..REGS_HEX_LEN], &mut cpsr)?;
+    eframe.spsr_el1 = (eframe.spsr_el1 & !0xffff_ffff) | u32::from_le_bytes(cpsr) as u64;
+    Ok(())
+}
+
+// Parses "addr,length" followed by the rest of the arguments
+fn parse_memory_args(args: &[u8]) -> Result<(u64, usize, &[u8]), u8> {
+    let comma = args.iter().position(|&c| c == b',').ok_or(EINVAL)?;
+    let addr = parse_hex(&args[..comma]).ok_or(EINVAL)?;
+    let rest = &args[comma + 1..];
+    let end = rest.iter().position(|&c| c == b':').unwrap_or(rest.len());
+    let len = parse_hex(&rest[..end]).ok_or(EINVAL)? as usize;
+    Ok((addr, len, rest.get(end + 1..).unwrap_or(&[])))
+}
+
+fn read_memory(args: &[u8], reply: &mut Reply) -> Result<(), u8> {
+    let (addr, len, _) = parse_memory_args(args)?;
+    // Each byte takes two hex digits
+    let len = len.min(MAX_PACKET_SIZE / 2);
+    let end = addr.checked_add(len as u64).ok_or(EINVAL)?;
+
+    for a in addr..end {
+        if !paging::is_readable(a) {
+            return Err(EFAULT);
+        }
+        // SAFETY: We just checked that reading the address doesn't fault
+        let byte = unsafe { core::ptr::read_volatile(a as *const u8) };
+        write_hex(reply, &[byte]);
+    }
+    Ok(())
+}
+
+fn write_memory(args: &[u8]) -> Result<(), u8> {
+    let (addr, len, data) = parse_memory_args(args)?;
+    let end = addr.checked_add(len as u64).ok_or(EINVAL)?;
+    if Some(data.len()) != len.checked_mul(2) {
+        return Err(EINVAL);
+    }
+
+    for (i, a) in (addr..end).enumerate() {
+        if !paging::is_writable(a) {
+            return Err(EFAULT);
+        }
+        let mut byte = [0u8; 1];
+        decode_hex(&data[i * 2..i * 2 + 2], &mut byte)?;
+        // SAFETY: We just checked that writing the address doesn't fault.
+        // Whatever GDB writes is up to the user.
+        unsafe { core::ptr::write_volatile(a as *mut u8, byte[0]) };
+    }
+    Ok(())
+}
+
+// Parses "type,addr,kind"
+fn parse_breakpoint(args: &[u8]) -> Option<(u64, u64)> {
+    let mut fields = args.split(|&c| c == b',');
+    let kind = parse_hex(fields.next()?)?;
+    let addr = parse_hex(fields.next()?)?;
+    Some((kind, addr))
+}
+
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    let s = core::str::from_utf8(s).ok()?;
+    u64::from_str_radix(s, 16).ok()
+}
+
+fn decode_hex(hex: &[u8], bytes: &mut [u8]) -> Result<(), u8> {
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+        *byte = parse_hex(pair).ok_or(EINVAL)? as u8;
+    }
+    Ok(())
+}
+
+fn write_hex(reply: &mut Reply, bytes: &[u8]) {
+    for byte in bytes {
+        write!(reply, "{byte:02x}").unwrap();
+    }
+}
+
+fn write_stop_reply(reply: &mut Reply) {
+    write!(reply, "S{SIGTRAP:02x}").unwrap();
+}
+
+fn result_reply(reply: &mut Reply, result: Result<(), u8>) {
+    match result {
+        Ok(()) => reply.push_str("OK").unwrap(),
+        Err(e) => error_reply(reply, e),
+    }
+}
+
+fn error_reply(reply: &mut Reply, errno: u8) {
+    write!(reply, "E{errno:02x}").unwrap();
+}
+
+fn send_stop_reply() {
+    let mut reply = Reply::new();
+    write_stop_reply(&mut reply);
+    send_packet(&reply);
+}
+
+fn get_byte() -> u8 {
+    loop {
+        if let Some(byte) = uart_mini::poll_byte() {
+            return byte;
+        }
+    }
+}
+
+fn put_byte(byte: u8) {
+    uart_mini::put_char_sync(byte as char);
+}
+
+// Receives a "$data#checksum" packet into the buffer, acknowledging it, and
+// returns the data
+fn receive_packet(buffer: &mut [u8]) -> &[u8] {
+    loop {
+        while get_byte() != b'$' {}
+
+        let mut len = 0;
+        let mut checksum = 0u8;
+        let mut overflow = false;
+        loop {
+            match get_byte() {
+                b'#' => break,
+                // GDB starts over if it doesn't get an ACK, the '$' begins
+                // the resent packet
+                b'$' => {
+                    len = 0;
+                    checksum = 0;
+                    overflow = false;
+                }
+                byte => {
+                    checksum = checksum.wrapping_add(byte);
+                    if len == buffer.len() {
+                        overflow = true;
+                        continue;
+                    }
+                    buffer[len] = byte;
+                    len += 1;
+                }
+            }
+        }
+
+        let received = parse_hex(&[get_byte(), get_byte()]);
+        if !overflow && received == Some(checksum as u64) {
+            put_byte(b'+');
+            return &buffer[..len];
+        }
+        put_byte(b'-');
+    }
+}
+
+// Sends a packet until GDB acknowledges it
+fn send_packet(data: &str) {
+    let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    loop {
+        put_byte(b'$');
+        data.bytes().for_each(put_byte);
+        put_byte(b'#');
+        let mut trailer = String::<2>::new();
+        write!(trailer, "{checksum:02x}").unwrap();
+        trailer.bytes().for_each(put_byte);
+
+        // Ignore anything but an ACK or a NACK, like a Ctrl-C sent while the
+        // kernel was stopping
+        loop {
+            match get_byte() {
+                b'+' => return,
+                b'-' => break,
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,365 @@
+// 2D drawing on pixel surfaces such as the framebuffer. Everything is clipped
+// to the surface, so coordinates can be negative or lie beyond its edges.
+//
+// Colors are given as 8-bit RGB and converted to the pixel format of the
+// surface when drawn. Surfaces are plain memory, double buffering and page
+// flipping are done by the framebuffer driver, see framebuffer::flip().
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    // 16 bits, 5 for red, 6 for green and 5 for blue from the top bit down
+    Rgb565,
+    // 24 bits, blue in the first byte and red in the last
+    Rgb888,
+    // 32 bits, 0xAARRGGBB as a little endian word
+    Argb8888,
+}
+
+impl PixelFormat {
+    /// Returns the format with that many bits per pixel
+    pub fn from_depth(depth: u32) -> Option<Self> {
+        match depth {
+            16 => Some(Self::Rgb565),
+            24 => Some(Self::Rgb888),
+            32 => Some(Self::Argb8888),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            Self::Rgb565 => 2,
+            Self::Rgb888 => 3,
+            Self::Argb8888 => 4,
+        }
+    }
+
+    fn encode(self, color: Color) -> u32 {
+        let (r, g, b, a) = (
+            color.r as u32,
+            color.g as u32,
+            color.b as u32,
+            color.a as u32,
+        );
+        match self {
+            Self::Rgb565 => ((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3),
+            Self::Rgb888 => (r << 16) | (g << 8) | b,
+            Self::Argb8888 => (a << 24) | (r << 16) | (g << 8) | b,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    // Only stored in surfaces with an alpha channel, see Surface::blit() for
+    // how images use it
+    pub a: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(0xFF, 0xFF, 0xFF);
+    pub const RED: Color = Color::rgb(0xFF, 0, 0);
+    pub const GREEN: Color = Color::rgb(0, 0xFF, 0);
+    pub const BLUE: Color = Color::rgb(0, 0, 0xFF);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Color { r, g, b, a: 0xFF }
+    }
+
+    /// From a 0xAARRGGBB value
+    pub const fn from_argb(argb: u32) -> Self {
+        Color {
+            r: (argb >> 16) as u8,
+            g: (argb >> 8) as u8,
+            b: argb as u8,
+            a: (argb >> 24) as u8,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub const fn new(x: i32, y: i32, width: u32, height: u32) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn right(&self) -> i64 {
+        self.x as i64 + self.width as i64
+    }
+
+    fn bottom(&self) -> i64 {
+        self.y as i64 + self.height as i64
+    }
+
+    /// Returns the part of the rectangle that is also in `other`, if any
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        if right <= x as i64 || bottom <= y as i64 {
+            return None;
+        }
+        Some(Rect::new(
+            x,
+            y,
+            (right - x as i64) as u32,
+            (bottom - y as i64) as u32,
+        ))
+    }
+}
+
+/// ARGB8888 pixels, row by row, to draw with Surface::blit()
+pub struct Image<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: &'a [u32],
+}
+
+/// A block of memory holding pixels, e.g. one of the framebuffer's buffers
+#[derive(Clone, Copy)]
+pub struct Surface {
+    base: u64,
+    width: u32,
+    height: u32,
+    // Bytes per line, which can be more than the width needs
+    pitch: u32,
+    format: PixelFormat,
+    // Whether red and blue are swapped in memory
+    bgr: bool,
+}
+
+// Cohen-Sutherland outcodes, which side(s) of the clip rectangle a point is on
+const LEFT: u8 = 1;
+const RIGHT: u8 = 2;
+const TOP: u8 = 4;
+const BOTTOM: u8 = 8;
+
+impl Surface {
+    /// # Safety
+    ///
+    /// `base` must point to `height * pitch` bytes of memory which stay
+    /// mapped and writable for as long as any copy of the surface is used
+    pub unsafe fn new(
+        base: u64,
+        width: u32,
+        height: u32,
+        pitch: u32,
+        format: PixelFormat,
+        bgr: bool,
+    ) -> Self {
+        assert!(width * format.bytes_per_pixel() <= pitch);
+        Surface {
+            base,
+            width,
+            height,
+            pitch,
+            format,
+            bgr,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    // The pixel must be inside the surface
+    fn write_pixel(&self, x: u32, y: u32, color: Color) {
+        let color = if self.bgr {
+            Color {
+                r: color.b,
+                b: color.r,
+                ..color
+            }
+        } else {
+            color
+        };
+        let value = self.format.encode(color);
+        let addr = self.base + y as u64 * self.pitch as u64;
+        let addr = addr + (x * self.format.bytes_per_pixel()) as u64;
+
+        // SAFETY: The pixel is inside the surface, whose memory is valid as
+        // promised to new(). Rgb888 pixels aren't aligned so they are written
+        // byte by byte.
+        unsafe {
+            match self.format {
+                PixelFormat::Rgb565 => core::ptr::write_volatile(addr as *mut u16, value as u16),
+                PixelFormat::Rgb888 => {
+                    for (i, byte) in value.to_le_bytes()[..3].iter().enumerate() {
+                        core::ptr::write_volatile((addr + i as u64) as *mut u8, *byte);
+                    }
+                }
+                PixelFormat::Argb8888 => core::ptr::write_volatile(addr as *mut u32, value),
+            }
+        }
+    }
+
+    pub fn put_pixel(&self, x: i32, y: i32, color: Color) {
+        if x >= 0 && y >= 0 && (x as u32) < self.width && (y as u32) < self.height {
+            self.write_pixel(x as u32, y as u32, color);
+        }
+    }
+
+    pub fn fill_rect(&self, rect: Rect, color: Color) {
+        let Some(rect) = rect.intersect(&self.bounds()) else {
+            return;
+        };
+        for y in rect.y..rect.y + rect.height as i32 {
+            for x in rect.x..rect.x + rect.width as i32 {
+                self.write_pixel(x as u32, y as u32, color);
+            }
+        }
+    }
+
+    pub fn clear(&self, color: Color) {
+        self.fill_rect(self.bounds(), color);
+    }
+
+    fn outcode(&self, x: i64, y: i64) -> u8 {
+        let mut code = 0;
+        if x < 0 {
+            code |= LEFT;
+        } else if x >= self.width as i64 {
+            code |= RIGHT;
+        }
+        if y < 0 {
+            code |= TOP;
+        } else if y >= self.height as i64 {
+            code |= BOTTOM;
+        }
+        code
+    }
+
+    // Moves the end points of a line onto the surface, or returns None if the
+    // line misses it
+    fn clip_line(
+        &self,
+        (mut x0, mut y0): (i64, i64),
+        (mut x1, mut y1): (i64, i64),
+    ) -> Option<[i64; 4]> {
+        let (max_x, max_y) = (self.width as i64 - 1, self.height as i64 - 1);
+        let mut code0 = self.outcode(x0, y0);
+        let mut code1 = self.outcode(x1, y1);
+
+        loop {
+            if code0 | code1 == 0 {
+                return Some([x0, y0, x1, y1]);
+            }
+            if code0 & code1 != 0 {
+                return None;
+            }
+
+            // Move the end point that is outside onto the edge it crosses
+            let code = if code0 != 0 { code0 } else { code1 };
+            let (x, y) = if code & TOP != 0 {
+                (x0 + (x1 - x0) * -y0 / (y1 - y0), 0)
+            } else if code & BOTTOM != 0 {
+                (x0 + (x1 - x0) * (max_y - y0) / (y1 - y0), max_y)
+            } else if code & LEFT != 0 {
+                (0, y0 + (y1 - y0) * -x0 / (x1 - x0))
+            } else {
+                (max_x, y0 + (y1 - y0) * (max_x - x0) / (x1 - x0))
+            };
+
+            if code == code0 {
+                (x0, y0) = (x, y);
+                code0 = self.outcode(x0, y0);
+            } else {
+                (x1, y1) = (x, y);
+                code1 = self.outcode(x1, y1);
+            }
+        }
+    }
+
+    /// Draws a line including both end points
+    pub fn draw_line(&self, x0: i32, y0: i32, x1: i32, y1: i32, color: Color) {
+        let Some([mut x, mut y, x1, y1]) =
+            self.clip_line((x0 as i64, y0 as i64), (x1 as i64, y1 as i64))
+        else {
+            return;
+        };
+
+        // Bresenham's algorithm, for all octants
+        let dx = (x1 - x).abs();
+        let dy = -(y1 - y).abs();
+        let step_x = if x < x1 { 1 } else { -1 };
+        let step_y = if y < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+        loop {
+            self.write_pixel(x as u32, y as u32, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let error2 = 2 * error;
+            if error2 >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if error2 <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    pub fn draw_rect(&self, rect: Rect, color: Color) {
+        if rect.width == 0 || rect.height == 0 {
+            return;
+        }
+        let right = (rect.right() - 1) as i32;
+        let bottom = (rect.bottom() - 1) as i32;
+        self.draw_line(rect.x, rect.y, right, rect.y, color);
+        self.draw_line(rect.x, bottom, right, bottom, color);
+        self.draw_line(rect.x, rect.y, rect.x, bottom, color);
+        self.draw_line(right, rect.y, right, bottom, color);
+    }
+
+    /// Draws an image with its top left corner at (x, y). Pixels with an
+    /// alpha of 0 are transparent and left alone, all others are opaque.
+    pub fn blit(&self, image: &Image, x: i32, y: i32) {
+        assert!(image.pixels.len() >= (image.width * image.height) as usize);
+        let target = Rect::new(x, y, image.width, image.height);
+        let Some(clipped) = target.intersect(&self.bounds()) else {
+            return;
+        };
+
+        for dst_y in clipped.y..clipped.y + clipped.height as i32 {
+            let src_y = (dst_y - y) as u32;
+            for dst_x in clipped.x..clipped.x + clipped.width as i32 {
+                let src_x = (dst_x - x) as u32;
+                let color = Color::from_argb(image.pixels[(src_y * image.width + src_x) as usize]);
+                if color.a != 0 {
+                    self.write_pixel(dst_x as u32, dst_y as u32, color);
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,7 @@
-use crate::drivers::uart_mini;
+use crate::drivers::{gpio, system_timer, uart_mini, uart_pl011};
 use crate::drivers::{interrupt_controller, interrupt_controller::PendingIrqs};
 use aarch64_cpu::registers::DAIF;
-use core::sync::atomic::{compiler_fence, Ordering};
+use core::sync::atomic::{compiler_fence, AtomicU64, Ordering};
 use tock_registers::interfaces::ReadWriteable;
 
 #[allow(dead_code)]
@@ -23,6 +23,7 @@ pub enum ArmIrq {
 }
 
 #[allow(dead_code)]
+#[derive(Debug)]
 pub enum GpuIrq {
     SystemTimer1 = 1,
     SystemTimer3 = 3,
@@ -47,18 +48,33 @@ impl TryFrom<u32> for GpuIrq {
 
     fn try_from(value: u32) -> Result<Self, Self::Error> {
         match value {
+            1 => Ok(GpuIrq::SystemTimer1),
+            3 => Ok(GpuIrq::SystemTimer3),
             29 => Ok(GpuIrq::Aux),
+            49 => Ok(GpuIrq::Gpio0),
+            50 => Ok(GpuIrq::Gpio1),
+            51 => Ok(GpuIrq::Gpio2),
+            52 => Ok(GpuIrq::Gpio3),
+            57 => Ok(GpuIrq::Uart),
             _ => Err(()),
         }
     }
 }
 
+const NUM_GPU_IRQS: usize = 64;
+
+// How many times each GPU IRQ was handled
+static GPU_IRQ_COUNTS: [AtomicU64; NUM_GPU_IRQS] = [const { AtomicU64::new(0) }; NUM_GPU_IRQS];
+
+pub fn gpu_irq_counts() -> [u64; NUM_GPU_IRQS] {
+    core::array::from_fn(|irq| GPU_IRQ_COUNTS[irq].load(Ordering::Relaxed))
+}
+
 #[inline]
 pub fn enable_irq(irq: Irq) {
     interrupt_controller::enable_irq(irq);
 }
 
-#[allow(dead_code)]
 #[inline]
 pub fn disable_irq(irq: Irq) {
     interrupt_controller::disable_irq(irq);
@@ -69,7 +85,7 @@ pub fn enable_interrupts() {
     // No H/W barriers are needed when writing PSTATE fields, but compiler
     // barriers are still required.
     compiler_fence(Ordering::SeqCst);
-    DAIF.modify(DAIF::D::Unmasked + DAIF::A::Unmasked + DAIF::I::Unmasked + DAIF::F::Unmasked);
+    DAIF.modify(DAIF::A::Unmasked + DAIF::I::Unmasked + DAIF::F::Unmasked);
     compiler_fence(Ordering::SeqCst);
 }
 
@@ -78,7 +94,9 @@ pub fn disable_interrupts() {
     // No H/W barriers are needed when writing PSTATE fields, but compiler
     // barriers are still required.
     compiler_fence(Ordering::SeqCst);
-    DAIF.modify(DAIF::D::Masked + DAIF::A::Masked + DAIF::I::Masked + DAIF::F::Masked);
+    // Debug exceptions are left alone so that watchpoints also catch
+    // accesses made with interrupts disabled
+    DAIF.modify(DAIF::A::Masked + DAIF::I::Masked + DAIF::F::Masked);
     compiler_fence(Ordering::SeqCst);
 }
 
@@ -88,9 +106,19 @@ pub fn process_irqs() {
     // TODO: Allow drivers to register handlers dynamically
     while gpu != 0 {
         let lowest_set_bit = gpu.trailing_zeros();
+        GPU_IRQ_COUNTS[lowest_set_bit as usize].fetch_add(1, Ordering::Relaxed);
         match GpuIrq::try_from(lowest_set_bit) {
+            Ok(irq @ (GpuIrq::SystemTimer1 | GpuIrq::SystemTimer3)) => {
+                system_timer::process_irq(irq);
+            }
             Ok(GpuIrq::Aux) => {
-                uart_mini::process_rx_irq();
+                uart_mini::process_irq();
+            }
+            Ok(irq @ (GpuIrq::Gpio0 | GpuIrq::Gpio1 | GpuIrq::Gpio2 | GpuIrq::Gpio3)) => {
+                gpio::process_irq(irq);
+            }
+            Ok(GpuIrq::Uart) => {
+                uart_pl011::process_irq();
             }
             _ => {
                 panic!("Unexpected GPU IRQ {lowest_set_bit}")
@@ -0,0 +1,150 @@
+use crate::address::{
+    AddressVirtual, KSTACK_REGION_SIZE, KSTACK_REGION_START, KSTACK_SIZE, KSTACK_SLOT_SIZE,
+};
+use crate::allocator::{allocate_page, free_page, AllocError};
+use crate::cpu::NUM_CPUS;
+use crate::locking::SpinLock;
+use crate::memory::PAGE_SIZE;
+use crate::paging;
+
+const NUM_SLOTS: usize = (KSTACK_REGION_SIZE / KSTACK_SLOT_SIZE) as usize;
+
+pub const EMERGENCY_STACK_SIZE: usize = 4 * PAGE_SIZE as usize;
+
+#[repr(C, align(16))]
+pub struct EmergencyStacks([[u8; EMERGENCY_STACK_SIZE]; NUM_CPUS]);
+
+// Small per-CPU stacks used by the exception vectors when a kernel stack
+// overflows, since the overflowing stack can't be used to handle the fault.
+// These are only written to by the exception vectors.
+pub static mut EMERGENCY_STACKS: EmergencyStacks =
+    EmergencyStacks([[0; EMERGENCY_STACK_SIZE]; NUM_CPUS]);
+
+// The name of the thread that owns each slot, None if the slot is free
+static SLOTS: SpinLock<[Option<&'static str>; NUM_SLOTS]> = SpinLock::new([None; NUM_SLOTS]);
+
+static CPU_STACKS: SpinLock<[Option<KernelStack>; NUM_CPUS]> =
+    SpinLock::new([const { None }; NUM_CPUS]);
+
+const CPU_STACK_NAMES: [&str; NUM_CPUS] = ["cpu0", "cpu1", "cpu2", "cpu3"];
+
+/// A kernel stack mapped in its own slot in the kernel stack region, right
+/// above an unmapped guard area. The stack is unmapped and its pages are
+/// freed when this is dropped.
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    pub fn new(owner: &'static str) -> Result<Self, AllocError> {
+        let slot = {
+            let mut slots = SLOTS.lock();
+            let slot = slots
+                .iter()
+                .position(|s| s.is_none())
+                .ok_or(AllocError::OutOfMemory)?;
+            slots[slot] = Some(owner);
+            slot
+        };
+
+        let stack = KernelStack { slot };
+        let mut va = stack.bottom();
+        while va < stack.top() {
+            // If this fails dropping the stack will unmap the pages mapped so
+            // far, so map them one by one.
+            let page = allocate_page()?;
+            paging::map_kernel_data(va, page.as_physical(), PAGE_SIZE);
+            va = va.add(PAGE_SIZE);
+        }
+
+        Ok(stack)
+    }
+
+    fn slot_base(&self) -> AddressVirtual {
+        KSTACK_REGION_START.add(self.slot as u64 * KSTACK_SLOT_SIZE)
+    }
+
+    /// The lowest address of the usable stack
+    pub fn bottom(&self) -> AddressVirtual {
+        self.slot_base().add(KSTACK_SLOT_SIZE - KSTACK_SIZE)
+    }
+
+    /// The address the stack pointer should start from. This is one byte past
+    /// the end of the stack so it's never mapped itself.
+    pub fn top(&self) -> AddressVirtual {
+        self.bottom().add(KSTACK_SIZE)
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let bottom = self.bottom();
+        let mut va = bottom;
+        // Pages are mapped in order so stop at the first hole, which can only
+        // exist if KernelStack::new() failed half way through
+        while va < self.top() && paging::is_mapped(va) {
+            va = va.add(PAGE_SIZE);
+        }
+
+        paging::unmap_range(bottom, va.as_u64() - bottom.as_u64(), |pa| {
+            // SAFETY: The page was allocated with allocate_page() in
+            // KernelStack::new() and it's no longer mapped anywhere else
+            unsafe { free_page(pa.as_virtual()) }
+        });
+
+        SLOTS.lock()[self.slot] = None;
+    }
+}
+
+/// Allocates the stack that the given CPU will run on after early boot and
+/// returns its top.
+pub fn allocate_cpu_stack(cpu: usize) -> AddressVirtual {
+    let stack = KernelStack::new(CPU_STACK_NAMES[cpu]).unwrap();
+    let top = stack.top();
+
+    let mut cpu_stacks = CPU_STACKS.lock();
+    assert!(cpu_stacks[cpu].is_none());
+    cpu_stacks[cpu] = Some(stack);
+
+    top
+}
+
+/// Returns true if the address lies in the guard area of a kernel stack slot
+pub fn is_guard_address(addr: u64) -> bool {
+    let start = KSTACK_REGION_START.as_u64();
+    if !(start..start + KSTACK_REGION_SIZE).contains(&addr) {
+        return false;
+    }
+
+    (addr - start) % KSTACK_SLOT_SIZE < KSTACK_SLOT_SIZE - KSTACK_SIZE
+}
+
+/// Returns the name of the thread whose stack has its guard area at the given
+/// address. This is called when handling stack overflows, so the owner can
+/// be holding the lock protecting the slots. In that case the owner will be
+/// reported as unknown rather than deadlocking.
+pub fn guard_owner(addr: u64) -> Option<&'static str> {
+    if !is_guard_address(addr) {
+        return None;
+    }
+
+    let slot = ((addr - KSTACK_REGION_START.as_u64()) / KSTACK_SLOT_SIZE) as usize;
+    SLOTS.try_lock()?[slot]
+}
+
+/// Switches the stack pointer to `top` and jumps to `entry`. The frame pointer
+/// and link register are zeroed so that unwinding stops there.
+///
+/// SAFETY: `top` must be the top of a stack that stays valid for as long as
+/// `entry` runs. Nothing on the current stack can be used after the switch.
+pub unsafe fn switch_to_stack(top: AddressVirtual, entry: fn() -> !) -> ! {
+    core::arch::asm!(
+        "mov sp, {top}",
+        "mov x29, xzr",
+        "mov x30, xzr",
+        "br {entry}",
+        top = in(reg) top.as_u64(),
+        entry = in(reg) entry,
+        options(noreturn),
+    );
+}
@@ -42,6 +42,15 @@ impl<T> SpinLock<T> {
 
         LockGuard { lock: self }
     }
+
+    /// Returns None instead of spinning if the lock is already taken
+    pub fn try_lock(&self) -> Option<LockGuard<'_, T>> {
+        if self.lock.swap(true, Ordering::Acquire) {
+            return None;
+        }
+
+        Some(LockGuard { lock: self })
+    }
 }
 
 // The lifetime annotation means that the LockGuard can't outlive the spinlock
@@ -111,6 +120,22 @@ impl<T> IRQSpinLock<T> {
             old_daif: daif,
         }
     }
+
+    /// Returns None instead of spinning if the lock is already taken
+    pub fn try_lock(&self) -> Option<IRQLockGuard<'_, T>> {
+        let daif = DAIF.get();
+        irq::disable_interrupts();
+
+        if self.lock.swap(true, Ordering::Acquire) {
+            DAIF.set(daif);
+            return None;
+        }
+
+        Some(IRQLockGuard {
+            lock: self,
+            old_daif: daif,
+        })
+    }
 }
 
 // The lifetime annotation means that the IRQLockGuard can't outlive the spinlock
@@ -1,74 +1,338 @@
-use crate::drivers::uart_mini;
-use core::arch::asm;
+// The kernel log.
+//
+// Like print!() and println!(), the log macros (error!() to trace!()) write
+// to the console, and to the framebuffer console once there is one. They also
+// record every message that passes the level filter in a ring buffer, together
+// with a CNTPCT_EL0 timestamp and the ID of the CPU that logged it. Messages
+// logged before the console is attached are only recorded and are replayed
+// once it is.
+
+use crate::drivers::pm;
+use crate::locking::IRQSpinLock;
+use crate::time::{self, Instant};
+use crate::{backtrace, exceptions, fbconsole};
+use crate::{console, cpu, println};
+use core::fmt::Write;
 use core::panic::PanicInfo;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Error),
+            2 => Some(Self::Warn),
+            3 => Some(Self::Info),
+            4 => Some(Self::Debug),
+            5 => Some(Self::Trace),
+            _ => None,
+        }
+    }
+
+    /// Parses a level name like "warn"
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(Self::Error),
+            "warn" => Some(Self::Warn),
+            "info" => Some(Self::Info),
+            "debug" => Some(Self::Debug),
+            "trace" => Some(Self::Trace),
+            _ => None,
+        }
+    }
+
+    fn tag(&self) -> char {
+        match self {
+            Self::Error => 'E',
+            Self::Warn => 'W',
+            Self::Info => 'I',
+            Self::Debug => 'D',
+            Self::Trace => 'T',
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::logging::log($level, module_path!(), format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log!($crate::logging::Level::Error, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log!($crate::logging::Level::Warn, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log!($crate::logging::Level::Info, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log!($crate::logging::Level::Debug, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::log!($crate::logging::Level::Trace, $($arg)*) };
+}
+
+// The level used for modules without an entry in MODULE_LEVELS
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+const MAX_MODULE_LEVELS: usize = 16;
+const MAX_MODULE_PATH_LEN: usize = 64;
+
+type ModulePath = heapless::String<MAX_MODULE_PATH_LEN>;
+
+// Per-module overrides of MAX_LEVEL. An entry applies to the module with that
+// path and its submodules, and the longest matching path wins.
+static MODULE_LEVELS: IRQSpinLock<heapless::Vec<(ModulePath, Level), MAX_MODULE_LEVELS>> =
+    IRQSpinLock::new(heapless::Vec::new());
+
+pub fn max_level() -> Level {
+    Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed)).unwrap()
+}
+
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Overrides the level for a module path like "mythos::paging". Fails if the
+/// path is too long or there are too many overrides.
+pub fn set_module_level(module: &str, level: Level) -> Result<(), ()> {
+    let mut levels = MODULE_LEVELS.lock();
+    match levels.iter_mut().find(|(m, _)| m == module) {
+        Some(entry) => entry.1 = level,
+        None => {
+            let path = ModulePath::try_from(module).map_err(|_| ())?;
+            levels.push((path, level)).map_err(|_| ())?;
+        }
+    }
+    Ok(())
+}
+
+fn enabled(level: Level, module: &str) -> bool {
+    let is_prefix = |m: &str| {
+        module
+            .strip_prefix(m)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    };
+
+    let max = MODULE_LEVELS
+        .lock()
+        .iter()
+        .filter(|(m, _)| is_prefix(m))
+        .max_by_key(|(m, _)| m.len())
+        .map(|&(_, level)| level as u8)
+        .unwrap_or(MAX_LEVEL.load(Ordering::Relaxed));
+
+    level as u8 <= max
+}
+
+const LOG_BUFFER_SIZE: usize = 16 * 1024;
+// Longer messages are truncated
+const MAX_MESSAGE_LEN: usize = 256;
+
+// Every record in the ring buffer is a header followed by the message
+struct RecordHeader {
+    timestamp: u64,
+    cpu: u8,
+    level: Level,
+    len: u16,
+}
 
-pub struct SerialConsole;
+const RECORD_HEADER_SIZE: usize = 12;
 
-impl core::fmt::Write for SerialConsole {
+impl RecordHeader {
+    fn to_bytes(&self) -> [u8; RECORD_HEADER_SIZE] {
+        let mut bytes = [0; RECORD_HEADER_SIZE];
+        bytes[0..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[8] = self.cpu;
+        bytes[9] = self.level as u8;
+        bytes[10..12].copy_from_slice(&self.len.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; RECORD_HEADER_SIZE]) -> Self {
+        RecordHeader {
+            timestamp: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            cpu: bytes[8],
+            level: Level::from_u8(bytes[9]).unwrap(),
+            len: u16::from_le_bytes(bytes[10..12].try_into().unwrap()),
+        }
+    }
+}
+
+struct LogBuffer {
+    data: [u8; LOG_BUFFER_SIZE],
+    // Positions grow forever and are wrapped when indexing into the data.
+    // head is where the oldest record starts and tail where the next one
+    // will be written.
+    head: usize,
+    tail: usize,
+}
+
+static LOG_BUFFER: IRQSpinLock<LogBuffer> = IRQSpinLock::new(LogBuffer {
+    data: [0; LOG_BUFFER_SIZE],
+    head: 0,
+    tail: 0,
+});
+
+impl LogBuffer {
+    fn write_at(&mut self, pos: usize, bytes: &[u8]) {
+        for (i, &b) in bytes.iter().enumerate() {
+            self.data[(pos + i) % LOG_BUFFER_SIZE] = b;
+        }
+    }
+
+    fn read_at(&self, pos: usize, bytes: &mut [u8]) {
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = self.data[(pos + i) % LOG_BUFFER_SIZE];
+        }
+    }
+
+    fn header_at(&self, pos: usize) -> RecordHeader {
+        let mut bytes = [0; RECORD_HEADER_SIZE];
+        self.read_at(pos, &mut bytes);
+        RecordHeader::from_bytes(&bytes)
+    }
+
+    fn push(&mut self, header: RecordHeader, message: &[u8]) {
+        let size = RECORD_HEADER_SIZE + message.len();
+        // Drop the oldest records to make room
+        while self.tail + size - self.head > LOG_BUFFER_SIZE {
+            self.head += RECORD_HEADER_SIZE + self.header_at(self.head).len as usize;
+        }
+
+        self.write_at(self.tail, &header.to_bytes());
+        self.write_at(self.tail + RECORD_HEADER_SIZE, message);
+        self.tail += size;
+    }
+
+    // Calls f for every record, oldest first
+    fn for_each(&self, mut f: impl FnMut(&RecordHeader, &str)) {
+        let mut message = [0; MAX_MESSAGE_LEN];
+        let mut pos = self.head;
+        while pos < self.tail {
+            let header = self.header_at(pos);
+            let message = &mut message[..header.len as usize];
+            self.read_at(pos + RECORD_HEADER_SIZE, message);
+            f(
+                &header,
+                core::str::from_utf8(message).unwrap_or("<invalid UTF-8>"),
+            );
+            pos += RECORD_HEADER_SIZE + header.len as usize;
+        }
+    }
+}
+
+// Formats into a fixed buffer, silently truncating what doesn't fit
+struct MessageBuffer {
+    buffer: [u8; MAX_MESSAGE_LEN],
+    len: usize,
+}
+
+impl core::fmt::Write for MessageBuffer {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         for c in s.chars() {
-            if c == '\n' {
-                uart_mini::put_char('\r');
+            let mut encoded = [0; 4];
+            let encoded = c.encode_utf8(&mut encoded).as_bytes();
+            if self.len + encoded.len() > MAX_MESSAGE_LEN {
+                break;
             }
-            uart_mini::put_char(c);
+            self.buffer[self.len..self.len + encoded.len()].copy_from_slice(encoded);
+            self.len += encoded.len();
         }
         Ok(())
     }
 }
 
-#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => {{
-        use core::fmt::Write;
-        let mut serial = $crate::logging::SerialConsole;
-        serial.write_fmt(format_args!($($arg)*)).unwrap();
-    }};
+static CONSOLE_ATTACHED: AtomicBool = AtomicBool::new(false);
+
+fn print_record(out: &mut impl core::fmt::Write, header: &RecordHeader, message: &str) {
+    let timestamp = time::ticks_to_duration(header.timestamp);
+    let (secs, micros) = (timestamp.as_secs(), timestamp.subsec_micros());
+    let _ = writeln!(
+        out,
+        "[{secs:5}.{micros:06}] cpu{} {} {message}",
+        header.cpu,
+        header.level.tag()
+    );
 }
 
-#[macro_export]
-macro_rules! println {
-    ($($arg:tt)*) => {{
-        $crate::print!($($arg)*);
-        $crate::print!("\n");
-    }};
-}
-
-fn dump_registers() {
-    let x0: u64;
-    let x1: u64;
-    let x2: u64;
-    let x3: u64;
-    let fp: u64;
-    let lr: u64;
-    let sp: u64;
-
-    // SAFETY: Simply moving registers and then copying them to memory
-    unsafe {
-        asm!(
-            "mov {0}, x0",
-            "mov {1}, x1",
-            "mov {2}, x2",
-            "mov {3}, x3",
-            "mov {4}, fp",
-            "mov {5}, lr",
-            "mov {6}, sp",
-            out(reg) x0,
-            out(reg) x1,
-            out(reg) x2,
-            out(reg) x3,
-            out(reg) fp,
-            out(reg) lr,
-            out(reg) sp,
-        );
+/// Called by the log macros
+pub fn log(level: Level, module: &'static str, args: core::fmt::Arguments) {
+    if !enabled(level, module) {
+        return;
     }
 
-    println!(
-        "Register dump:\n\
-        x0: 0x{:016x} x1: 0x{:016x} x2: 0x{:016x} x3: 0x{:016x}\n\
-        fp: 0x{:016x} lr: 0x{:016x} sp: 0x{:016x}",
-        x0, x1, x2, x3, fp, lr, sp
-    );
+    let mut message = MessageBuffer {
+        buffer: [0; MAX_MESSAGE_LEN],
+        len: 0,
+    };
+    let module = module.strip_prefix("mythos::").unwrap_or(module);
+    let _ = write!(message, "{module}: {args}");
+    // Truncation could have split a character, but write_str() doesn't do that
+    let text = core::str::from_utf8(&message.buffer[..message.len]).unwrap();
+
+    let header = RecordHeader {
+        timestamp: Instant::now().ticks(),
+        cpu: cpu::current_id() as u8,
+        level,
+        len: message.len as u16,
+    };
+
+    if CONSOLE_ATTACHED.load(Ordering::Relaxed) {
+        print_record(&mut console::lock(), &header, text);
+    }
+    fbconsole::mirror(|out| print_record(out, &header, text));
+    LOG_BUFFER.lock().push(header, text.as_bytes());
+}
+
+/// Writes every record in the log buffer, oldest first
+pub fn replay(out: &mut impl core::fmt::Write) {
+    LOG_BUFFER
+        .lock()
+        .for_each(|header, message| print_record(out, header, message));
+}
+
+/// Starts printing log messages on the console, after replaying the ones
+/// logged so far
+pub fn attach_console() {
+    replay(&mut console::lock());
+    CONSOLE_ATTACHED.store(true, Ordering::Relaxed);
+}
+
+/// Prints the log buffer on the console
+pub fn dmesg() {
+    replay(&mut console::lock());
+}
+
+// The panic handler can't wait for the log buffer lock since the panicking
+// code could be holding it
+fn dump_log_on_panic() {
+    let Some(buffer) = LOG_BUFFER.try_lock() else {
+        println!("(log buffer locked)");
+        return;
+    };
+    let mut console = console::lock();
+    buffer.for_each(|header, message| print_record(&mut console, header, message));
 }
 
 #[panic_handler]
@@ -81,6 +345,11 @@ pub fn panic(info: &PanicInfo) -> ! {
         loop {}
     }
 
+    // Whoever holds the console lock might never release it and interrupts
+    // might not be serviced anymore
+    console::break_lock();
+    console::disable_buffering();
+
     let (file, line, column) = match info.location() {
         Some(location) => (location.file(), location.line(), location.column()),
         _ => ("", 0, 0),
@@ -93,8 +362,22 @@ pub fn panic(info: &PanicInfo) -> ! {
         line,
         column,
     );
+    fbconsole::print_on_panic(format_args!(
+        "\nKERNEL PANIC - {} ('{}', line {})\n",
+        info.message(),
+        file,
+        line
+    ));
+
+    // If the panic was caused by an exception the backtrace of the panic
+    // handler itself is of no interest
+    if !exceptions::report_fatal_exception() {
+        backtrace::print_current();
+    }
 
-    dump_registers();
+    println!("\nKernel log:");
+    dump_log_on_panic();
 
+    pm::reboot_after_panic();
     loop {}
 }
@@ -3,24 +3,44 @@
 
 mod address;
 mod allocator;
+mod backtrace;
+mod clock;
+mod cmdline;
+mod console;
+mod cpu;
+mod debug;
 mod delay;
 mod drivers;
+mod esr;
 mod exceptions;
+mod fbconsole;
+mod font;
+mod fpsimd;
+#[cfg(feature = "gdb")]
+mod gdb;
+mod graphics;
 mod irq;
+mod kstack;
 mod locking;
 mod logging;
 mod memory;
 mod paging;
+mod random;
+mod shell;
+mod thread;
+mod time;
+mod tty;
 
 use crate::address::{AddressPhysical, RangePhysical, KSTACK_GUARD_CPU0, KSTACK_TOP_CPU0};
 use crate::delay::busy_wait;
 use crate::locking::IRQSpinLock;
 use crate::memory::PAGE_SIZE;
 use aarch64_cpu::asm;
-use aarch64_cpu::registers::{CurrentEL, ELR_EL2, HCR_EL2, SP, SPSR_EL2, SP_EL1};
+use aarch64_cpu::registers::{CurrentEL, CPTR_EL2, ELR_EL2, HCR_EL2, SP, SPSR_EL2, SP_EL1};
 use core::arch::global_asm;
-use drivers::{mailbox, uart_mini};
+use drivers::{mailbox, pm, uart_mini, uart_pl011};
 use tock_registers::interfaces::{Readable, Writeable};
+use tock_registers::{register_bitfields, LocalRegisterCopy};
 
 global_asm!(include_str!("boot.s"));
 
@@ -33,6 +53,23 @@ pub static PENDING_ACTIONS: IRQSpinLock<u64> = IRQSpinLock::new(0);
 
 pub enum ACTIONS {
     UartAction = 0,
+    Pl011Action = 1,
+    ClockAction = 2,
+    FbConsoleAction = 3,
+}
+
+register_bitfields! {u64,
+    // CPTR_EL2 with HCR_EL2.E2H == 0. The aarch64_cpu crate only defines TAM.
+    CPTR [
+        TCPAC OFFSET(31) NUMBITS(1) [],
+        TAM OFFSET(30) NUMBITS(1) [],
+        TTA OFFSET(20) NUMBITS(1) [],
+        RES1_HIGH OFFSET(12) NUMBITS(2) [],
+        TFP OFFSET(10) NUMBITS(1) [],
+        RES1_9 OFFSET(9) NUMBITS(1) [],
+        TZ OFFSET(8) NUMBITS(1) [],
+        RES1_LOW OFFSET(0) NUMBITS(8) [],
+    ]
 }
 
 pub fn jump_to_el1() {
@@ -44,6 +81,19 @@ pub fn jump_to_el1() {
 
     HCR_EL2.write(HCR_EL2::RW::EL1IsAarch64);
 
+    // Don't trap FP/SIMD (TFP) or SVE (TZ) accesses to EL2, CPACR_EL1 controls
+    // trapping to EL1. Nor trace, activity monitor or CPACR accesses. The RES1
+    // bits must be written as ones.
+    let mut cptr: LocalRegisterCopy<u64, CPTR::Register> = LocalRegisterCopy::new(0);
+    cptr.write(
+        CPTR::TFP::CLEAR
+            + CPTR::TZ::CLEAR
+            + CPTR::RES1_LOW::SET
+            + CPTR::RES1_9::SET
+            + CPTR::RES1_HIGH::SET,
+    );
+    CPTR_EL2.set(cptr.get());
+
     // Also SPSR_EL2 bit 4 must be 0 to indicate that we'll return to the
     // AArch64 execution state. Unfortunately the aarch64_cpu crate doesn't
     // support that bit, but the write() function will make sure it's cleared.
@@ -55,7 +105,7 @@ pub fn jump_to_el1() {
             + SPSR_EL2::M::EL1h,
     );
 
-    ELR_EL2.set(AddressPhysical::new(pre_main as usize as u64).as_u64());
+    ELR_EL2.set(AddressPhysical::new(pre_main as *const () as u64).as_u64());
 
     SP_EL1.set(KSTACK_TOP_CPU0.as_physical().as_u64());
 
@@ -77,7 +127,7 @@ pub fn pre_main() {
     SP.set(sp_high.as_u64());
     asm::barrier::isb(asm::barrier::SY);
 
-    let main_addr = AddressPhysical::new(main as usize as u64).as_virtual();
+    let main_addr = AddressPhysical::new(main as *const () as u64).as_virtual();
     // SAFETY: We trust that paging has been setup correctly
     let main = unsafe { core::mem::transmute::<u64, fn()>(main_addr.as_u64()) };
     main();
@@ -140,32 +190,39 @@ fn allocator_init(ram_range: RangePhysical, binary_size: usize) {
 pub fn main() -> ! {
     exceptions::install_exception_table();
     irq::enable_interrupts();
+    time::init();
 
-    uart_mini::init(115200);
+    console::init(115200);
+    // GDB always talks over the mini UART, the console is on the PL011
+    #[cfg(feature = "gdb")]
+    uart_mini::init(115200, console::FLOW_CONTROL);
+    logging::attach_console();
 
     blink_onboard_led();
 
-    println!(
-        "VideoCore Firmware Version: {:#x}",
-        mailbox::get_vc_fw_version().unwrap()
-    );
-    println!(
-        "Board Serial Number: {:#x}",
-        mailbox::get_board_serial().unwrap()
+    let board = mailbox::get_board_info().unwrap();
+    cmdline::init();
+    info!("VideoCore Firmware Version: {:#x}", board.fw_version);
+    info!("Board Model: {:#x}", board.model);
+    info!("Board Serial Number: {:#x}", board.serial);
+    info!(
+        "Core clock: {} Hz (max {} Hz)",
+        mailbox::get_clock_rate(mailbox::ClockId::Core).unwrap(),
+        mailbox::get_max_clock_rate(mailbox::ClockId::Core).unwrap()
     );
 
     let kernel_size = &raw const __kernel_size as usize;
-    println!("Kernel binary size = {kernel_size:#x} bytes");
+    info!("Kernel binary size = {kernel_size:#x} bytes");
 
-    let ram_range = mailbox::get_arm_memory().unwrap();
-    println!(
+    let ram_range = board.arm_memory;
+    info!(
         "ARM memory base={:#x} size={:#x}",
         ram_range.base().as_u64(),
         ram_range.size()
     );
 
-    let vc_range = mailbox::get_videocore_memory().unwrap();
-    println!(
+    let vc_range = board.videocore_memory;
+    info!(
         "VideoCore memory base={:#x} size={:#x}",
         vc_range.base().as_u64(),
         vc_range.size()
@@ -175,9 +232,39 @@ pub fn main() -> ! {
 
     paging::setup_runtime_paging();
 
-    print!("Everything you type will be echoed: ");
+    // Move off the boot stack, which has no guard area in the linear map,
+    // to a stack in the kernel stack region.
+    let stack_top = kstack::allocate_cpu_stack(cpu::current_id());
+    // SAFETY: The stack was just allocated and is never freed
+    unsafe { kstack::switch_to_stack(stack_top, kernel_main) }
+}
+
+// When execution gets here the kernel is running on a guarded stack
+fn kernel_main() -> ! {
+    debug::init();
+    fpsimd::init();
+    thread::init();
+    random::init();
+
+    if let Err(e) = fbconsole::init() {
+        warn!("No framebuffer console: {e}");
+    }
+
+    #[cfg(feature = "gdb")]
+    {
+        info!("Waiting for GDB on the mini UART");
+        gdb::breakpoint();
+    }
+
+    clock::init();
+    pm::init();
+    shell::init();
 
     loop {
+        // Let the shell thread run
+        thread::yield_now();
+        pm::watchdog_pet();
+
         loop {
             irq::disable_interrupts();
 
@@ -195,6 +282,15 @@ pub fn main() -> ! {
             if pending & (1 << (ACTIONS::UartAction as u64)) != 0 {
                 uart_mini::process_pending_chars();
             }
+            if pending & (1 << (ACTIONS::Pl011Action as u64)) != 0 {
+                uart_pl011::process_pending_chars();
+            }
+            if pending & (1 << (ACTIONS::ClockAction as u64)) != 0 {
+                clock::process_pending_poll();
+            }
+            if pending & (1 << (ACTIONS::FbConsoleAction as u64)) != 0 {
+                fbconsole::process_pending();
+            }
         }
 
         // When we get here interrupts must be disabled, otherwise an interrupt
@@ -1,3 +1,5 @@
+#[cfg(feature = "gdb")]
+use crate::address::TEXT_PATCH_VA;
 use crate::address::{
     AddressPhysical, AddressVirtual, KSTACK_BOTTOM_CPU0, KSTACK_SIZE, PERIPHERALS_BASE,
     PERIPHERALS_SIZE,
@@ -9,6 +11,7 @@ use aarch64_cpu::asm::barrier;
 use aarch64_cpu::registers::{
     ID_AA64MMFR0_EL1, MAIR_EL1, SCTLR_EL1, SP, TCR_EL1, TTBR0_EL1, TTBR1_EL1,
 };
+use heapless::Vec;
 use tock_registers::fields::FieldValue;
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 use tock_registers::{register_bitfields, LocalRegisterCopy};
@@ -42,6 +45,9 @@ register_bitfields! {
 enum MairType {
     Normal = 0,
     Device = 1,
+    // Normal memory that isn't cached but where writes can be combined, for
+    // memory that other bus masters read like the framebuffer
+    NormalNonCacheable = 2,
 }
 
 #[repr(align(4096))]
@@ -49,8 +55,10 @@ struct PageTable {
     pte: [LocalRegisterCopy<u64, PTE::Register>; 512],
 }
 
-// Root L2 page table used after early boot. Uses a 4KiB translation granule.
-static L2_PT: SpinLock<PageTable> = SpinLock::new(PageTable {
+// Root L1 page table used after early boot. Uses a 4KiB translation granule.
+// With a 31-bit kernel address space only its first 2 entries are used, one
+// for each GiB. The L2 and L3 tables are allocated on demand.
+static L1_PT: SpinLock<PageTable> = SpinLock::new(PageTable {
     pte: [LocalRegisterCopy::new(0); 512],
 });
 
@@ -86,7 +94,9 @@ pub fn setup_early_boot_paging() {
     MAIR_EL1.write(
         MAIR_EL1::Attr0_Normal_Inner::WriteBack_NonTransient_ReadWriteAlloc
             + MAIR_EL1::Attr0_Normal_Outer::WriteBack_NonTransient_ReadWriteAlloc
-            + MAIR_EL1::Attr1_Device::nonGathering_nonReordering_EarlyWriteAck,
+            + MAIR_EL1::Attr1_Device::nonGathering_nonReordering_EarlyWriteAck
+            + MAIR_EL1::Attr2_Normal_Inner::NonCacheable
+            + MAIR_EL1::Attr2_Normal_Outer::NonCacheable,
     );
 
     // The first PTE in the L2 PT maps a 512MiB block of normal memory
@@ -152,6 +162,10 @@ pub fn setup_early_boot_paging() {
     barrier::isb(barrier::SY);
 }
 
+fn l1_idx(va: AddressVirtual) -> usize {
+    ((va.as_u64() >> 30) & 0x1) as usize
+}
+
 fn l2_idx(va: AddressVirtual) -> usize {
     ((va.as_u64() >> 21) & 0x1ff) as usize
 }
@@ -160,30 +174,42 @@ fn l3_idx(va: AddressVirtual) -> usize {
     ((va.as_u64() >> 12) & 0x1ff) as usize
 }
 
-/// Panics if the page is mapped already
-fn map_page(va: AddressVirtual, pa: AddressPhysical, attributes: FieldValue<u64, PTE::Register>) {
-    let l2_pt = &mut *L2_PT.lock();
-    let l2_pte = &mut l2_pt.pte[l2_idx(va)];
+/// Returns the next level page table that the given table descriptor points
+/// to. If the descriptor is not valid and `allocate` is true a new page table
+/// is allocated and the descriptor is updated accordingly.
+fn next_level_table(
+    pte: &mut LocalRegisterCopy<u64, PTE::Register>,
+    allocate: bool,
+) -> Option<&'static mut PageTable> {
+    if pte.is_set(PTE::VALID) {
+        let pt_addr = AddressPhysical::new(pte.read(PTE::ADDRESS) << 12);
+        // SAFETY: Valid table descriptors always point to page tables that
+        // were allocated by this function and are never freed
+        return Some(unsafe { &mut *(pt_addr.as_virtual().as_u64() as *mut PageTable) });
+    }
 
-    let l3_pt;
-    if l2_pte.is_set(PTE::VALID) {
-        // There already is a L3 PT for the 4KiB in question
-        let l3_pt_addr = AddressPhysical::new(l2_pte.read(PTE::ADDRESS) << 12);
-        l3_pt = unsafe { &mut *(l3_pt_addr.as_virtual().as_u64() as *mut PageTable) };
-    } else {
-        // We need to allocate a new page to be used as the L3 PT, and we need
-        // to update the L2 PTE accordingly
-        let page = allocate_page().unwrap();
-        l3_pt = unsafe { &mut *(page.as_u64() as *mut PageTable) };
-
-        l2_pte.write(
-            PTE::ADDRESS.val(page.as_physical().as_u64() >> 12)
-                + PTE::VALID::SET
-                + PTE::AF::SET
-                + PTE::DESC_TYPE::TABLE_OR_PAGE,
-        );
+    if !allocate {
+        return None;
     }
 
+    let page = allocate_page().unwrap();
+    pte.write(
+        PTE::ADDRESS.val(page.as_physical().as_u64() >> 12)
+            + PTE::VALID::SET
+            + PTE::AF::SET
+            + PTE::DESC_TYPE::TABLE_OR_PAGE,
+    );
+
+    // SAFETY: allocate_page() returned a zeroed page that nobody else uses
+    Some(unsafe { &mut *(page.as_u64() as *mut PageTable) })
+}
+
+/// Panics if the page is mapped already
+fn map_page(va: AddressVirtual, pa: AddressPhysical, attributes: FieldValue<u64, PTE::Register>) {
+    let l1_pt = &mut *L1_PT.lock();
+    let l2_pt = next_level_table(&mut l1_pt.pte[l1_idx(va)], true).unwrap();
+    let l3_pt = next_level_table(&mut l2_pt.pte[l2_idx(va)], true).unwrap();
+
     let l3_pte = &mut l3_pt.pte[l3_idx(va)];
     assert!(!l3_pte.is_set(PTE::VALID));
 
@@ -196,6 +222,97 @@ fn map_page(va: AddressVirtual, pa: AddressPhysical, attributes: FieldValue<u64,
     );
 }
 
+pub fn is_mapped(va: AddressVirtual) -> bool {
+    let l1_pt = &mut *L1_PT.lock();
+    let Some(l2_pt) = next_level_table(&mut l1_pt.pte[l1_idx(va)], false) else {
+        return false;
+    };
+    let Some(l3_pt) = next_level_table(&mut l2_pt.pte[l2_idx(va)], false) else {
+        return false;
+    };
+
+    l3_pt.pte[l3_idx(va)].is_set(PTE::VALID)
+}
+
+/// Returns the descriptors the runtime page tables translate `va` with, one
+/// per level starting from L1 and stopping at the first invalid one
+pub fn walk(va: AddressVirtual) -> Vec<u64, 3> {
+    let mut descriptors = Vec::new();
+    let l1_pt = &mut *L1_PT.lock();
+
+    let l1_pte = &mut l1_pt.pte[l1_idx(va)];
+    descriptors.push(l1_pte.get()).unwrap();
+    let Some(l2_pt) = next_level_table(l1_pte, false) else {
+        return descriptors;
+    };
+
+    let l2_pte = &mut l2_pt.pte[l2_idx(va)];
+    descriptors.push(l2_pte.get()).unwrap();
+    let Some(l3_pt) = next_level_table(l2_pte, false) else {
+        return descriptors;
+    };
+
+    descriptors.push(l3_pt.pte[l3_idx(va)].get()).unwrap();
+    descriptors
+}
+
+/// Returns true if reading from the address wouldn't fault. Unlike
+/// is_mapped() this asks the MMU and doesn't take any locks, so it's safe to
+/// use from the panic handler.
+pub fn is_readable(va: u64) -> bool {
+    let par_el1: u64;
+    // SAFETY: The AT instruction only performs a translation and stores the
+    // result in PAR_EL1, it doesn't access the address itself
+    unsafe {
+        core::arch::asm!(
+            "at s1e1r, {va}",
+            "isb",
+            "mrs {par}, par_el1",
+            va = in(reg) va,
+            par = out(reg) par_el1,
+            options(nostack, preserves_flags),
+        );
+    }
+
+    // PAR_EL1.F, bit 0: set if the translation failed
+    par_el1 & 1 == 0
+}
+
+/// Same as is_readable() but for writes
+pub fn is_writable(va: u64) -> bool {
+    let par_el1: u64;
+    // SAFETY: Same as in is_readable()
+    unsafe {
+        core::arch::asm!(
+            "at s1e1w, {va}",
+            "isb",
+            "mrs {par}, par_el1",
+            va = in(reg) va,
+            par = out(reg) par_el1,
+            options(nostack, preserves_flags),
+        );
+    }
+
+    par_el1 & 1 == 0
+}
+
+/// Unmaps a page and returns the physical address it was mapped to. Panics if
+/// the page is not mapped.
+fn unmap_page(va: AddressVirtual) -> AddressPhysical {
+    let l1_pt = &mut *L1_PT.lock();
+    let l2_pt = next_level_table(&mut l1_pt.pte[l1_idx(va)], false).unwrap();
+    let l3_pt = next_level_table(&mut l2_pt.pte[l2_idx(va)], false).unwrap();
+
+    let l3_pte = &mut l3_pt.pte[l3_idx(va)];
+    assert!(l3_pte.is_set(PTE::VALID));
+
+    let pa = AddressPhysical::new(l3_pte.read(PTE::ADDRESS) << 12);
+    l3_pte.set(0);
+    flush_tlb_page(va);
+
+    pa
+}
+
 pub fn map_range(
     mut va: AddressVirtual,
     mut pa: AddressPhysical,
@@ -220,6 +337,78 @@ pub fn map_range(
     }
 }
 
+/// Maps a range of pages as RW and non-executable normal memory. Unlike
+/// map_range() this can be called after the runtime page tables are active.
+pub fn map_kernel_data(va: AddressVirtual, pa: AddressPhysical, size: u64) {
+    let attributes = PTE::ATTR_INDEX.val(MairType::Normal as u64)
+        + PTE::SH::INNER_SHAREABLE
+        + PTE::UXN::SET
+        + PTE::PXN::SET
+        + PTE::AP::RW_KERNEL;
+    map_range(va, pa, size, attributes);
+
+    // Make sure the table walker can see the new entries before they're used.
+    // No TLB maintenance is needed since the entries were invalid before.
+    barrier::dsb(barrier::ISHST);
+    barrier::isb(barrier::SY);
+}
+
+/// Same as map_kernel_data() but the pages are mapped as non-cacheable, so
+/// that writes reach memory without cache maintenance, e.g. for framebuffers
+pub fn map_kernel_write_combining(va: AddressVirtual, pa: AddressPhysical, size: u64) {
+    let attributes = PTE::ATTR_INDEX.val(MairType::NormalNonCacheable as u64)
+        + PTE::SH::OUTER_SHAREABLE
+        + PTE::UXN::SET
+        + PTE::PXN::SET
+        + PTE::AP::RW_KERNEL;
+    map_range(va, pa, size, attributes);
+
+    barrier::dsb(barrier::ISHST);
+    barrier::isb(barrier::SY);
+}
+
+/// Unmaps a range of pages and calls `f` with the physical address each page
+/// was mapped to.
+pub fn unmap_range(mut va: AddressVirtual, size: u64, mut f: impl FnMut(AddressPhysical)) {
+    assert!(size.is_multiple_of(PAGE_SIZE));
+
+    for _ in 0..size / PAGE_SIZE {
+        f(unmap_page(va));
+        va = va.add(PAGE_SIZE);
+    }
+}
+
+/// Overwrites the instruction at `va` in the kernel text, which is mapped
+/// read-only, through a temporary writable mapping of the same page.
+#[cfg(feature = "gdb")]
+pub fn patch_text(va: AddressVirtual, insn: u32) {
+    assert!(va.as_u64().is_multiple_of(4));
+
+    let page = va.align_down(PAGE_SIZE);
+    let offset = va.as_u64() - page.as_u64();
+    map_kernel_data(TEXT_PATCH_VA, page.as_physical(), PAGE_SIZE);
+    // SAFETY: TEXT_PATCH_VA was just mapped and is only used here
+    unsafe {
+        core::ptr::write_volatile(TEXT_PATCH_VA.add(offset).as_u64() as *mut u32, insn);
+    }
+    unmap_range(TEXT_PATCH_VA, PAGE_SIZE, |_| {});
+
+    // Make the new instruction visible to instruction fetches. Data caches
+    // are physically indexed so cleaning through the original VA is enough.
+    // SAFETY: Only cache maintenance for a single line
+    unsafe {
+        core::arch::asm!(
+            "dc cvau, {va}",
+            "dsb ish",
+            "ic ivau, {va}",
+            "dsb ish",
+            "isb",
+            va = in(reg) va.as_u64(),
+            options(nostack, preserves_flags),
+        );
+    }
+}
+
 /// Setup the runtime page tables used by the kernel after early boot and after
 /// initialising the page allocator. The runtime page tables use a 4KiB
 /// translation granule and identity map all RAM (except the stack guard areas)
@@ -332,7 +521,7 @@ pub fn setup_runtime_paging() {
 
     // And then jump to a low address
     let func_addr =
-        AddressVirtual::new(switch_to_runtime_page_tables as usize as u64).as_physical();
+        AddressVirtual::new(switch_to_runtime_page_tables as *const () as u64).as_physical();
     // SAFETY: TTBR0_EL1 is active so it's safe to jump to a low address
     let switch_to_runtime_page_tables =
         unsafe { core::mem::transmute::<u64, fn()>(func_addr.as_u64()) };
@@ -355,12 +544,13 @@ pub fn setup_runtime_paging() {
 // This function must be run from a low address
 #[inline(never)]
 fn switch_to_runtime_page_tables() {
-    // Change the translation granule of TTBR1_EL1 to 4KiB
-    TCR_EL1.modify(TCR_EL1::TG1::KiB_4);
+    // Change the translation granule of TTBR1_EL1 to 4KiB and grow the
+    // address space to 2GiB so that the walks start from an L1 table
+    TCR_EL1.modify(TCR_EL1::TG1::KiB_4 + TCR_EL1::T1SZ.val(33));
     barrier::dsb(barrier::SY);
 
     // And update the root page table
-    let ttbr1_baddr = AddressPhysical::new(&raw const *L2_PT.lock() as u64).as_u64();
+    let ttbr1_baddr = AddressPhysical::new(&raw const *L1_PT.lock() as u64).as_u64();
     TTBR1_EL1.write(TTBR1_EL1::BADDR.val(ttbr1_baddr >> 1) + TTBR1_EL1::CnP::SET);
     barrier::dsb(barrier::SY);
 
@@ -369,6 +559,20 @@ fn switch_to_runtime_page_tables() {
     // Jump back to a high address using the address stored in the LR!
 }
 
+#[inline]
+fn flush_tlb_page(va: AddressVirtual) {
+    barrier::dsb(barrier::ISHST);
+    // The operand holds VA[55:12] in its bits [43:0], the rest are RES0
+    let operand = (va.as_u64() >> 12) & ((1 << 44) - 1);
+    // SAFETY: The inline assembly invalidates the TLB entries for a single VA
+    unsafe {
+        core::arch::asm!("tlbi vaae1, {}", in(reg) operand);
+    }
+
+    barrier::dsb(barrier::SY);
+    barrier::isb(barrier::SY);
+}
+
 #[inline]
 fn flush_tlb_all() {
     // SAFETY: The inline assembly flushes the TLB
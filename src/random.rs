@@ -0,0 +1,195 @@
+// The kernel's source of random numbers: a ChaCha20 based CSPRNG. Its key is
+// the entropy pool, seeded at boot from the hardware RNG and from timer
+// jitter. Words waiting in the hardware RNG's FIFO are mixed in again on
+// every request.
+//
+// The output is ChaCha20 keystream. After every request the key is replaced
+// with fresh keystream ("fast key erasure"), so a leaked key doesn't reveal
+// earlier output.
+//
+// QEMU may not emulate the hardware RNG, in which case only the timer jitter
+// seeds the pool. That is fine for testing but not much more.
+
+use crate::drivers::{rng, system_timer};
+use crate::locking::IRQSpinLock;
+use crate::time::Instant;
+use crate::{info, warn};
+use core::time::Duration;
+
+const BLOCK_SIZE: usize = 64;
+const KEY_WORDS: usize = 8;
+
+// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574];
+
+// Hardware words to seed the pool with, 256 bits
+const SEED_WORDS: usize = 8;
+// Timer jitter samples to seed the pool with. Each is worth a bit at best.
+const JITTER_SAMPLES: usize = 1024;
+
+struct Pool {
+    key: [u32; KEY_WORDS],
+    // The block counter, the nonce is always 0 since the key changes after
+    // every request
+    counter: u64,
+}
+
+static POOL: IRQSpinLock<Pool> = IRQSpinLock::new(Pool {
+    key: [0; KEY_WORDS],
+    counter: 0,
+});
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+// The ChaCha20 block function from RFC 8439
+fn chacha20_block(input: &[u32; 16]) -> [u32; 16] {
+    let mut s = *input;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+    for (out, word) in s.iter_mut().zip(input) {
+        *out = out.wrapping_add(*word);
+    }
+    s
+}
+
+impl Pool {
+    fn next_block(&mut self) -> [u32; 16] {
+        let mut input = [0; 16];
+        input[0..4].copy_from_slice(&CONSTANTS);
+        input[4..12].copy_from_slice(&self.key);
+        input[12] = self.counter as u32;
+        input[13] = (self.counter >> 32) as u32;
+        self.counter = self.counter.wrapping_add(1);
+        chacha20_block(&input)
+    }
+
+    fn rekey(&mut self) {
+        let block = self.next_block();
+        self.key.copy_from_slice(&block[..KEY_WORDS]);
+        self.counter = 0;
+    }
+
+    // XORs the words into the key and stirs after every key's worth, so that
+    // every input affects the whole key
+    fn mix(&mut self, words: &[u32]) {
+        for chunk in words.chunks(KEY_WORDS) {
+            for (key, word) in self.key.iter_mut().zip(chunk) {
+                *key ^= word;
+            }
+            self.rekey();
+        }
+    }
+
+    fn mix_hardware(&mut self) {
+        let mut words = [0; KEY_WORDS];
+        let mut count = 0;
+        while count < KEY_WORDS {
+            let Some(word) = rng::try_read() else {
+                break;
+            };
+            words[count] = word;
+            count += 1;
+        }
+        if count > 0 {
+            self.mix(&words[..count]);
+        }
+    }
+
+    fn fill(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(BLOCK_SIZE) {
+            let block = self.next_block();
+            for (out, byte) in chunk
+                .iter_mut()
+                .zip(block.iter().flat_map(|w| w.to_le_bytes()))
+            {
+                *out = byte;
+            }
+        }
+        self.rekey();
+    }
+}
+
+// Times a loop whose length depends on the previous sample. The two timers
+// run off different clocks, and cache and bus activity make the loop's
+// duration vary a little, so the low bits are somewhat unpredictable.
+fn jitter_sample(previous: u64) -> u32 {
+    let start = Instant::now().ticks();
+    let mut x = previous ^ start;
+    for i in 0..(x & 0x3f) {
+        x = core::hint::black_box(x.rotate_left(7) ^ i);
+    }
+    let elapsed = Instant::now().ticks() - start;
+    (elapsed ^ (elapsed >> 32) ^ system_timer::now() ^ x) as u32
+}
+
+fn jitter_words() -> [u32; KEY_WORDS] {
+    let mut words = [0u32; KEY_WORDS];
+    let mut sample = 0;
+    for i in 0..JITTER_SAMPLES {
+        sample = jitter_sample(sample as u64);
+        // Spread the samples over the words so that each word folds in many
+        words[i % KEY_WORDS] = words[i % KEY_WORDS].rotate_left(5) ^ sample;
+    }
+    words
+}
+
+/// Seeds the pool. Must be called once at boot, before anything uses it.
+pub fn init() {
+    rng::init();
+
+    let mut seed = [0; SEED_WORDS];
+    let mut hardware_words = 0;
+    for word in seed.iter_mut() {
+        // The warm-up happens before the first word
+        match rng::read(Duration::from_millis(100)) {
+            Some(w) => {
+                *word = w;
+                hardware_words += 1;
+            }
+            None => break,
+        }
+    }
+
+    let mut pool = POOL.lock();
+    pool.mix(&seed[..hardware_words]);
+    pool.mix(&jitter_words());
+    drop(pool);
+
+    if hardware_words == SEED_WORDS {
+        info!("Random pool seeded from the hardware RNG and timer jitter");
+    } else if hardware_words > 0 {
+        warn!("Random pool seeded from {hardware_words} of {SEED_WORDS} hardware RNG words and timer jitter");
+    } else {
+        warn!("No hardware RNG, random pool seeded from timer jitter only");
+    }
+}
+
+/// Fills `bytes` with random bytes
+pub fn fill_bytes(bytes: &mut [u8]) {
+    let mut pool = POOL.lock();
+    pool.mix_hardware();
+    pool.fill(bytes);
+}
+
+pub fn next_u64() -> u64 {
+    let mut bytes = [0; 8];
+    fill_bytes(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
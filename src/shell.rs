@@ -0,0 +1,942 @@
+// A debug shell on the console. It runs in its own thread and reads commands
+// through the TTY. Other modules can add their own commands with register().
+
+use crate::address::{AddressPhysical, AddressVirtual, KSTACK_REGION_START};
+use crate::debug::{self, DebugError, WatchKind};
+use crate::drivers::gpio::{Event, GPIOPin, GpioError, PinMode, PullMode};
+use crate::drivers::mailbox::{self, ClockId, MailboxError, PowerDevice, VoltageId};
+use crate::drivers::{framebuffer, pm, uart_mini, uart_pl011};
+use crate::graphics::{Color, Image, Rect};
+use crate::locking::SpinLock;
+use crate::logging::{self, Level};
+use crate::memory::{GiB, PAGE_SIZE};
+use crate::time::Instant;
+use crate::tty::{self, Key, TtyError, MAX_LINE_LEN};
+use crate::{
+    allocator, clock, console, delay, fpsimd, info, irq, paging, print, println, random, thread,
+};
+use core::time::Duration;
+use heapless::Vec;
+
+const MAX_COMMANDS: usize = 32;
+const MAX_ARGS: usize = 8;
+const MAX_GPIO_PINS: usize = 8;
+
+#[derive(Debug)]
+pub enum CommandError {
+    // The arguments don't match the usage of the command
+    Usage,
+    Failed(&'static str),
+}
+
+pub struct Command {
+    pub name: &'static str,
+    // The arguments, e.g. "<addr> [count]"
+    pub usage: &'static str,
+    pub help: &'static str,
+    // Called with the arguments that follow the command name
+    pub run: fn(&[&str]) -> Result<(), CommandError>,
+}
+
+static COMMANDS: SpinLock<Vec<&'static Command, MAX_COMMANDS>> = SpinLock::new(Vec::new());
+
+// The pins claimed by the gpio command, kept until it releases them
+static GPIO_PINS: SpinLock<Vec<GPIOPin, MAX_GPIO_PINS>> = SpinLock::new(Vec::new());
+
+/// Adds a command to the shell. Panics if a command with the same name
+/// exists or there are too many commands.
+pub fn register(command: &'static Command) {
+    let mut commands = COMMANDS.lock();
+    assert!(
+        !commands.iter().any(|c| c.name == command.name),
+        "Shell command {} registered twice",
+        command.name
+    );
+    if commands.push(command).is_err() {
+        panic!("Too many shell commands");
+    }
+}
+
+fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.lock().iter().find(|c| c.name == name).copied()
+}
+
+// Accepts hex numbers with a 0x prefix and decimal numbers
+fn parse_number(s: &str) -> Result<u64, CommandError> {
+    let result = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    result.map_err(|_| CommandError::Usage)
+}
+
+const POWER_DEVICES: [(&str, PowerDevice); 9] = [
+    ("sdcard", PowerDevice::SdCard),
+    ("uart0", PowerDevice::Uart0),
+    ("uart1", PowerDevice::Uart1),
+    ("usb", PowerDevice::UsbHcd),
+    ("i2c0", PowerDevice::I2c0),
+    ("i2c1", PowerDevice::I2c1),
+    ("i2c2", PowerDevice::I2c2),
+    ("spi", PowerDevice::Spi),
+    ("ccp2tx", PowerDevice::Ccp2tx),
+];
+
+const CLOCKS: [(&str, ClockId); 10] = [
+    ("EMMC", ClockId::Emmc),
+    ("UART", ClockId::Uart),
+    ("ARM", ClockId::Arm),
+    ("Core", ClockId::Core),
+    ("V3D", ClockId::V3d),
+    ("H264", ClockId::H264),
+    ("ISP", ClockId::Isp),
+    ("SDRAM", ClockId::Sdram),
+    ("Pixel", ClockId::Pixel),
+    ("PWM", ClockId::Pwm),
+];
+
+const VOLTAGES: [(&str, VoltageId); 4] = [
+    ("core", VoltageId::Core),
+    ("sdram_c", VoltageId::SdramC),
+    ("sdram_p", VoltageId::SdramP),
+    ("sdram_i", VoltageId::SdramI),
+];
+
+// Finds the value for a name in one of the tables above
+fn lookup<T: Copy>(table: &[(&str, T)], name: &str) -> Result<T, CommandError> {
+    table
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|&(_, value)| value)
+        .ok_or(CommandError::Failed(
+            "unknown name, run without arguments to list them",
+        ))
+}
+
+fn mailbox_failed(e: MailboxError) -> CommandError {
+    println!("  {e}");
+    CommandError::Failed("mailbox request failed")
+}
+
+fn debug_failed(e: DebugError) -> CommandError {
+    CommandError::Failed(match e {
+        DebugError::NoFreeSlot => "no free slot",
+        DebugError::InvalidRange => "invalid address or length",
+        DebugError::NotSet => "not set",
+    })
+}
+
+// Physical addresses, MMIO included, are accessed through the linear map
+fn physical_to_virtual(addr: u64) -> Result<AddressVirtual, CommandError> {
+    if addr >= GiB {
+        return Err(CommandError::Failed("address out of range"));
+    }
+    Ok(AddressPhysical::new(addr).as_virtual())
+}
+
+fn help(_args: &[&str]) -> Result<(), CommandError> {
+    const WIDTH: usize = 28;
+    for command in COMMANDS.lock().iter() {
+        // Padding doesn't apply to format_args!(), so it's done by hand
+        let len = command.name.len() + 1 + command.usage.len();
+        print!("  {} {}", command.name, command.usage);
+        if len > WIDTH {
+            // Long usages get the help on a line of its own
+            println!();
+            print!("  {:WIDTH$}", "");
+        } else {
+            print!("{:1$}", "", WIDTH - len);
+        }
+        println!(" {}", command.help);
+    }
+    Ok(())
+}
+
+fn uptime(_args: &[&str]) -> Result<(), CommandError> {
+    let uptime = Instant::now().since_boot();
+    println!("  {}.{:06} s", uptime.as_secs(), uptime.subsec_micros());
+    Ok(())
+}
+
+fn meminfo(_args: &[&str]) -> Result<(), CommandError> {
+    let stats = allocator::stats();
+    let kib = |pages: usize| pages as u64 * PAGE_SIZE / 1024;
+    println!("Total: {:>8} KiB", kib(stats.total_pages));
+    println!(
+        "Used:  {:>8} KiB",
+        kib(stats.total_pages - stats.free_pages)
+    );
+    println!("Free:  {:>8} KiB", kib(stats.free_pages));
+    Ok(())
+}
+
+fn irqstat(_args: &[&str]) -> Result<(), CommandError> {
+    for (irq, count) in irq::gpu_irq_counts().into_iter().enumerate() {
+        if count == 0 {
+            continue;
+        }
+        match irq::GpuIrq::try_from(irq as u32) {
+            Ok(name) => println!("  GPU {irq:2} {count:>10}  {name:?}"),
+            Err(()) => println!("  GPU {irq:2} {count:>10}"),
+        }
+    }
+    Ok(())
+}
+
+fn uartstat(_args: &[&str]) -> Result<(), CommandError> {
+    let mini = uart_mini::rx_stats();
+    let pl011 = uart_pl011::rx_stats();
+    println!("            {:>10} {:>10}", "mini UART", "PL011");
+    println!("  Received: {:>10} {:>10}", mini.received, pl011.received);
+    println!("  Overruns: {:>10} {:>10}", mini.overruns, pl011.overruns);
+    println!("  Dropped:  {:>10} {:>10}", mini.dropped, pl011.dropped);
+    Ok(())
+}
+
+fn dmesg(_args: &[&str]) -> Result<(), CommandError> {
+    logging::dmesg();
+    Ok(())
+}
+
+fn loglevel(args: &[&str]) -> Result<(), CommandError> {
+    let parse_level = |name: &str| Level::from_name(name).ok_or(CommandError::Usage);
+    match args {
+        [] => println!("  {:?}", logging::max_level()),
+        [level] => logging::set_max_level(parse_level(level)?),
+        [module, level] => logging::set_module_level(module, parse_level(level)?)
+            .map_err(|()| CommandError::Failed("too many overrides or path too long"))?,
+        _ => return Err(CommandError::Usage),
+    }
+    Ok(())
+}
+
+fn console(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => println!("  {:?}", console::backend()),
+        ["baud", rate] => {
+            let rate = u32::try_from(parse_number(rate)?).map_err(|_| CommandError::Usage)?;
+            if rate == 0 {
+                return Err(CommandError::Usage);
+            }
+            console::set_baud_rate(rate);
+        }
+        _ => return Err(CommandError::Usage),
+    }
+    Ok(())
+}
+
+fn keys(_args: &[&str]) -> Result<(), CommandError> {
+    println!("  Press keys to see how they decode, Ctrl-C to stop");
+    tty::set_mode(tty::Mode::Raw);
+    loop {
+        match tty::read_key() {
+            // Ctrl-C
+            Key::Char('\x03') => break,
+            key => println!("  {key:?}"),
+        }
+    }
+    tty::set_mode(tty::Mode::Canonical);
+    Ok(())
+}
+
+fn pt(args: &[&str]) -> Result<(), CommandError> {
+    let [va] = args else {
+        return Err(CommandError::Usage);
+    };
+    let va = parse_number(va)?;
+    if va < KSTACK_REGION_START.as_u64() {
+        return Err(CommandError::Failed("not a kernel address"));
+    }
+
+    let descriptors = paging::walk(AddressVirtual::new(va));
+    for (level, descriptor) in descriptors.iter().enumerate() {
+        println!("  L{} {descriptor:#018x}", level + 1);
+    }
+    match descriptors.last() {
+        Some(&pte) if descriptors.len() == 3 && pte & 1 != 0 => {
+            let pa = (pte & 0x0000_ffff_ffff_f000) | (va & (PAGE_SIZE - 1));
+            println!("  {va:#018x} -> {pa:#x}");
+        }
+        _ => println!("  {va:#018x} is not mapped"),
+    }
+    Ok(())
+}
+
+fn peek(args: &[&str]) -> Result<(), CommandError> {
+    let (addr, count) = match args {
+        [addr] => (parse_number(addr)?, 1),
+        [addr, count] => (parse_number(addr)?, parse_number(count)?),
+        _ => return Err(CommandError::Usage),
+    };
+    if !addr.is_multiple_of(4) {
+        return Err(CommandError::Failed("address must be 4-byte aligned"));
+    }
+
+    for i in 0..count {
+        let pa = addr + i * 4;
+        let va = physical_to_virtual(pa)?.as_u64();
+        if !paging::is_readable(va) {
+            return Err(CommandError::Failed("address not mapped"));
+        }
+        // SAFETY: The address is mapped and 32-bit reads are fine for both
+        // RAM and MMIO registers, though reading some registers has side
+        // effects. That's up to the user.
+        let value = unsafe { core::ptr::read_volatile(va as *const u32) };
+        println!("  {pa:#010x}: {value:#010x}");
+    }
+    Ok(())
+}
+
+fn poke(args: &[&str]) -> Result<(), CommandError> {
+    let [addr, value] = args else {
+        return Err(CommandError::Usage);
+    };
+    let addr = parse_number(addr)?;
+    let value = u32::try_from(parse_number(value)?).map_err(|_| CommandError::Usage)?;
+    if !addr.is_multiple_of(4) {
+        return Err(CommandError::Failed("address must be 4-byte aligned"));
+    }
+
+    let va = physical_to_virtual(addr)?.as_u64();
+    if !paging::is_writable(va) {
+        return Err(CommandError::Failed("address not writable"));
+    }
+    // SAFETY: The address is mapped as writable. Whether the write breaks
+    // anything is up to the user.
+    unsafe { core::ptr::write_volatile(va as *mut u32, value) };
+    Ok(())
+}
+
+fn hbreak(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        ["clear", n] => debug::clear_breakpoint(parse_number(n)? as usize).map_err(debug_failed),
+        [addr] => {
+            let n = debug::set_breakpoint(parse_number(addr)?).map_err(debug_failed)?;
+            println!("  Breakpoint {n} set");
+            Ok(())
+        }
+        _ => Err(CommandError::Usage),
+    }
+}
+
+fn watch(args: &[&str]) -> Result<(), CommandError> {
+    let (addr, len, kind) = match args {
+        ["clear", n] => {
+            return debug::clear_watchpoint(parse_number(n)? as usize).map_err(debug_failed)
+        }
+        [addr, len] => (addr, len, WatchKind::Store),
+        [addr, len, "r"] => (addr, len, WatchKind::Load),
+        [addr, len, "w"] => (addr, len, WatchKind::Store),
+        [addr, len, "rw"] => (addr, len, WatchKind::Any),
+        _ => return Err(CommandError::Usage),
+    };
+    let n = debug::set_watchpoint(parse_number(addr)?, parse_number(len)? as usize, kind)
+        .map_err(debug_failed)?;
+    println!("  Watchpoint {n} set");
+    Ok(())
+}
+
+// Calls `f` with the pin, claiming it first if the shell doesn't own it yet
+fn with_gpio_pin(
+    pin: &str,
+    f: impl FnOnce(&GPIOPin) -> Result<(), CommandError>,
+) -> Result<(), CommandError> {
+    let pin = u8::try_from(parse_number(pin)?).map_err(|_| CommandError::Usage)?;
+    let mut pins = GPIO_PINS.lock();
+    if !pins.iter().any(|p| p.number() == pin) {
+        if pins.is_full() {
+            return Err(CommandError::Failed("too many pins, release one"));
+        }
+        match GPIOPin::claim(pin, "shell") {
+            Ok(claimed) => pins.push(claimed).unwrap_or(()),
+            Err(GpioError::InvalidPin) => return Err(CommandError::Failed("invalid pin")),
+            Err(GpioError::AlreadyClaimed(owner)) => {
+                println!("  GPIO{pin} belongs to {owner}");
+                return Err(CommandError::Failed("pin not available"));
+            }
+        }
+    }
+    f(pins.iter().find(|p| p.number() == pin).unwrap())
+}
+
+// Logs the events the gpio command watches for
+fn gpio_event(pin: u8) {
+    info!("GPIO{pin} changed");
+}
+
+fn gpio(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [pin, "release"] => {
+            let pin = parse_number(pin)?;
+            let mut pins = GPIO_PINS.lock();
+            // Dropping the handle gives the pin back
+            pins.retain(|p| p.number() as u64 != pin);
+            Ok(())
+        }
+        [pin] => with_gpio_pin(pin, |pin| {
+            println!(
+                "  GPIO{} {}",
+                pin.number(),
+                if pin.read() { "high" } else { "low" }
+            );
+            Ok(())
+        }),
+        [pin, action] => with_gpio_pin(pin, |pin| {
+            match *action {
+                "in" => pin.select_mode(PinMode::Input),
+                "out" => pin.select_mode(PinMode::Output),
+                "high" => pin.set_high(),
+                "low" => pin.set_low(),
+                "toggle" => pin.toggle(),
+                _ => return Err(CommandError::Usage),
+            }
+            Ok(())
+        }),
+        [pin, "watch", edges] => {
+            let (rising, falling) = match *edges {
+                "rising" => (true, false),
+                "falling" => (false, true),
+                "both" => (true, true),
+                "off" => (false, false),
+                _ => return Err(CommandError::Usage),
+            };
+            with_gpio_pin(pin, |pin| {
+                if !rising && !falling {
+                    pin.remove_event_handler();
+                    return Ok(());
+                }
+                pin.set_event_handler(gpio_event);
+                for (event, enable) in [(Event::RisingEdge, rising), (Event::FallingEdge, falling)]
+                {
+                    if enable {
+                        pin.enable_event(event);
+                    } else {
+                        pin.disable_event(event);
+                    }
+                }
+                Ok(())
+            })
+        }
+        [pin, "pulse", us] => {
+            let us = parse_number(us)?;
+            with_gpio_pin(pin, |pin| {
+                pin.set_high();
+                delay::delay_us(us);
+                pin.set_low();
+                Ok(())
+            })
+        }
+        [pin, "pull", mode] => {
+            let mode = match *mode {
+                "up" => PullMode::Up,
+                "down" => PullMode::Down,
+                "off" => PullMode::Off,
+                _ => return Err(CommandError::Usage),
+            };
+            with_gpio_pin(pin, |pin| {
+                pin.set_pull(mode);
+                Ok(())
+            })
+        }
+        _ => Err(CommandError::Usage),
+    }
+}
+
+fn led(args: &[&str]) -> Result<(), CommandError> {
+    let status = match args {
+        ["on"] => mailbox::OnboardLEDStatus::High,
+        ["off"] => mailbox::OnboardLEDStatus::Low,
+        _ => return Err(CommandError::Usage),
+    };
+    mailbox::set_onboard_led_status(mailbox::OnboardLEDPin::ActivityLED, status)
+        .map_err(|_| CommandError::Failed("mailbox request failed"))
+}
+
+fn fwinfo(_args: &[&str]) -> Result<(), CommandError> {
+    let board = mailbox::get_board_info().map_err(mailbox_failed)?;
+    println!("  Firmware version: {:#x}", board.fw_version);
+    println!("  Board model:      {:#x}", board.model);
+    println!("  Board serial:     {:#x}", board.serial);
+    if let Ok(revision) = mailbox::get_board_revision() {
+        println!("  Board revision:   {revision:#x}");
+    }
+    if let Ok(mac) = mailbox::get_board_mac_address() {
+        println!(
+            "  MAC address:      {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+        );
+    }
+    if let (Ok(temperature), Ok(max)) = (mailbox::get_temperature(), mailbox::get_max_temperature())
+    {
+        println!(
+            "  Temperature:      {}.{} C (max {}.{} C)",
+            temperature / 1000,
+            temperature % 1000 / 100,
+            max / 1000,
+            max % 1000 / 100
+        );
+    }
+    if let Ok(mask) = mailbox::get_dma_channels() {
+        println!("  DMA channels:     {mask:#06x}");
+    }
+    for (name, range) in [
+        ("ARM memory:", board.arm_memory),
+        ("VC memory:", board.videocore_memory),
+    ] {
+        println!(
+            "  {name:<17} {:#x} size {:#x}",
+            range.base().as_u64(),
+            range.size()
+        );
+    }
+    Ok(())
+}
+
+fn clock(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            for (name, id) in CLOCKS {
+                let rates = mailbox::get_clock_rate(id).and_then(|rate| {
+                    let min = mailbox::get_min_clock_rate(id)?;
+                    Ok((rate, min, mailbox::get_max_clock_rate(id)?))
+                });
+                match rates {
+                    Ok((rate, min, max)) => {
+                        println!("  {name:<5} {rate:>10} Hz (min {min} Hz, max {max} Hz)")
+                    }
+                    // Not every firmware knows every clock
+                    Err(e) => println!("  {name:<5} {e}"),
+                }
+            }
+            let turbo = mailbox::get_turbo().map_err(mailbox_failed)?;
+            println!("  Turbo {}", if turbo { "on" } else { "off" });
+        }
+        ["core", rate] => {
+            let rate = u32::try_from(parse_number(rate)?).map_err(|_| CommandError::Usage)?;
+            let rate = clock::set_core_clock_rate(rate).map_err(mailbox_failed)?;
+            println!("  Core clock set to {rate} Hz");
+        }
+        ["turbo", "on"] => clock::set_turbo(true).map_err(mailbox_failed)?,
+        ["turbo", "off"] => clock::set_turbo(false).map_err(mailbox_failed)?,
+        _ => return Err(CommandError::Usage),
+    }
+    Ok(())
+}
+
+fn power(args: &[&str]) -> Result<(), CommandError> {
+    let (device, on) = match args {
+        [] => {
+            for (name, device) in POWER_DEVICES {
+                match mailbox::get_power_state(device) {
+                    Ok(on) => println!("  {name:<7} {}", if on { "on" } else { "off" }),
+                    Err(e) => println!("  {name:<7} {e}"),
+                }
+            }
+            return Ok(());
+        }
+        [device, "on"] => (device, true),
+        [device, "off"] => (device, false),
+        _ => return Err(CommandError::Usage),
+    };
+    let on =
+        mailbox::set_power_state(lookup(&POWER_DEVICES, device)?, on).map_err(mailbox_failed)?;
+    println!("  {device} is {}", if on { "on" } else { "off" });
+    Ok(())
+}
+
+fn voltage(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            for (name, id) in VOLTAGES {
+                let voltage = mailbox::get_voltage(id).map_err(mailbox_failed)?;
+                let min = mailbox::get_min_voltage(id).map_err(mailbox_failed)?;
+                let max = mailbox::get_max_voltage(id).map_err(mailbox_failed)?;
+                println!("  {name:<8} {voltage:>8} uV (min {min} uV, max {max} uV)");
+            }
+        }
+        [name, microvolts] => {
+            let microvolts =
+                i32::try_from(parse_number(microvolts)?).map_err(|_| CommandError::Usage)?;
+            let voltage = mailbox::set_voltage(lookup(&VOLTAGES, name)?, microvolts)
+                .map_err(mailbox_failed)?;
+            println!("  {name} set to {voltage} uV");
+        }
+        _ => return Err(CommandError::Usage),
+    }
+    Ok(())
+}
+
+const SPRITE_SIZE: u32 = 16;
+
+// A ball with transparent corners, for the graphics demo
+const SPRITE: [u32; (SPRITE_SIZE * SPRITE_SIZE) as usize] = {
+    let mut pixels = [0; (SPRITE_SIZE * SPRITE_SIZE) as usize];
+    let mut i = 0;
+    while i < pixels.len() {
+        let x = (i as i32 % SPRITE_SIZE as i32) * 2 - (SPRITE_SIZE as i32 - 1);
+        let y = (i as i32 / SPRITE_SIZE as i32) * 2 - (SPRITE_SIZE as i32 - 1);
+        if x * x + y * y <= (SPRITE_SIZE * SPRITE_SIZE) as i32 {
+            pixels[i] = 0xFFFF_D700;
+        }
+        i += 1;
+    }
+    pixels
+};
+
+fn gfxdemo(args: &[&str]) -> Result<(), CommandError> {
+    let frames = match args {
+        [] => 300,
+        [frames] => parse_number(frames)?,
+        _ => return Err(CommandError::Usage),
+    };
+    let Some(fb) = framebuffer::get() else {
+        return Err(CommandError::Failed("no framebuffer"));
+    };
+    let failed = |e: framebuffer::FramebufferError| {
+        println!("  {e}");
+        CommandError::Failed("framebuffer request failed")
+    };
+    let sprite = Image {
+        width: SPRITE_SIZE,
+        height: SPRITE_SIZE,
+        pixels: &SPRITE,
+    };
+    println!(
+        "  {:?}, {} buffers, Ctrl-C stops",
+        fb.buffer(0).format(),
+        fb.buffers()
+    );
+
+    let start = Instant::now();
+    let mut frame = 0;
+    while frame < frames && !tty::take_interrupt() {
+        let surface = framebuffer::back_buffer().map_err(failed)?;
+        let (width, height) = (surface.width() as i32, surface.height() as i32);
+        // Keeps the coordinates below from overflowing
+        let t = (frame % 4096) as i32;
+
+        surface.clear(Color::BLACK);
+        surface.draw_rect(surface.bounds(), Color::WHITE);
+        surface.fill_rect(Rect::new(t % width - 50, height / 4, 100, 50), Color::BLUE);
+        surface.draw_line(0, 0, (t * 4) % width, height - 1, Color::RED);
+        surface.draw_line(
+            width - 1,
+            0,
+            width - 1 - (t * 4) % width,
+            height - 1,
+            Color::GREEN,
+        );
+        surface.blit(&sprite, (t * 3) % width, height / 2 + (t % 64 - 32).abs());
+        framebuffer::flip().map_err(failed)?;
+
+        frame += 1;
+        // Let the bottom halves run, Ctrl-C arrives through them
+        thread::yield_now();
+    }
+
+    let elapsed = start.elapsed();
+    framebuffer::show_console().map_err(failed)?;
+    println!(
+        "  {frame} frames in {}.{:03} s",
+        elapsed.as_secs(),
+        elapsed.subsec_millis()
+    );
+    Ok(())
+}
+
+fn rand(args: &[&str]) -> Result<(), CommandError> {
+    let count = match args {
+        [] => 16,
+        ["u64"] => {
+            println!("  {:#018x}", random::next_u64());
+            return Ok(());
+        }
+        [count] => parse_number(count)?,
+        _ => return Err(CommandError::Usage),
+    };
+    let mut bytes = [0; 16];
+    let mut left = count;
+    while left > 0 {
+        let line = &mut bytes[..left.min(16) as usize];
+        random::fill_bytes(line);
+        print!(" ");
+        for byte in line.iter() {
+            print!(" {byte:02x}");
+        }
+        println!();
+        left -= line.len() as u64;
+    }
+    Ok(())
+}
+
+fn ps(_args: &[&str]) -> Result<(), CommandError> {
+    let current = thread::current();
+    println!("  ID  STATE    NAME");
+    for thread in thread::list() {
+        let marker = if thread.id == current { '*' } else { ' ' };
+        let state = match thread.state {
+            thread::State::Running => "running",
+            thread::State::Ready => "ready",
+            thread::State::Dead => "dead",
+        };
+        println!(" {marker}{:2}  {state:<8} {}", thread.id, thread.name);
+    }
+    Ok(())
+}
+
+fn fptest(_args: &[&str]) -> Result<(), CommandError> {
+    match fpsimd::self_test() {
+        Ok(true) => println!("  FP/SIMD state is kept per thread"),
+        Ok(false) => return Err(CommandError::Failed("FP/SIMD state leaked between threads")),
+        Err(_) => return Err(CommandError::Failed("can't spawn the test thread")),
+    }
+    Ok(())
+}
+
+fn watchdog(args: &[&str]) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            let left = pm::watchdog_time_left();
+            if left.is_zero() {
+                println!("  Stopped");
+            } else {
+                println!(
+                    "  Expires in {}.{:03} s",
+                    left.as_secs(),
+                    left.subsec_millis()
+                );
+            }
+        }
+        ["start", seconds] => {
+            let timeout = pm::watchdog_start(Duration::from_secs(parse_number(seconds)?));
+            println!(
+                "  Started with a timeout of {}.{:03} s",
+                timeout.as_secs(),
+                timeout.subsec_millis()
+            );
+        }
+        ["stop"] => pm::watchdog_stop(),
+        _ => return Err(CommandError::Usage),
+    }
+    Ok(())
+}
+
+fn reboot(_args: &[&str]) -> Result<(), CommandError> {
+    println!("Rebooting...");
+    pm::reboot();
+}
+
+fn poweroff(_args: &[&str]) -> Result<(), CommandError> {
+    println!("Halting, the board can be unplugged now");
+    pm::poweroff();
+}
+
+static BUILTIN_COMMANDS: [Command; 27] = [
+    Command {
+        name: "help",
+        usage: "",
+        help: "List the commands",
+        run: help,
+    },
+    Command {
+        name: "uptime",
+        usage: "",
+        help: "Show the time since the counter started",
+        run: uptime,
+    },
+    Command {
+        name: "meminfo",
+        usage: "",
+        help: "Show page allocator usage",
+        run: meminfo,
+    },
+    Command {
+        name: "irqstat",
+        usage: "",
+        help: "Show how many times each IRQ was handled",
+        run: irqstat,
+    },
+    Command {
+        name: "uartstat",
+        usage: "",
+        help: "Show the RX counters of both UARTs",
+        run: uartstat,
+    },
+    Command {
+        name: "dmesg",
+        usage: "",
+        help: "Print the kernel log buffer",
+        run: dmesg,
+    },
+    Command {
+        name: "loglevel",
+        usage: "[module] [error|warn|info|debug|trace]",
+        help: "Show or set the log level, e.g. for mythos::paging",
+        run: loglevel,
+    },
+    Command {
+        name: "console",
+        usage: "[baud <rate>]",
+        help: "Show the console UART or change its baud rate",
+        run: console,
+    },
+    Command {
+        name: "keys",
+        usage: "",
+        help: "Print the keys read from the console in raw mode",
+        run: keys,
+    },
+    Command {
+        name: "pt",
+        usage: "<va>",
+        help: "Walk the page tables for a virtual address",
+        run: pt,
+    },
+    Command {
+        name: "peek",
+        usage: "<pa> [count]",
+        help: "Read 32-bit words from physical or MMIO addresses",
+        run: peek,
+    },
+    Command {
+        name: "poke",
+        usage: "<pa> <value>",
+        help: "Write a 32-bit word to a physical or MMIO address",
+        run: poke,
+    },
+    Command {
+        name: "hbreak",
+        usage: "<va> | clear <n>",
+        help: "Set or clear a hardware breakpoint",
+        run: hbreak,
+    },
+    Command {
+        name: "watch",
+        usage: "<va> <len> [r|w|rw] | clear <n>",
+        help: "Set or clear a watchpoint, on writes by default",
+        run: watch,
+    },
+    Command {
+        name: "gpio",
+        usage: "<pin> [in|out|high|low|toggle|pulse <us>|pull up|down|off|watch <edges>|release]",
+        help: "Read, drive or watch a GPIO pin (edges: rising|falling|both|off)",
+        run: gpio,
+    },
+    Command {
+        name: "led",
+        usage: "on|off",
+        help: "Switch the activity LED",
+        run: led,
+    },
+    Command {
+        name: "fwinfo",
+        usage: "",
+        help: "Show information reported by the firmware",
+        run: fwinfo,
+    },
+    Command {
+        name: "clock",
+        usage: "[core <hz> | turbo on|off]",
+        help: "Show or change the clock rates",
+        run: clock,
+    },
+    Command {
+        name: "power",
+        usage: "[<device> on|off]",
+        help: "Show or switch the power of the devices the firmware manages",
+        run: power,
+    },
+    Command {
+        name: "voltage",
+        usage: "[<id> <uv>]",
+        help: "Show or set the voltages",
+        run: voltage,
+    },
+    Command {
+        name: "gfxdemo",
+        usage: "[frames]",
+        help: "Draw a double buffered animation on the framebuffer",
+        run: gfxdemo,
+    },
+    Command {
+        name: "rand",
+        usage: "[count | u64]",
+        help: "Print random bytes or a random 64-bit number",
+        run: rand,
+    },
+    Command {
+        name: "ps",
+        usage: "",
+        help: "List the kernel threads",
+        run: ps,
+    },
+    Command {
+        name: "fptest",
+        usage: "",
+        help: "Check that threads keep their own FP/SIMD registers",
+        run: fptest,
+    },
+    Command {
+        name: "watchdog",
+        usage: "[start <seconds> | stop]",
+        help: "Show, start or stop the watchdog, which the kernel pets",
+        run: watchdog,
+    },
+    Command {
+        name: "reboot",
+        usage: "",
+        help: "Reset the board",
+        run: reboot,
+    },
+    Command {
+        name: "poweroff",
+        usage: "",
+        help: "Halt the board",
+        run: poweroff,
+    },
+];
+
+fn run_line(line: &str) {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return;
+    };
+    let mut args: Vec<&str, MAX_ARGS> = Vec::new();
+    for word in words {
+        if args.push(word).is_err() {
+            println!("Too many arguments");
+            return;
+        }
+    }
+
+    let Some(command) = find(name) else {
+        println!("Unknown command: {name}, try help");
+        return;
+    };
+
+    match (command.run)(&args) {
+        Ok(()) => {}
+        Err(CommandError::Usage) => println!("Usage: {} {}", command.name, command.usage),
+        Err(CommandError::Failed(reason)) => println!("{}: {reason}", command.name),
+    }
+}
+
+fn shell_main() {
+    let mut buf = [0; MAX_LINE_LEN];
+    loop {
+        print!("mythos> ");
+        match tty::read_line(&mut buf) {
+            // The TTY only hands out complete lines so this is valid UTF-8
+            Ok(len) => run_line(core::str::from_utf8(&buf[..len]).unwrap_or("")),
+            Err(TtyError::Interrupted) => {}
+        }
+    }
+}
+
+/// Registers the built-in commands and starts the shell thread
+pub fn init() {
+    for command in &BUILTIN_COMMANDS {
+        register(command);
+    }
+    thread::spawn("shell", shell_main).unwrap();
+}
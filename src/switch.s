@@ -0,0 +1,36 @@
+// NOTE: The CpuContext layout is defined in thread.rs
+.section .text
+
+// Saves the callee-saved registers, the SP and the LR of the current thread
+// to the context pointed to by x0 and loads the ones pointed to by x1. The
+// caller-saved registers have already been saved by the compiler since this
+// is a regular function call. Returns to the LR of the next thread.
+.global cpu_switch_to
+cpu_switch_to:
+	mov x9, sp
+	stp x19, x20, [x0, #16 * 0]
+	stp x21, x22, [x0, #16 * 1]
+	stp x23, x24, [x0, #16 * 2]
+	stp x25, x26, [x0, #16 * 3]
+	stp x27, x28, [x0, #16 * 4]
+	stp x29, x30, [x0, #16 * 5]
+	str x9, [x0, #16 * 6]
+
+	ldp x19, x20, [x1, #16 * 0]
+	ldp x21, x22, [x1, #16 * 1]
+	ldp x23, x24, [x1, #16 * 2]
+	ldp x25, x26, [x1, #16 * 3]
+	ldp x27, x28, [x1, #16 * 4]
+	ldp x29, x30, [x1, #16 * 5]
+	ldr x9, [x1, #16 * 6]
+	mov sp, x9
+	ret
+
+// New threads start here the first time they are switched to, with the
+// entry point in x19. Zero the FP and LR so that unwinding stops here.
+.global thread_trampoline
+thread_trampoline:
+	mov x0, x19
+	mov x29, xzr
+	mov x30, xzr
+	bl thread_main
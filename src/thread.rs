@@ -0,0 +1,226 @@
+// Kernel threads with cooperative scheduling: a thread keeps running until it
+// calls yield_now() or exit(). Threads are kept in a fixed size table and are
+// identified by their index in it. Each CPU starts out running a boot thread
+// on the stack allocated for it by kstack::allocate_cpu_stack().
+//
+// NOTE: Only the boot CPU runs threads for now. Switching drops the scheduler
+// lock before the context of the previous thread has been saved, which is
+// only fine as long as no other CPU can pick that thread up in the meantime.
+
+use crate::allocator::AllocError;
+use crate::cpu::{self, NUM_CPUS};
+use crate::fpsimd::{self, FpSimdState};
+use crate::kstack::KernelStack;
+use crate::locking::SpinLock;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use heapless::Vec;
+
+global_asm!(include_str!("switch.s"));
+
+extern "C" {
+    fn cpu_switch_to(prev: *mut CpuContext, next: *const CpuContext);
+    fn thread_trampoline();
+}
+
+const MAX_THREADS: usize = 32;
+
+// The callee-saved registers of a thread that isn't running.
+// NOTE: The layout of this struct is hardcoded in switch.s
+#[repr(C)]
+#[derive(Default)]
+struct CpuContext {
+    // x19 to x28
+    regs: [u64; 10],
+    fp: u64,
+    lr: u64,
+    sp: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Ready,
+    Dead,
+}
+
+struct Thread {
+    name: &'static str,
+    state: State,
+    context: CpuContext,
+    fpsimd: FpSimdState,
+    // None for the boot threads, whose stacks are owned by kstack
+    stack: Option<KernelStack>,
+}
+
+static THREADS: SpinLock<[Option<Thread>; MAX_THREADS]> =
+    SpinLock::new([const { None }; MAX_THREADS]);
+
+// The thread each CPU is running
+static CURRENT: [AtomicUsize; NUM_CPUS] = [const { AtomicUsize::new(0) }; NUM_CPUS];
+
+// The boot thread of each CPU, which never exits
+static BOOT_THREADS: [AtomicUsize; NUM_CPUS] = [const { AtomicUsize::new(0) }; NUM_CPUS];
+
+fn add_thread(thread: Thread) -> Result<usize, AllocError> {
+    let mut threads = THREADS.lock();
+    let id = threads
+        .iter()
+        .position(|t| t.is_none())
+        .ok_or(AllocError::OutOfMemory)?;
+    threads[id] = Some(thread);
+    Ok(id)
+}
+
+/// Turns the code running on the current CPU into its boot thread
+pub fn init() {
+    let id = add_thread(Thread {
+        name: "main",
+        state: State::Running,
+        context: CpuContext::default(),
+        fpsimd: FpSimdState::new(),
+        stack: None,
+    })
+    .unwrap();
+
+    CURRENT[cpu::current_id()].store(id, Ordering::Relaxed);
+    BOOT_THREADS[cpu::current_id()].store(id, Ordering::Relaxed);
+}
+
+/// Returns the ID of the thread running on the current CPU
+pub fn current() -> usize {
+    CURRENT[cpu::current_id()].load(Ordering::Relaxed)
+}
+
+/// Creates a new thread that will start running `entry` the next time it's
+/// scheduled. The thread exits when `entry` returns.
+pub fn spawn(name: &'static str, entry: fn()) -> Result<usize, AllocError> {
+    let stack = KernelStack::new(name)?;
+
+    let mut context = CpuContext::default();
+    // thread_trampoline expects the entry point in x19
+    context.regs[0] = entry as *const () as u64;
+    context.lr = thread_trampoline as *const () as u64;
+    context.sp = stack.top().as_u64();
+
+    add_thread(Thread {
+        name,
+        state: State::Ready,
+        context,
+        fpsimd: FpSimdState::new(),
+        stack: Some(stack),
+    })
+}
+
+pub struct ThreadInfo {
+    pub id: usize,
+    pub name: &'static str,
+    pub state: State,
+}
+
+/// Returns the threads that exist, including dead ones not reaped yet
+pub fn list() -> Vec<ThreadInfo, MAX_THREADS> {
+    let threads = THREADS.lock();
+    let mut list = Vec::new();
+    for (id, thread) in threads.iter().enumerate() {
+        if let Some(thread) = thread {
+            let _ = list.push(ThreadInfo {
+                id,
+                name: thread.name,
+                state: thread.state,
+            });
+        }
+    }
+    list
+}
+
+/// Switches to the next ready thread, if there is one
+pub fn yield_now() {
+    let cpu = cpu::current_id();
+    let prev = CURRENT[cpu].load(Ordering::Relaxed);
+
+    let mut threads = THREADS.lock();
+    // Round robin, starting from the thread after the current one
+    let Some(next) = (1..MAX_THREADS)
+        .map(|i| (prev + i) % MAX_THREADS)
+        .find(|&id| matches!(&threads[id], Some(t) if t.state == State::Ready))
+    else {
+        return;
+    };
+
+    let prev_thread = threads[prev].as_mut().unwrap();
+    if prev_thread.state == State::Running {
+        prev_thread.state = State::Ready;
+    }
+    let prev_context = &raw mut prev_thread.context;
+
+    let next_thread = threads[next].as_mut().unwrap();
+    next_thread.state = State::Running;
+    let next_context = &raw const next_thread.context;
+
+    CURRENT[cpu].store(next, Ordering::Relaxed);
+    fpsimd::on_switch(next);
+    drop(threads);
+
+    // SAFETY: Both contexts live in THREADS and the slots can't be freed
+    // before the switch completes since neither thread is dead yet or, if
+    // prev is dead, it's only reaped by the thread we are switching to.
+    unsafe { cpu_switch_to(prev_context, next_context) };
+
+    // We are back, possibly after a switch away from a thread that exited
+    reap_dead_threads();
+}
+
+/// Terminates the current thread. The boot thread can't exit since the CPU
+/// falls back to it, it keeps running the other threads instead.
+pub fn exit() -> ! {
+    let id = current();
+    if id == BOOT_THREADS[cpu::current_id()].load(Ordering::Relaxed) {
+        loop {
+            yield_now();
+            core::hint::spin_loop();
+        }
+    }
+    THREADS.lock()[id].as_mut().unwrap().state = State::Dead;
+
+    // There is always somewhere to go: the boot thread is ready whenever
+    // another thread runs
+    yield_now();
+    unreachable!("Dead thread {id} was scheduled again");
+}
+
+fn reap_dead_threads() {
+    loop {
+        let stack = {
+            let mut threads = THREADS.lock();
+            let Some(id) = (0..MAX_THREADS).find(|&id| {
+                matches!(&threads[id], Some(t) if t.state == State::Dead)
+                    && !CURRENT.iter().any(|c| c.load(Ordering::Relaxed) == id)
+            }) else {
+                return;
+            };
+            fpsimd::on_exit(id);
+            threads[id].take().unwrap().stack
+        };
+
+        // The stack is unmapped and freed outside of the lock
+        drop(stack);
+    }
+}
+
+/// Runs `f` on the saved FP/SIMD state of the given thread, if it still exists
+pub fn with_fpsimd_state(id: usize, f: impl FnOnce(&mut FpSimdState)) {
+    if let Some(thread) = THREADS.lock()[id].as_mut() {
+        f(&mut thread.fpsimd);
+    }
+}
+
+// The first Rust function a new thread runs, called by thread_trampoline.
+// Only Rust code passes `entry` around so the ABI of fn() doesn't matter.
+#[no_mangle]
+#[allow(improper_ctypes_definitions)]
+extern "C" fn thread_main(entry: fn()) -> ! {
+    reap_dead_threads();
+    entry();
+    exit();
+}
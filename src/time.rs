@@ -0,0 +1,115 @@
+// Monotonic time based on the ARM generic timer. CNTPCT_EL0 counts at the
+// frequency in CNTFRQ_EL0, which the firmware sets up and which doesn't
+// change, so it's read once at boot.
+//
+// Conversions between ticks and Durations split the ticks into whole seconds
+// and a remainder, so they don't overflow for any counter value.
+
+use aarch64_cpu::asm::barrier;
+use aarch64_cpu::registers::{CNTFRQ_EL0, CNTPCT_EL0};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use tock_registers::interfaces::Readable;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// Ticks per second, 0 until init()
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the counter frequency. Must be called once at boot.
+pub fn init() {
+    let frequency = CNTFRQ_EL0.get();
+    assert!(frequency != 0, "CNTFRQ_EL0 isn't set");
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+}
+
+/// Returns the counter frequency in Hz
+pub fn frequency() -> u64 {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        // Log messages can be timestamped before init()
+        0 => CNTFRQ_EL0.get().max(1),
+        frequency => frequency,
+    }
+}
+
+fn ticks() -> u64 {
+    // Without the ISB the read could happen before earlier instructions
+    barrier::isb(barrier::SY);
+    CNTPCT_EL0.get()
+}
+
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let frequency = frequency();
+    let secs = ticks / frequency;
+    // The remainder is below the frequency, which is far below 2^64 / 10^9
+    let nanos = (ticks % frequency) * NANOS_PER_SEC / frequency;
+    Duration::new(secs, nanos as u32)
+}
+
+/// Rounds up, so that waiting for the ticks takes at least the duration.
+/// Saturates at u64::MAX.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let frequency = frequency();
+    let nanos = duration.subsec_nanos() as u64;
+    duration
+        .as_secs()
+        .saturating_mul(frequency)
+        .saturating_add((nanos * frequency).div_ceil(NANOS_PER_SEC))
+}
+
+/// A point in time, measured by the monotonic counter
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(ticks())
+    }
+
+    /// Returns the counter value at this instant
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    /// Returns the time since the counter started, usually at power on
+    pub fn since_boot(&self) -> Duration {
+        ticks_to_duration(self.0)
+    }
+
+    /// Returns zero if `earlier` is later than self
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        ticks_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0.checked_add(duration_to_ticks(duration)).map(Instant)
+    }
+
+    /// Returns the last instant the counter can reach if the result doesn't
+    /// fit in it, which for waiting is as good as forever
+    pub fn saturating_add(&self, duration: Duration) -> Instant {
+        Instant(self.0.saturating_add(duration_to_ticks(duration)))
+    }
+}
+
+impl core::ops::Add<Duration> for Instant {
+    type Output = Instant;
+
+    /// Panics if the result doesn't fit in the counter
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl core::ops::Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
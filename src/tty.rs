@@ -0,0 +1,312 @@
+// The TTY sits between the UART drivers and the code reading from the
+// console. The RX bottom halves feed it every received character and it
+// either edits them into lines or passes them on as they are.
+//
+// In canonical mode input is echoed and collected into a line that can be
+// edited with backspace, the arrow keys, Home/End, Ctrl-U (kill line) and
+// Ctrl-W (erase word). Enter completes the line and Ctrl-C discards it and
+// signals an interrupt. In raw mode every key is queued without echo.
+//
+// Output doesn't go through here, the console writes to the UART directly.
+
+use crate::locking::SpinLock;
+use crate::{print, thread};
+use core::sync::atomic::{AtomicBool, Ordering};
+use heapless::{Deque, String, Vec};
+
+pub const MAX_LINE_LEN: usize = 128;
+
+// Completed lines nobody has read yet. The oldest is dropped when full.
+const MAX_PENDING_LINES: usize = 4;
+// Keys received in raw mode nobody has read yet. New keys are dropped when full.
+const MAX_PENDING_KEYS: usize = 64;
+
+const CTRL_C: char = '\x03';
+const CTRL_H: char = '\x08';
+const CTRL_U: char = '\x15';
+const CTRL_W: char = '\x17';
+const ESC: char = '\x1b';
+const DEL: char = '\x7f';
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Canonical,
+    Raw,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    Delete,
+}
+
+#[derive(Debug)]
+pub enum TtyError {
+    // Ctrl-C was pressed while waiting for a line
+    Interrupted,
+}
+
+// Where we are in an escape sequence
+#[derive(Clone, Copy)]
+enum Escape {
+    None,
+    // After ESC
+    Start,
+    // After ESC [ or ESC O, with the numeric parameter so far
+    Sequence(u8),
+}
+
+struct Tty {
+    mode: Mode,
+    escape: Escape,
+    // Whether the last character was a CR, so that a CR LF pair is one Enter
+    after_cr: bool,
+    // The line being edited and the position of the cursor in it
+    line: Vec<char, MAX_LINE_LEN>,
+    cursor: usize,
+    lines: Deque<String<MAX_LINE_LEN>, MAX_PENDING_LINES>,
+    keys: Deque<Key, MAX_PENDING_KEYS>,
+}
+
+static TTY: SpinLock<Tty> = SpinLock::new(Tty {
+    mode: Mode::Canonical,
+    escape: Escape::None,
+    after_cr: false,
+    line: Vec::new(),
+    cursor: 0,
+    lines: Deque::new(),
+    keys: Deque::new(),
+});
+
+// Set by Ctrl-C in canonical mode
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Moves the terminal cursor left or right by n columns
+fn move_cursor_left(n: usize) {
+    if n > 0 {
+        print!("\x1b[{n}D");
+    }
+}
+
+fn move_cursor_right(n: usize) {
+    if n > 0 {
+        print!("\x1b[{n}C");
+    }
+}
+
+impl Tty {
+    // Turns characters into keys, returns None while in an escape sequence
+    fn decode(&mut self, c: char) -> Option<Key> {
+        match (self.escape, c) {
+            (Escape::None, ESC) => {
+                self.escape = Escape::Start;
+                None
+            }
+            (Escape::None, c) => Some(Key::Char(c)),
+            (Escape::Start, '[' | 'O') => {
+                self.escape = Escape::Sequence(0);
+                None
+            }
+            (Escape::Sequence(param), '0'..='9') => {
+                let digit = c as u8 - b'0';
+                self.escape = Escape::Sequence(param.saturating_mul(10).saturating_add(digit));
+                None
+            }
+            (Escape::Sequence(param), _) => {
+                self.escape = Escape::None;
+                match (c, param) {
+                    ('A', _) => Some(Key::Up),
+                    ('B', _) => Some(Key::Down),
+                    ('C', _) => Some(Key::Right),
+                    ('D', _) => Some(Key::Left),
+                    ('H', _) | ('~', 1 | 7) => Some(Key::Home),
+                    ('F', _) | ('~', 4 | 8) => Some(Key::End),
+                    ('~', 3) => Some(Key::Delete),
+                    // Unsupported sequences are dropped
+                    _ => None,
+                }
+            }
+            // A lone ESC followed by something that doesn't start a sequence
+            (Escape::Start, _) => {
+                self.escape = Escape::None;
+                None
+            }
+        }
+    }
+
+    fn receive(&mut self, c: char) {
+        let Some(key) = self.decode(c) else {
+            return;
+        };
+
+        match self.mode {
+            Mode::Raw => {
+                let _ = self.keys.push_back(key);
+            }
+            Mode::Canonical => self.edit(key),
+        }
+    }
+
+    // Redraws the line from the cursor onwards, clearing `erased` more
+    // columns, and leaves the terminal cursor where it was
+    fn redraw_tail(&self, erased: usize) {
+        let tail = &self.line[self.cursor..];
+        for &c in tail {
+            print!("{c}");
+        }
+        for _ in 0..erased {
+            print!(" ");
+        }
+        move_cursor_left(tail.len() + erased);
+    }
+
+    // Removes n characters before the cursor
+    fn erase_before_cursor(&mut self, n: usize) {
+        if n == 0 {
+            return;
+        }
+        let start = self.cursor - n;
+        for _ in 0..n {
+            self.line.remove(start);
+        }
+        move_cursor_left(n);
+        self.cursor = start;
+        self.redraw_tail(n);
+    }
+
+    fn edit(&mut self, key: Key) {
+        let after_cr = core::mem::replace(&mut self.after_cr, key == Key::Char('\r'));
+
+        match key {
+            Key::Char('\n') if after_cr => {}
+            Key::Char('\r' | '\n') => {
+                print!("\n");
+                let line: String<MAX_LINE_LEN> = self.line.iter().collect();
+                if self.lines.is_full() {
+                    self.lines.pop_front();
+                }
+                let _ = self.lines.push_back(line);
+                self.line.clear();
+                self.cursor = 0;
+            }
+            Key::Char(CTRL_C) => {
+                print!("^C\n");
+                self.line.clear();
+                self.cursor = 0;
+                INTERRUPTED.store(true, Ordering::Relaxed);
+            }
+            Key::Char(DEL | CTRL_H) => self.erase_before_cursor(self.cursor.min(1)),
+            Key::Char(CTRL_U) => {
+                move_cursor_left(self.cursor);
+                print!("\x1b[K");
+                self.line.clear();
+                self.cursor = 0;
+            }
+            Key::Char(CTRL_W) => {
+                // Spaces before the cursor and then the word before them
+                let before = &self.line[..self.cursor];
+                let spaces = before.iter().rev().take_while(|c| **c == ' ').count();
+                let word = before[..before.len() - spaces]
+                    .iter()
+                    .rev()
+                    .take_while(|c| **c != ' ')
+                    .count();
+                self.erase_before_cursor(spaces + word);
+            }
+            // Other control characters aren't inserted
+            Key::Char(c) if c.is_control() => {}
+            Key::Char(c) => {
+                // The line must fit in a String<MAX_LINE_LEN> when encoded
+                let encoded_len: usize = self.line.iter().map(|c| c.len_utf8()).sum();
+                if encoded_len + c.len_utf8() > MAX_LINE_LEN {
+                    return;
+                }
+                self.line.insert(self.cursor, c).unwrap();
+                self.cursor += 1;
+                print!("{c}");
+                self.redraw_tail(0);
+            }
+            Key::Left if self.cursor > 0 => {
+                self.cursor -= 1;
+                move_cursor_left(1);
+            }
+            Key::Right if self.cursor < self.line.len() => {
+                self.cursor += 1;
+                move_cursor_right(1);
+            }
+            Key::Home => {
+                move_cursor_left(self.cursor);
+                self.cursor = 0;
+            }
+            Key::End => {
+                move_cursor_right(self.line.len() - self.cursor);
+                self.cursor = self.line.len();
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+                self.redraw_tail(1);
+            }
+            // There is no history to go through
+            Key::Up | Key::Down | Key::Left | Key::Right | Key::Delete => {}
+        }
+    }
+}
+
+/// Called by the UART RX bottom halves for every received character
+pub fn receive(c: char) {
+    TTY.lock().receive(c);
+}
+
+pub fn set_mode(mode: Mode) {
+    let mut tty = TTY.lock();
+    tty.mode = mode;
+    tty.escape = Escape::None;
+}
+
+/// Returns whether Ctrl-C was pressed since the last call, so that long
+/// running commands can be stopped
+pub fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::Relaxed)
+}
+
+// Input arrives through the bottom halves, which the boot thread runs. So
+// this only works on other threads.
+fn wait_for_input() {
+    thread::yield_now();
+}
+
+/// Waits for a line in canonical mode and copies it to `buf`, without the
+/// line terminator. Returns the length of the line, which is truncated if it
+/// doesn't fit. Must not be called from the boot thread.
+pub fn read_line(buf: &mut [u8]) -> Result<usize, TtyError> {
+    // Only a Ctrl-C pressed while waiting interrupts the read
+    INTERRUPTED.store(false, Ordering::Relaxed);
+
+    loop {
+        if let Some(line) = TTY.lock().lines.pop_front() {
+            let len = line.len().min(buf.len());
+            buf[..len].copy_from_slice(&line.as_bytes()[..len]);
+            return Ok(len);
+        }
+        if take_interrupt() {
+            return Err(TtyError::Interrupted);
+        }
+        wait_for_input();
+    }
+}
+
+/// Waits for a key in raw mode. Must not be called from the boot thread.
+pub fn read_key() -> Key {
+    loop {
+        if let Some(key) = TTY.lock().keys.pop_front() {
+            return key;
+        }
+        wait_for_input();
+    }
+}
@@ -0,0 +1,234 @@
+// Generates the kernel symbol table used to symbolize backtraces and writes
+// it into the space reserved for it in the kernel ELF binary (the region
+// between the __ksyms_start and __ksyms_end symbols). Patching the binary in
+// place means the kernel doesn't have to be linked twice and no addresses
+// change.
+//
+// The table layout, all values little-endian, is:
+//   magic: [u8; 4] = b"KSYM"
+//   count: u32
+//   entries: [{ offset: u32, size: u32, name: u32 }; count], sorted by offset
+//   names: NUL-terminated strings
+//
+// Entry offsets are relative to the start of the .text section and name
+// offsets are relative to the start of the names area. A size of 0 means
+// the symbol extends up to the next one.
+//
+// The reserved space is KSYMS_SIZE in src/backtrace.rs. A warning is printed
+// once the table fills more than WARN_PERCENT of it, raise KSYMS_SIZE then
+// rather than waiting for the build to fail.
+
+use std::process::ExitCode;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const WARN_PERCENT: usize = 75;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_NOTYPE: u8 = 0;
+const STT_FUNC: u8 = 2;
+
+struct Section {
+    name: u32,
+    kind: u32,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+}
+
+struct Symbol {
+    name: String,
+    kind: u8,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
+
+struct Elf<'a> {
+    data: &'a [u8],
+    sections: Vec<Section>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_cstr(data: &[u8], offset: usize) -> &str {
+    let len = data[offset..].iter().position(|&b| b == 0).unwrap();
+    std::str::from_utf8(&data[offset..offset + len]).unwrap_or("")
+}
+
+impl<'a> Elf<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self, String> {
+        // ELFCLASS64, ELFDATA2LSB
+        if data.len() < 64 || &data[0..4] != b"\x7fELF" || data[4] != 2 || data[5] != 1 {
+            return Err("not a 64-bit little-endian ELF file".into());
+        }
+
+        let shoff = read_u64(data, 0x28) as usize;
+        let shentsize = read_u16(data, 0x3a) as usize;
+        let shnum = read_u16(data, 0x3c) as usize;
+
+        let sections = (0..shnum)
+            .map(|i| {
+                let sh = shoff + i * shentsize;
+                Section {
+                    name: read_u32(data, sh),
+                    kind: read_u32(data, sh + 4),
+                    addr: read_u64(data, sh + 16),
+                    offset: read_u64(data, sh + 24),
+                    size: read_u64(data, sh + 32),
+                    link: read_u32(data, sh + 40),
+                }
+            })
+            .collect();
+
+        Ok(Elf { data, sections })
+    }
+
+    fn section_by_name(&self, name: &str) -> Option<(usize, &Section)> {
+        let shstrndx = read_u16(self.data, 0x3e) as usize;
+        let shstrtab = &self.sections[shstrndx];
+        self.sections.iter().enumerate().find(|(_, s)| {
+            read_cstr(self.data, shstrtab.offset as usize + s.name as usize) == name
+        })
+    }
+
+    fn symbols(&self) -> Result<Vec<Symbol>, String> {
+        let symtab = self
+            .sections
+            .iter()
+            .find(|s| s.kind == SHT_SYMTAB)
+            .ok_or("no symbol table, is the binary stripped?")?;
+        let strtab = &self.sections[symtab.link as usize];
+
+        const SYM_SIZE: usize = 24;
+        let count = symtab.size as usize / SYM_SIZE;
+        Ok((0..count)
+            .map(|i| {
+                let sym = symtab.offset as usize + i * SYM_SIZE;
+                let name = read_u32(self.data, sym) as usize;
+                Symbol {
+                    name: read_cstr(self.data, strtab.offset as usize + name).to_string(),
+                    kind: self.data[sym + 4] & 0xf,
+                    shndx: read_u16(self.data, sym + 6),
+                    value: read_u64(self.data, sym + 8),
+                    size: read_u64(self.data, sym + 16),
+                }
+            })
+            .collect())
+    }
+
+    fn file_offset(&self, addr: u64) -> Option<usize> {
+        self.sections
+            .iter()
+            .find(|s| s.addr != 0 && addr >= s.addr && addr < s.addr + s.size)
+            .map(|s| (s.offset + (addr - s.addr)) as usize)
+    }
+}
+
+fn build_table(elf: &Elf, symbols: &[Symbol]) -> Result<Vec<u8>, String> {
+    let (text_idx, text) = elf.section_by_name(".text").ok_or("no .text section")?;
+
+    let mut funcs: Vec<&Symbol> = symbols
+        .iter()
+        .filter(|s| s.shndx as usize == text_idx)
+        .filter(|s| s.kind == STT_FUNC || s.kind == STT_NOTYPE)
+        // Skip mapping symbols ($x, $d) and linker script markers
+        .filter(|s| !s.name.is_empty() && !s.name.starts_with('$'))
+        .filter(|s| !(s.name.starts_with("__") && s.name.ends_with("_start")))
+        .filter(|s| !(s.name.starts_with("__") && s.name.ends_with("_end")))
+        .collect();
+    funcs.sort_by_key(|s| s.value);
+    funcs.dedup_by_key(|s| s.value);
+
+    let mut entries = Vec::new();
+    let mut names = Vec::new();
+    for sym in &funcs {
+        let offset = u32::try_from(sym.value - text.addr).map_err(|_| "symbol out of range")?;
+        let size = u32::try_from(sym.size).map_err(|_| "symbol too large")?;
+        let name = format!("{:#}", rustc_demangle::demangle(&sym.name));
+
+        entries.extend_from_slice(&offset.to_le_bytes());
+        entries.extend_from_slice(&size.to_le_bytes());
+        entries.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+    }
+
+    let mut table = Vec::new();
+    table.extend_from_slice(MAGIC);
+    table.extend_from_slice(&(funcs.len() as u32).to_le_bytes());
+    table.extend_from_slice(&entries);
+    table.extend_from_slice(&names);
+    Ok(table)
+}
+
+fn run(path: &str) -> Result<(), String> {
+    let mut data = std::fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+
+    let (start, size, table) = {
+        let elf = Elf::parse(&data)?;
+        let symbols = elf.symbols()?;
+        let find = |name: &str| {
+            symbols
+                .iter()
+                .find(|s| s.name == name)
+                .map(|s| s.value)
+                .ok_or(format!("symbol {name} not found"))
+        };
+
+        let start = find("__ksyms_start")?;
+        let end = find("__ksyms_end")?;
+        let table = build_table(&elf, &symbols)?;
+        let offset = elf
+            .file_offset(start)
+            .ok_or("__ksyms_start is not backed by file data")?;
+        (offset, (end - start) as usize, table)
+    };
+
+    if table.len() > size {
+        return Err(format!(
+            "symbol table needs {} bytes but only {size} are reserved, increase KSYMS_SIZE",
+            table.len()
+        ));
+    }
+
+    data[start..start + size].fill(0);
+    data[start..start + table.len()].copy_from_slice(&table);
+    std::fs::write(path, data).map_err(|e| format!("{path}: {e}"))?;
+
+    let percent = table.len() * 100 / size;
+    println!(
+        "ksyms: wrote {} of {size} bytes of symbols ({percent}%) to {path}",
+        table.len()
+    );
+    if percent > WARN_PERCENT {
+        eprintln!("ksyms: warning: symbol table is over {WARN_PERCENT}% full, increase KSYMS_SIZE");
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 2 {
+        eprintln!("usage: {} <kernel ELF>", args[0]);
+        return ExitCode::FAILURE;
+    }
+
+    match run(&args[1]) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("ksyms: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}